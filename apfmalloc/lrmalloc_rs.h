@@ -2,14 +2,91 @@
 #define __LRMALLOC_RS_HEADER__
 
 #include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+
+/* glibc 2.33 and later declare mallinfo2 in <malloc.h>, which has to come before the declarations below */
+#if defined(__GLIBC__) && (__GLIBC__ > 2 || (__GLIBC__ == 2 && __GLIBC_MINOR__ >= 33))
+#include <malloc.h>
+#define LRMALLOC_RS_HAS_MALLINFO2
+#endif
+
+#ifdef __cplusplus
+extern "C" {
+#endif
 
 void* malloc(size_t size);
 void* calloc(size_t count, size_t size);
 void* realloc(void* ptr, size_t new_size);
 void free(void* ptr);
+void free_sized(void* ptr, size_t size);
+void free_aligned_sized(void* ptr, size_t alignment, size_t size);
 void* aligned_alloc(size_t align, size_t size);
 
+size_t malloc_usable_size(void* ptr);
+void* memalign(size_t align, size_t size);
+void* valloc(size_t size);
+void* pvalloc(size_t size);
+void* reallocarray(void* ptr, size_t count, size_t size);
+
+
+#ifndef LRMALLOC_RS_HAS_MALLINFO2
+struct mallinfo2 {
+    size_t arena;
+    size_t ordblks;
+    size_t smblks;
+    size_t hblks;
+    size_t hblkhd;
+    size_t usmblks;
+    size_t fsmblks;
+    size_t uordblks;
+    size_t fordblks;
+    size_t keepcost;
+};
+
+struct mallinfo2 mallinfo2(void);
+#endif
+void malloc_stats(void);
+int malloc_info(int options, FILE* stream);
+
+/* Flushes the calling thread's cache and releases every fully free superblock. Returns the bytes released */
+size_t apfmalloc_purge(void);
+/* Also gives back the free pages of partially used superblocks. Returns 1 if any memory was released */
+int malloc_trim(size_t pad);
+
+/* The number of calls to free, free_sized, realloc and malloc_usable_size with a pointer from another allocator, which
+ * were forwarded to the next allocator */
+size_t apfmalloc_foreign_count(void);
+
+/* Flags for mallocx, rallocx and dallocx, with the same values as jemalloc's */
+#define MALLOCX_LG_ALIGN(la) ((int)(la))
+#define MALLOCX_ALIGN(a) ((int)(__builtin_ctzl((size_t)(a))))
+#define MALLOCX_ZERO ((int)0x40)
+#define MALLOCX_TCACHE_NONE ((int)0x100)
+/* Not in jemalloc: keeps the allocation out of the superblocks of short-lived allocations */
+#define MALLOCX_LONG_LIVED ((int)0x80)
+#define MALLOCX_ARENA(a) ((int)(((unsigned)(a) + 1) << 20))
+
+void* mallocx(size_t size, int flags);
+void* rallocx(void* ptr, size_t size, int flags);
+void dallocx(void* ptr, int flags);
+size_t sallocx(const void* ptr, int flags);
+size_t nallocx(size_t size, int flags);
+
+/* Arenas free all of their allocations at once when they are destroyed. Returns -1 if the arena can't be created */
+int apfmalloc_arena_create(void);
+void apfmalloc_arena_destroy(int arena);
+
+/* Walks every allocation starting in [base, base + size), as bionic does. Only call while disabled */
+int malloc_iterate(uintptr_t base, size_t size, void (*callback)(uintptr_t ptr, size_t size, void* arg), void* arg);
+void malloc_disable(void);
+void malloc_enable(void);
+
 unsigned char check_override();
 
+#ifdef __cplusplus
+}
+#endif
+
 
 #endif
\ No newline at end of file
@@ -0,0 +1,419 @@
+//! Overrides for the C++ `operator new` and `operator delete` family, exported with their Itanium mangled names so
+//! that C++ programs linking this library allocate through lrmalloc-rs instead of going through libstdc++.
+//!
+//! The symbols mangle `size_t` as `unsigned long`, so they are only exported on 64 bit targets.
+//!
+//! The throwing forms of `operator new` follow the `std::bad_alloc` semantics of the standard. When an allocation
+//! fails, the handler installed with `std::set_new_handler` is called and the allocation is attempted again. A handler
+//! can either free up memory, or throw an exception itself. If no handler is installed, `std::bad_alloc` is thrown.
+//!
+//! The handler and the exception come from the C++ runtime of the program, which is looked up with `dlsym` the first
+//! time an allocation fails, so the library does not depend on libstdc++. A program without a C++ runtime has no
+//! handler, and is terminated in the same way an uncaught `std::bad_alloc` would terminate it.
+//!
+//! The `std::nothrow_t` forms call the handler in the same way, and return NULL once no handler is installed. An
+//! exception thrown by the handler can not be caught on this side of the boundary, so it is not turned into NULL, and
+//! unwinds out of the `operator new` instead.
+//!
+//! The `std::align_val_t` forms of `operator delete` without a size can't work out the size class of the block, so
+//! they look it up like `free`, after checking that the pointer has the alignment it was allocated with.
+
+use std::ffi::c_void;
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use apfmalloc_lib::{do_aligned_alloc, do_malloc, try_free, try_free_sized};
+
+use crate::foreign;
+
+/// A C++ new-handler. It is declared as `C-unwind` so that it may throw an exception through `operator new`
+pub type NewHandler = Option<unsafe extern "C-unwind" fn()>;
+
+type GetNewHandlerFn = unsafe extern "C" fn() -> NewHandler;
+type AllocateExceptionFn = unsafe extern "C" fn(usize) -> *mut c_void;
+type DestructorFn = unsafe extern "C" fn(*mut c_void);
+type ThrowFn = unsafe extern "C-unwind" fn(*mut c_void, *mut c_void, Option<DestructorFn>) -> !;
+
+/// A symbol of the C++ runtime, which is looked up the first time it is needed
+struct CxxSymbol {
+    /// The nul terminated name of the symbol
+    name: &'static [u8],
+    looked_up: AtomicBool,
+    ptr: AtomicPtr<c_void>,
+}
+
+impl CxxSymbol {
+    const fn new(name: &'static [u8]) -> Self {
+        Self {
+            name,
+            looked_up: AtomicBool::new(false),
+            ptr: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+
+    /// Gets the address of the symbol, or `None` if no object loaded by the program defines it. Looking it up from
+    /// several threads at once gives the same address, so the race is harmless.
+    fn get(&self) -> Option<*mut c_void> {
+        if !self.looked_up.load(Ordering::Acquire) {
+            let ptr = unsafe { libc::dlsym(libc::RTLD_DEFAULT, self.name.as_ptr() as *const c_char) };
+            self.ptr.store(ptr, Ordering::Relaxed);
+            self.looked_up.store(true, Ordering::Release);
+        }
+        let ptr = self.ptr.load(Ordering::Relaxed);
+        if ptr.is_null() {
+            None
+        } else {
+            Some(ptr)
+        }
+    }
+}
+
+/// `std::get_new_handler()`
+static GET_NEW_HANDLER: CxxSymbol = CxxSymbol::new(b"_ZSt15get_new_handlerv\0");
+static ALLOCATE_EXCEPTION: CxxSymbol = CxxSymbol::new(b"__cxa_allocate_exception\0");
+static THROW: CxxSymbol = CxxSymbol::new(b"__cxa_throw\0");
+/// The `std::type_info` of `std::bad_alloc`
+static BAD_ALLOC_TYPEINFO: CxxSymbol = CxxSymbol::new(b"_ZTISt9bad_alloc\0");
+/// The virtual table of `std::bad_alloc`
+static BAD_ALLOC_VTABLE: CxxSymbol = CxxSymbol::new(b"_ZTVSt9bad_alloc\0");
+/// `std::bad_alloc::~bad_alloc()`
+static BAD_ALLOC_DESTRUCTOR: CxxSymbol = CxxSymbol::new(b"_ZNSt9bad_allocD1Ev\0");
+
+/// Gets the handler installed with `std::set_new_handler`, or NULL if there isn't one or the program has no C++
+/// runtime
+fn new_handler() -> NewHandler {
+    let get_new_handler = GET_NEW_HANDLER.get()?;
+    unsafe { std::mem::transmute::<*mut c_void, GetNewHandlerFn>(get_new_handler)() }
+}
+
+/// Throws a `std::bad_alloc`. The exception object only holds the virtual table pointer, which points past the offset
+/// to the top and the type info at the start of the virtual table, as laid out by the Itanium C++ ABI.
+fn bad_alloc() -> ! {
+    let symbols = (|| {
+        Some((
+            ALLOCATE_EXCEPTION.get()?,
+            THROW.get()?,
+            BAD_ALLOC_TYPEINFO.get()?,
+            BAD_ALLOC_VTABLE.get()?,
+            BAD_ALLOC_DESTRUCTOR.get()?,
+        ))
+    })();
+    match symbols {
+        Some((allocate_exception, throw, typeinfo, vtable, destructor)) => unsafe {
+            let allocate_exception = std::mem::transmute::<*mut c_void, AllocateExceptionFn>(allocate_exception);
+            let throw = std::mem::transmute::<*mut c_void, ThrowFn>(throw);
+            let destructor = std::mem::transmute::<*mut c_void, DestructorFn>(destructor);
+            let exception = allocate_exception(std::mem::size_of::<*const c_void>()) as *mut *const c_void;
+            exception.write((vtable as *const *const c_void).add(2) as *const c_void);
+            throw(exception as *mut c_void, typeinfo, Some(destructor))
+        },
+        None => {
+            eprintln!("terminate called after throwing an instance of 'std::bad_alloc'");
+            std::process::abort()
+        }
+    }
+}
+
+/// Repeats an allocation until it succeeds, calling the new-handler after every failure. Returns NULL once there is
+/// no handler.
+fn new_or_null<F: Fn() -> *mut c_void>(allocate: F) -> *mut c_void {
+    loop {
+        let ptr = allocate();
+        if !ptr.is_null() {
+            return ptr;
+        }
+        match new_handler() {
+            Some(handler) => unsafe { handler() },
+            None => return ptr,
+        }
+    }
+}
+
+/// Repeats an allocation in the same way as [`new_or_null`](fn.new_or_null.html), but throws `std::bad_alloc` once
+/// there is no handler
+fn new_or_handle<F: Fn() -> *mut c_void>(allocate: F) -> *mut c_void {
+    let ptr = new_or_null(allocate);
+    if ptr.is_null() {
+        bad_alloc()
+    }
+    ptr
+}
+
+fn new_aligned(size: usize, align: usize) -> *mut c_void {
+    do_aligned_alloc(align, size) as *mut c_void
+}
+
+/// `operator new(size_t)`
+#[no_mangle]
+pub extern "C-unwind" fn _Znwm(size: usize) -> *mut c_void {
+    new_or_handle(|| do_malloc(size) as *mut c_void)
+}
+
+/// `operator new[](size_t)`
+#[no_mangle]
+pub extern "C-unwind" fn _Znam(size: usize) -> *mut c_void {
+    new_or_handle(|| do_malloc(size) as *mut c_void)
+}
+
+/// `operator new(size_t, const std::nothrow_t&)`
+#[no_mangle]
+pub extern "C-unwind" fn _ZnwmRKSt9nothrow_t(size: usize, _tag: *const c_void) -> *mut c_void {
+    new_or_null(|| do_malloc(size) as *mut c_void)
+}
+
+/// `operator new[](size_t, const std::nothrow_t&)`
+#[no_mangle]
+pub extern "C-unwind" fn _ZnamRKSt9nothrow_t(size: usize, _tag: *const c_void) -> *mut c_void {
+    new_or_null(|| do_malloc(size) as *mut c_void)
+}
+
+/// `operator new(size_t, std::align_val_t)`
+#[no_mangle]
+pub extern "C-unwind" fn _ZnwmSt11align_val_t(size: usize, align: usize) -> *mut c_void {
+    new_or_handle(|| new_aligned(size, align))
+}
+
+/// `operator new[](size_t, std::align_val_t)`
+#[no_mangle]
+pub extern "C-unwind" fn _ZnamSt11align_val_t(size: usize, align: usize) -> *mut c_void {
+    new_or_handle(|| new_aligned(size, align))
+}
+
+/// `operator new(size_t, std::align_val_t, const std::nothrow_t&)`
+#[no_mangle]
+pub extern "C-unwind" fn _ZnwmSt11align_val_tRKSt9nothrow_t(
+    size: usize,
+    align: usize,
+    _tag: *const c_void,
+) -> *mut c_void {
+    new_or_null(|| new_aligned(size, align))
+}
+
+/// `operator new[](size_t, std::align_val_t, const std::nothrow_t&)`
+#[no_mangle]
+pub extern "C-unwind" fn _ZnamSt11align_val_tRKSt9nothrow_t(
+    size: usize,
+    align: usize,
+    _tag: *const c_void,
+) -> *mut c_void {
+    new_or_null(|| new_aligned(size, align))
+}
+
+/// Frees `ptr` in the same way as `free`, so that a pointer of another allocator is passed on to it
+unsafe fn delete(ptr: *mut c_void) {
+    if !try_free(ptr) {
+        foreign::forward_free(ptr);
+    }
+}
+
+/// Frees `ptr`, which was allocated aligned to `align`, in the same way as `free`
+unsafe fn delete_aligned(ptr: *mut c_void, align: usize) {
+    debug_assert_eq!(ptr as usize & align.wrapping_sub(1), 0, "{:p} is not aligned to {}", ptr, align);
+    delete(ptr)
+}
+
+/// Frees `ptr` in the same way as `free_aligned_sized`
+unsafe fn delete_sized(ptr: *mut c_void, size: usize, align: usize) {
+    if !try_free_sized(ptr, size, align) {
+        foreign::forward_free(ptr);
+    }
+}
+
+/// `operator delete(void*)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new` that has not been deleted.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPv(ptr: *mut c_void) {
+    delete(ptr)
+}
+
+/// `operator delete[](void*)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new[]` that has not been deleted.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPv(ptr: *mut c_void) {
+    delete(ptr)
+}
+
+/// `operator delete(void*, const std::nothrow_t&)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new` that has not been deleted.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvRKSt9nothrow_t(ptr: *mut c_void, _tag: *const c_void) {
+    delete(ptr)
+}
+
+/// `operator delete[](void*, const std::nothrow_t&)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new[]` that has not been deleted.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvRKSt9nothrow_t(ptr: *mut c_void, _tag: *const c_void) {
+    delete(ptr)
+}
+
+/// `operator delete(void*, size_t)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new` that has not been deleted. `size` must be
+/// the size it was allocated with.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvm(ptr: *mut c_void, size: usize) {
+    delete_sized(ptr, size, 1)
+}
+
+/// `operator delete[](void*, size_t)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new[]` that has not been deleted. `size` must be
+/// the size it was allocated with.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvm(ptr: *mut c_void, size: usize) {
+    delete_sized(ptr, size, 1)
+}
+
+/// `operator delete(void*, std::align_val_t)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new` that has not been deleted. `align` must be
+/// the alignment it was allocated with.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvSt11align_val_t(ptr: *mut c_void, align: usize) {
+    delete_aligned(ptr, align)
+}
+
+/// `operator delete[](void*, std::align_val_t)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new[]` that has not been deleted. `align` must
+/// be the alignment it was allocated with.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvSt11align_val_t(ptr: *mut c_void, align: usize) {
+    delete_aligned(ptr, align)
+}
+
+/// `operator delete(void*, std::align_val_t, const std::nothrow_t&)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new` that has not been deleted. `align` must be
+/// the alignment it was allocated with.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvSt11align_val_tRKSt9nothrow_t(
+    ptr: *mut c_void,
+    align: usize,
+    _tag: *const c_void,
+) {
+    delete_aligned(ptr, align)
+}
+
+/// `operator delete[](void*, std::align_val_t, const std::nothrow_t&)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new[]` that has not been deleted. `align` must
+/// be the alignment it was allocated with.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvSt11align_val_tRKSt9nothrow_t(
+    ptr: *mut c_void,
+    align: usize,
+    _tag: *const c_void,
+) {
+    delete_aligned(ptr, align)
+}
+
+/// `operator delete(void*, size_t, std::align_val_t)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new` that has not been deleted. `size` and
+/// `align` must be the values it was allocated with.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvmSt11align_val_t(ptr: *mut c_void, size: usize, align: usize) {
+    delete_sized(ptr, size, align)
+}
+
+/// `operator delete[](void*, size_t, std::align_val_t)`
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by the matching `operator new[]` that has not been deleted. `size` and
+/// `align` must be the values it was allocated with.
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvmSt11align_val_t(ptr: *mut c_void, size: usize, align: usize) {
+    delete_sized(ptr, size, align)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ptr::null;
+    use std::sync::Mutex;
+
+    static HANDLER_CALLED: AtomicBool = AtomicBool::new(false);
+    static REMOVED_HANDLER_CALLED: AtomicBool = AtomicBool::new(false);
+    /// Held by the tests that install a new-handler or fail an allocation, as the handler is global
+    static HANDLER_LOCK: Mutex<()> = Mutex::new(());
+
+    #[link(name = "stdc++")]
+    extern "C" {
+        /// `std::set_new_handler(void (*)())`
+        fn _ZSt15set_new_handlerPFvvE(handler: NewHandler) -> NewHandler;
+    }
+
+    extern "C-unwind" fn panicking_handler() {
+        HANDLER_CALLED.store(true, Ordering::Release);
+        panic!("Handler should unwind through operator new");
+    }
+
+    extern "C-unwind" fn removing_handler() {
+        REMOVED_HANDLER_CALLED.store(true, Ordering::Release);
+        unsafe { _ZSt15set_new_handlerPFvvE(None) };
+    }
+
+    #[test]
+    fn new_and_delete() {
+        unsafe {
+            let single = _Znwm(24) as *mut usize;
+            let array = _Znam(64) as *mut usize;
+            let aligned = _ZnwmSt11align_val_t(48, 256) as *mut usize;
+            assert!(!single.is_null() && !array.is_null());
+            assert_eq!(aligned as usize % 256, 0);
+            *single = 1;
+            *array.add(7) = 2;
+            *aligned = 3;
+            _ZdlPvm(single as *mut c_void, 24);
+            _ZdaPv(array as *mut c_void);
+            _ZdlPvmSt11align_val_t(aligned as *mut c_void, 48, 256);
+        }
+    }
+
+    #[test]
+    fn nothrow_returns_null() {
+        let _lock = HANDLER_LOCK.lock().unwrap();
+        // an alignment that is not a power of 2 can never be satisfied
+        assert!(_ZnwmSt11align_val_tRKSt9nothrow_t(8, 3, null()).is_null());
+        let ptr = _ZnwmRKSt9nothrow_t(8, null());
+        assert!(!ptr.is_null());
+        unsafe { _ZdlPvRKSt9nothrow_t(ptr, null()) }
+    }
+
+    #[test]
+    fn new_handler_called_on_failure() {
+        let _lock = HANDLER_LOCK.lock().unwrap();
+        let old = unsafe { _ZSt15set_new_handlerPFvvE(Some(panicking_handler)) };
+        let result = std::panic::catch_unwind(|| _ZnwmSt11align_val_t(8, 3));
+        unsafe { _ZSt15set_new_handlerPFvvE(old) };
+        assert!(result.is_err());
+        assert!(HANDLER_CALLED.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn nothrow_calls_new_handler() {
+        let _lock = HANDLER_LOCK.lock().unwrap();
+        let old = unsafe { _ZSt15set_new_handlerPFvvE(Some(removing_handler)) };
+        let ptr = _ZnamSt11align_val_tRKSt9nothrow_t(8, 3, null());
+        unsafe { _ZSt15set_new_handlerPFvvE(old) };
+        assert!(ptr.is_null());
+        assert!(REMOVED_HANDLER_CALLED.load(Ordering::Acquire));
+    }
+}
@@ -0,0 +1,268 @@
+//! Forwarding of pointers that were not allocated by apfmalloc to the allocator that comes after it.
+//!
+//! When the library is loaded with `LD_PRELOAD`, some memory can still come from the allocator it replaced, such as
+//! memory allocated by the dynamic loader before the library was loaded. `free`, `free_sized`, `free_aligned_sized`,
+//! `realloc` and `malloc_usable_size` look up the versions of the next object in the lookup order with
+//! `dlsym(RTLD_NEXT, ..)`, which is normally glibc, and hand them any pointer that is neither in the page map nor in
+//! the bootstrap reserve.
+//!
+//! Pointers are only forwarded when apfmalloc is loaded as a shared object. When it is linked into the executable,
+//! every allocation of the program goes through it from the start, so a pointer it doesn't know did not come from another
+//! allocator, even though `dlsym(RTLD_NEXT, ..)` would still find the functions of glibc. A foreign pointer is then
+//! handled as if it had no allocator, as it is when there is no next allocator: it is not freed, and reallocating it
+//! aborts the program, as the size of its contents is not known.
+//!
+//! Setting `APFMALLOC_LOG_FOREIGN` in the environment writes a line to stderr for every call that is forwarded.
+
+use std::ffi::c_void;
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+
+type FreeFn = unsafe extern "C" fn(*mut c_void);
+type ReallocFn = unsafe extern "C" fn(*mut c_void, usize) -> *mut c_void;
+type UsableSizeFn = unsafe extern "C" fn(*mut c_void) -> usize;
+
+/// A function of the next allocator, which is looked up the first time it is needed, and the number of calls that were
+/// forwarded to it
+struct NextFn {
+    /// The nul terminated name of the function
+    name: &'static [u8],
+    looked_up: AtomicBool,
+    ptr: AtomicPtr<c_void>,
+    forwarded: AtomicUsize,
+}
+
+impl NextFn {
+    const fn new(name: &'static [u8]) -> Self {
+        Self {
+            name,
+            looked_up: AtomicBool::new(false),
+            ptr: AtomicPtr::new(std::ptr::null_mut()),
+            forwarded: AtomicUsize::new(0),
+        }
+    }
+
+    /// Gets the address of the function, or `None` if no object after this one defines it or pointers are not
+    /// forwarded. Looking it up from several threads at once gives the same address, so the race is harmless.
+    fn get(&self) -> Option<*mut c_void> {
+        if !forwarding() {
+            return None;
+        }
+        if !self.looked_up.load(Ordering::Acquire) {
+            let ptr = unsafe { libc::dlsym(libc::RTLD_NEXT, self.name.as_ptr() as *const c_char) };
+            self.ptr.store(ptr, Ordering::Relaxed);
+            self.looked_up.store(true, Ordering::Release);
+        }
+        let ptr = self.ptr.load(Ordering::Relaxed);
+        if ptr.is_null() {
+            None
+        } else {
+            Some(ptr)
+        }
+    }
+
+    /// Counts and logs a call with `ptr` that is about to be forwarded
+    fn forwarding(&self, ptr: *mut c_void) {
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+        if log_enabled() {
+            log(&self.name[..self.name.len() - 1], ptr);
+        }
+    }
+}
+
+static NEXT_FREE: NextFn = NextFn::new(b"free\0");
+static NEXT_REALLOC: NextFn = NextFn::new(b"realloc\0");
+static NEXT_USABLE_SIZE: NextFn = NextFn::new(b"malloc_usable_size\0");
+
+/// Whether pointers are forwarded, where 0 means it has not been checked yet, 1 means no and 2 means yes
+static FORWARDING: AtomicU8 = AtomicU8::new(0);
+
+pub(crate) fn forwarding() -> bool {
+    let mut state = FORWARDING.load(Ordering::Relaxed);
+    if state == 0 {
+        state = if loaded_as_shared_object() { 2 } else { 1 };
+        FORWARDING.store(state, Ordering::Relaxed);
+    }
+    state == 2
+}
+
+/// Checks whether this library was loaded as a shared object, either with `LD_PRELOAD` or as a dependency of the
+/// program, by comparing the object that contains it with the one that contains the entry point of the program. Neither
+/// is found in a statically linked program, which can only have linked the library in.
+fn loaded_as_shared_object() -> bool {
+    unsafe {
+        let entry = libc::getauxval(libc::AT_ENTRY) as *const c_void;
+        let mut own = std::mem::zeroed::<libc::Dl_info>();
+        let mut program = std::mem::zeroed::<libc::Dl_info>();
+        libc::dladdr(loaded_as_shared_object as *const c_void, &mut own) != 0
+            && libc::dladdr(entry, &mut program) != 0
+            && own.dli_fbase != program.dli_fbase
+    }
+}
+
+/// Whether forwarded calls are logged, where 0 means the environment has not been read yet, 1 means no and 2 means yes
+static LOG_FOREIGN: AtomicU8 = AtomicU8::new(0);
+
+fn log_enabled() -> bool {
+    let mut state = LOG_FOREIGN.load(Ordering::Relaxed);
+    if state == 0 {
+        // getenv does not allocate, unlike std::env::var
+        let set = unsafe { !libc::getenv(b"APFMALLOC_LOG_FOREIGN\0".as_ptr() as *const c_char).is_null() };
+        state = if set { 2 } else { 1 };
+        LOG_FOREIGN.store(state, Ordering::Relaxed);
+    }
+    state == 2
+}
+
+/// Writes a line about a forwarded call straight to stderr, as this is called from inside of the allocator and can't
+/// allocate
+fn log(function: &[u8], ptr: *mut c_void) {
+    const DIGITS: usize = std::mem::size_of::<usize>() * 2;
+    let mut line = [0u8; 64 + DIGITS];
+    let mut len = 0;
+    let mut push = |bytes: &[u8]| {
+        line[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+    };
+
+    let mut hex = [0u8; DIGITS];
+    for (i, digit) in hex.iter_mut().enumerate() {
+        *digit = b"0123456789abcdef"[(ptr as usize >> ((DIGITS - 1 - i) * 4)) & 0xf];
+    }
+    push(b"apfmalloc: forwarding ");
+    push(function);
+    push(b"(0x");
+    push(&hex);
+    push(b") to the next allocator\n");
+    unsafe { libc::write(libc::STDERR_FILENO, line.as_ptr() as *const c_void, len) };
+}
+
+/// Frees `ptr` with the `free` of the next allocator. Returns `false` if there is none.
+pub(crate) unsafe fn forward_free(ptr: *mut c_void) -> bool {
+    match NEXT_FREE.get() {
+        Some(next) => {
+            NEXT_FREE.forwarding(ptr);
+            std::mem::transmute::<*mut c_void, FreeFn>(next)(ptr);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Reallocates `ptr` with the `realloc` of the next allocator, so the result belongs to that allocator as well. Returns
+/// `None` if there is none.
+pub(crate) unsafe fn forward_realloc(ptr: *mut c_void, size: usize) -> Option<*mut c_void> {
+    let next = NEXT_REALLOC.get()?;
+    NEXT_REALLOC.forwarding(ptr);
+    Some(std::mem::transmute::<*mut c_void, ReallocFn>(next)(ptr, size))
+}
+
+/// Gets the usable size of `ptr` from the `malloc_usable_size` of the next allocator. Returns `None` if there is none.
+pub(crate) unsafe fn forward_usable_size(ptr: *mut c_void) -> Option<usize> {
+    let next = NEXT_USABLE_SIZE.get()?;
+    NEXT_USABLE_SIZE.forwarding(ptr);
+    Some(std::mem::transmute::<*mut c_void, UsableSizeFn>(next)(ptr))
+}
+
+/// The number of calls of each function that were given a foreign pointer and forwarded to the next allocator
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ForeignCounts {
+    pub frees: usize,
+    pub reallocs: usize,
+    pub usable_sizes: usize,
+}
+
+impl ForeignCounts {
+    /// The number of forwarded calls of every function
+    pub fn total(&self) -> usize {
+        self.frees + self.reallocs + self.usable_sizes
+    }
+}
+
+/// Gets the number of calls that have been forwarded to the next allocator so far
+pub fn foreign_counts() -> ForeignCounts {
+    ForeignCounts {
+        frees: NEXT_FREE.forwarded.load(Ordering::Relaxed),
+        reallocs: NEXT_REALLOC.forwarded.load(Ordering::Relaxed),
+        usable_sizes: NEXT_USABLE_SIZE.forwarded.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns the number of calls to `free`, `free_sized`, `realloc` and `malloc_usable_size` that were given a pointer
+/// that was not allocated by apfmalloc, and were forwarded to the next allocator
+#[no_mangle]
+pub extern "C" fn apfmalloc_foreign_count() -> usize {
+    foreign_counts().total()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{free, free_sized, malloc, malloc_usable_size, realloc};
+
+    type MallocFn = unsafe extern "C" fn(usize) -> *mut c_void;
+
+    /// Turns forwarding on until it is dropped, so the other tests of the binary still see it off
+    struct ForceForwarding(u8);
+
+    impl ForceForwarding {
+        fn new() -> Self {
+            Self(FORWARDING.swap(2, Ordering::Relaxed))
+        }
+    }
+
+    impl Drop for ForceForwarding {
+        fn drop(&mut self) {
+            FORWARDING.store(self.0, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn foreign_pointers_are_forwarded() {
+        let next_malloc = unsafe { libc::dlsym(libc::RTLD_NEXT, b"malloc\0".as_ptr() as *const c_char) };
+        assert!(
+            !next_malloc.is_null(),
+            "The test binary should be linked to the C library"
+        );
+        let next_malloc = unsafe { std::mem::transmute::<*mut c_void, MallocFn>(next_malloc) };
+        // the library is linked into the test binary, which would turn forwarding off
+        assert!(!loaded_as_shared_object());
+        let _forwarding = ForceForwarding::new();
+        let before = foreign_counts();
+
+        unsafe {
+            let ptr = next_malloc(100) as *mut u8;
+            assert!(!apfmalloc_lib::owns(ptr));
+            for i in 0..100 {
+                *ptr.add(i) = i as u8;
+            }
+            assert!(malloc_usable_size(ptr as *mut c_void) >= 100);
+
+            let ptr = realloc(ptr as *mut c_void, 10_000) as *mut u8;
+            assert!(!ptr.is_null());
+            assert!(
+                !apfmalloc_lib::owns(ptr),
+                "The next allocator should have reallocated the pointer"
+            );
+            for i in 0..100 {
+                assert_eq!(*ptr.add(i), i as u8, "The contents were not kept");
+            }
+            free(ptr as *mut c_void);
+
+            // the size doesn't let a foreign pointer skip the check of its allocator
+            let ptr = next_malloc(100);
+            free_sized(ptr, 100);
+
+            // pointers of this allocator are never forwarded
+            let own = malloc(100);
+            assert!(apfmalloc_lib::owns(own));
+            free(own);
+        }
+
+        let after = foreign_counts();
+        assert!(after.frees >= before.frees + 2);
+        assert!(after.reallocs > before.reallocs);
+        assert!(after.usable_sizes > before.usable_sizes);
+        assert!(apfmalloc_foreign_count() >= after.total());
+    }
+}
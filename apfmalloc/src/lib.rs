@@ -6,9 +6,25 @@ extern crate apfmalloc_lib;
 use std::ffi::c_void;
 use std::ptr::null_mut;
 
-pub use apfmalloc_lib::{do_aligned_alloc, do_free, do_malloc, do_realloc};
+use apfmalloc_lib::arena::Arena;
+use apfmalloc_lib::iterate::{disable_allocation, enable_allocation, iterate_heap, IterationMode};
+use apfmalloc_lib::mallocx::{do_dallocx, do_mallocx, do_nallocx, do_rallocx};
+use apfmalloc_lib::mem_info::PAGE;
+pub use apfmalloc_lib::{
+    do_aligned_alloc, do_aligned_alloc_zeroed, do_free, do_free_sized, do_malloc, do_malloc_zeroed, do_realloc, owns,
+    purge, thread_cache_flush, trim, try_aligned_alloc, try_free, try_free_sized, try_malloc, try_realloc, MallocError,
+};
 #[cfg(not(feature = "no-rust"))]
 pub use rust_global::*;
+#[cfg(target_pointer_width = "64")]
+pub use cpp::*;
+pub use foreign::{apfmalloc_foreign_count, foreign_counts, ForeignCounts};
+pub use stats::*;
+
+#[cfg(target_pointer_width = "64")]
+mod cpp;
+mod foreign;
+mod stats;
 
 /// Checks if a call to `malloc` use the lrmalloc-rs implementation.
 ///
@@ -70,16 +86,11 @@ pub extern "C" fn calloc(num: usize, size: usize) -> *mut c_void {
     unsafe {
         OVERRIDE_CALLOC = true;
     }
-    if num.checked_mul(size).is_none() {
-        return null_mut();
-    }
-    let ret = malloc(num * size) as *mut u8;
-    unsafe {
-        for i in 0..(num * size) {
-            *ret.offset(i as isize) = 0;
-        }
-    }
-    ret as *mut c_void
+    let total = match num.checked_mul(size) {
+        Some(total) => total,
+        None => return null_mut(),
+    };
+    do_malloc_zeroed(total) as *mut c_void
 }
 /// Reallocates the given area of memory. It must be previously allocated by malloc(), calloc() or realloc() and not yet freed with a call to free or realloc. Otherwise, the results are undefined.
 ///
@@ -92,9 +103,17 @@ pub extern "C" fn calloc(num: usize, size: usize) -> *mut c_void {
 /// If ptr is NULL, the behavior is the same as calling malloc(new_size).
 ///
 /// If size is zero, a pointer to the minimum sized allocation is created
+///
+/// A pointer that was not allocated by this allocator is reallocated by the next allocator, if the library was loaded as a
+/// shared object and there is one. Otherwise, the program is aborted, as there is no way to tell how much of it to keep.
 #[no_mangle]
 pub unsafe extern "C" fn realloc(ptr: *mut c_void, new_size: usize) -> *mut c_void {
     OVERRIDE_REALLOC = true;
+    if !ptr.is_null() && foreign::forwarding() && !owns(ptr) {
+        if let Some(ret) = foreign::forward_realloc(ptr, new_size) {
+            return ret;
+        }
+    }
     do_realloc(ptr, new_size)
 }
 
@@ -107,10 +126,52 @@ pub unsafe extern "C" fn realloc(ptr: *mut c_void, new_size: usize) -> *mut c_vo
 /// The behavior is undefined if the memory area referred to by ptr has already been deallocated, that is, free() or realloc() has already been called with ptr as the argument and no calls to malloc(), calloc() or realloc() resulted in a pointer equal to ptr afterwards.
 ///
 /// The behavior is undefined if after free() returns, an access is made through the pointer ptr (unless another allocation function happened to result in a pointer value equal to ptr)
+///
+/// A pointer that was not allocated by this allocator is freed by the next allocator, if the library was loaded as a
+/// shared object and there is one.
 #[no_mangle]
 pub unsafe extern "C" fn free(ptr: *mut c_void) {
     OVERRIDE_FREE = true;
-    do_free(ptr)
+    if !try_free(ptr) {
+        foreign::forward_free(ptr);
+    }
+}
+
+/// Deallocates the space previously allocated by malloc(), calloc() or realloc(), where `size` is the size that was
+/// requested for the allocation (C23).
+///
+/// If ptr is a null pointer, the function does nothing. The behavior is undefined if `size` is not the requested size.
+///
+/// A pointer that was not allocated by this allocator is freed by the next allocator, if the library was loaded as a
+/// shared object and there is one.
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by malloc(), calloc() or realloc() that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn free_sized(ptr: *mut c_void, size: usize) {
+    OVERRIDE_FREE = true;
+    if !try_free_sized(ptr, size, 1) {
+        foreign::forward_free(ptr);
+    }
+}
+
+/// Deallocates the space previously allocated by aligned_alloc(), where `alignment` and `size` are the values that were
+/// passed to aligned_alloc() (C23).
+///
+/// If ptr is a null pointer, the function does nothing. The behavior is undefined if `alignment` or `size` do not
+/// match.
+///
+/// A pointer that was not allocated by this allocator is freed by the next allocator, if the library was loaded as a
+/// shared object and there is one.
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by aligned_alloc() that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn free_aligned_sized(ptr: *mut c_void, alignment: usize, size: usize) {
+    OVERRIDE_FREE = true;
+    if !try_free_sized(ptr, size, alignment) {
+        foreign::forward_free(ptr);
+    }
 }
 
 /// Has similar behavior to malloc, but also ensures that all memory allocated is also properly aligned to the specified
@@ -165,6 +226,239 @@ pub extern "C" fn posix_memalign(ptr: *mut *mut std::ffi::c_void, alignment: usi
     0
 }
 
+/// Sets the C `errno` for the calling thread, in the same way glibc reports allocation failures
+fn set_errno(code: i32) {
+    errno::set_errno(errno::Errno(code));
+}
+
+/// Returns the number of usable bytes in the block pointed to by `ptr`, which may be larger than the size that was
+/// requested when the block was allocated.
+///
+/// If `ptr` is NULL, 0 is returned. If it was not allocated by this allocator, the size is asked of the next allocator,
+/// and 0 is returned if there is none.
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer to an allocation of this allocator or the next one that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> usize {
+    if ptr.is_null() {
+        return 0;
+    }
+    match apfmalloc_lib::sallocx(ptr) {
+        Some(size) => size,
+        None if !owns(ptr) => foreign::forward_usable_size(ptr).unwrap_or(0),
+        None => 0,
+    }
+}
+
+/// Returns the number of usable bytes in the block pointed to by `ptr`, in the same way as `malloc_usable_size`
+#[no_mangle]
+pub extern "C" fn sallocx(ptr: *const c_void, _flags: i32) -> usize {
+    if ptr.is_null() {
+        return 0;
+    }
+    apfmalloc_lib::sallocx(ptr).unwrap_or(0)
+}
+
+/// Returns the number of usable bytes `mallocx(size, flags)` would hand out, without allocating anything.
+///
+/// If the flags are invalid or the size is too big to be allocated, 0 is returned.
+#[no_mangle]
+pub extern "C" fn nallocx(size: usize, flags: i32) -> usize {
+    do_nallocx(size, flags).unwrap_or(0)
+}
+
+/// Allocates `size` bytes whose address is a multiple of `alignment`. This is the obsolete glibc version of
+/// `aligned_alloc`, and follows the glibc rules:
+///
+/// - If `alignment` is not a power of two, it is rounded up to the next power of two
+/// - If `alignment` is too large to be rounded up, `errno` is set to `EINVAL` and NULL is returned
+///
+/// If the memory can not be allocated, `errno` is set to `ENOMEM` and NULL is returned.
+#[no_mangle]
+pub extern "C" fn memalign(alignment: usize, size: usize) -> *mut c_void {
+    unsafe {
+        OVERRIDE_ALIGNED_ALLOC = true;
+    }
+    let alignment = match alignment.max(1).checked_next_power_of_two() {
+        Some(alignment) => alignment,
+        None => {
+            set_errno(libc::EINVAL);
+            return null_mut();
+        }
+    };
+    let ret = do_aligned_alloc(alignment, size) as *mut c_void;
+    if ret.is_null() {
+        set_errno(libc::ENOMEM);
+    }
+    ret
+}
+
+/// Allocates `size` bytes aligned to the page size.
+///
+/// If the memory can not be allocated, `errno` is set to `ENOMEM` and NULL is returned.
+#[no_mangle]
+pub extern "C" fn valloc(size: usize) -> *mut c_void {
+    memalign(PAGE, size)
+}
+
+/// Allocates `size` bytes aligned to the page size, with the size rounded up to the next multiple of the page size. A
+/// size of 0 allocates a whole page.
+///
+/// If rounding `size` overflows, or the memory can not be allocated, `errno` is set to `ENOMEM` and NULL is returned.
+#[no_mangle]
+pub extern "C" fn pvalloc(size: usize) -> *mut c_void {
+    // like glibc, a size of 0 still gets a whole page
+    let rounded = match size.max(1).checked_add(PAGE - 1) {
+        Some(size) => size & !(PAGE - 1),
+        None => {
+            set_errno(libc::ENOMEM);
+            return null_mut();
+        }
+    };
+    memalign(PAGE, rounded)
+}
+
+/// Reallocates `ptr` to be large enough for an array of `num` elements of `size` bytes, behaving the same as
+/// `realloc(ptr, num * size)`.
+///
+/// If `num * size` overflows, `errno` is set to `ENOMEM`, NULL is returned and `ptr` is left untouched.
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by this allocator that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn reallocarray(ptr: *mut c_void, num: usize, size: usize) -> *mut c_void {
+    let total = match num.checked_mul(size) {
+        Some(total) => total,
+        None => {
+            set_errno(libc::ENOMEM);
+            return null_mut();
+        }
+    };
+    let ret = realloc(ptr, total);
+    if ret.is_null() {
+        set_errno(libc::ENOMEM);
+    }
+    ret
+}
+
+/// Returns the thread cache of the calling thread to the central reserve, then gives every superblock whose blocks are
+/// all free back to the OS. Returns the number of bytes that were released.
+///
+/// Blocks cached by other threads are not returned, so calling this from every thread that has freed memory releases
+/// the most.
+#[no_mangle]
+pub extern "C" fn apfmalloc_purge() -> usize {
+    thread_cache_flush();
+    purge()
+}
+
+/// Gives free memory back to the OS in the same way as glibc's `malloc_trim`. The calling thread's cache is flushed,
+/// every superblock whose blocks are all free is released, the pages of the other superblocks that only hold free
+/// blocks are given back, and so is the unused end of the bootstrap reserve past the first `pad` bytes.
+///
+/// Returns 1 if any memory was released, and 0 otherwise.
+#[no_mangle]
+pub extern "C" fn malloc_trim(pad: usize) -> i32 {
+    (trim(pad) > 0) as i32
+}
+
+/// Allocates `size` bytes with the options in `flags`, which use the same values as jemalloc's `MALLOCX_*` flags.
+///
+/// If the memory can not be allocated, NULL is returned.
+#[no_mangle]
+pub extern "C" fn mallocx(size: usize, flags: i32) -> *mut c_void {
+    do_mallocx(size, flags) as *mut c_void
+}
+
+/// Resizes the allocation at `ptr` to `size` bytes with the options in `flags`.
+///
+/// If the memory can not be allocated, NULL is returned and `ptr` is left untouched.
+///
+/// # Safety
+/// `ptr` must be a pointer returned by this allocator that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rallocx(ptr: *mut c_void, size: usize, flags: i32) -> *mut c_void {
+    do_rallocx(ptr as *mut u8, size, flags) as *mut c_void
+}
+
+/// Frees `ptr`. Passing `MALLOCX_TCACHE_NONE` in `flags` returns the block to the central reserve instead of the thread
+/// cache.
+///
+/// # Safety
+/// `ptr` must be a pointer returned by this allocator that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn dallocx(ptr: *mut c_void, flags: i32) {
+    do_dallocx(ptr as *mut u8, flags)
+}
+
+/// Creates a new arena, and returns the index that `MALLOCX_ARENA` picks it with.
+///
+/// If the arena can not be created, or every index is taken, -1 is returned.
+#[no_mangle]
+pub extern "C" fn apfmalloc_arena_create() -> i32 {
+    let arena = match Arena::new() {
+        Ok(arena) => arena,
+        Err(_) => return -1,
+    };
+    match arena.index() {
+        Some(index) => {
+            std::mem::forget(arena);
+            index as i32
+        }
+        None => -1,
+    }
+}
+
+/// Destroys the arena with the index `index`, freeing all of its allocations at once. Does nothing if there is no arena
+/// with that index.
+///
+/// # Safety
+/// No other thread may be using the memory of the arena, as it is all unmapped.
+#[no_mangle]
+pub unsafe extern "C" fn apfmalloc_arena_destroy(index: i32) {
+    if index >= 0 {
+        drop(Arena::from_index(index as usize));
+    }
+}
+
+/// Calls `callback` with the pointer, usable size and `arg` of every allocation that starts in `[base, base + size)`.
+/// Returns 0.
+///
+/// This is bionic's `malloc_iterate`.
+///
+/// # Safety
+/// Must be called between `malloc_disable` and `malloc_enable`, so that no other thread uses the allocator.
+/// `callback` must not allocate or free memory.
+#[no_mangle]
+pub unsafe extern "C" fn malloc_iterate(
+    base: usize,
+    size: usize,
+    callback: extern "C" fn(ptr: usize, size: usize, arg: *mut c_void),
+    arg: *mut c_void,
+) -> i32 {
+    iterate_heap(IterationMode::Quiescent, |block, usable| {
+        let block = block as usize;
+        if block >= base && block - base < size {
+            callback(block, usable, arg);
+        }
+    });
+    0
+}
+
+/// Stops every other thread from allocating or freeing until `malloc_enable` is called, such as around a `fork` or a
+/// call to `malloc_iterate`
+#[no_mangle]
+pub extern "C" fn malloc_disable() {
+    disable_allocation()
+}
+
+/// Lets threads allocate and free again after `malloc_disable`
+#[no_mangle]
+pub extern "C" fn malloc_enable() {
+    enable_allocation()
+}
+
 #[no_mangle]
 pub extern "C" fn check_override() -> u8 {
     unsafe {
@@ -195,7 +489,7 @@ pub extern "C" fn check_override() -> u8 {
 mod rust_global {
     use std::alloc::{GlobalAlloc, Layout};
 
-    use apfmalloc_lib::mem_info::align_val;
+    use apfmalloc_lib::do_aligned_realloc;
 
     use super::*;
 
@@ -215,18 +509,18 @@ mod rust_global {
         }
 
         unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-            let _ = layout;
             OVERRIDE_FREE = true;
-            do_free(ptr as *mut c_void)
+            do_free_sized(ptr, layout.size(), layout.align())
         }
 
         unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-            calloc(1, align_val(layout.size(), layout.align())) as *mut u8
+            OVERRIDE_CALLOC = true;
+            do_aligned_alloc_zeroed(layout.align(), layout.size())
         }
 
         unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
             OVERRIDE_REALLOC = true;
-            do_realloc(ptr as *mut c_void, align_val(new_size, layout.align())) as *mut u8
+            do_aligned_realloc(ptr, layout.align(), new_size)
         }
     }
 }
@@ -265,8 +559,11 @@ pub fn __rust_dealloc(ptr: *mut u8, _size: usize, _align: usize) {
 #[no_mangle]
 #[doc(hidden)]
 #[cfg(feature = "no-rust")]
-pub fn __rust_realloc(ptr: *mut u8, _old_size: usize, _align: usize, new_size: usize) -> *mut u8 {
-    unsafe { realloc(ptr as *mut c_void, new_size) as *mut u8 }
+pub fn __rust_realloc(ptr: *mut u8, _old_size: usize, align: usize, new_size: usize) -> *mut u8 {
+    unsafe {
+        OVERRIDE_REALLOC = true;
+        apfmalloc_lib::do_aligned_realloc(ptr, align, new_size)
+    }
 }
 
 
@@ -357,6 +654,74 @@ mod behavior {
         assert!(aligned_alloc(std::mem::size_of::<usize>(), std::mem::size_of::<usize>() * 3 / 2).is_null(), "Size must be a multiple of alignment");
     }
 
+    #[test]
+    fn usable_size_covers_request() {
+        let small = malloc(20);
+        let large = malloc(PAGE * 5 + 1);
+        let aligned = memalign(PAGE * 4, PAGE * 2);
+        assert!(unsafe { malloc_usable_size(small) } >= 20);
+        assert!(unsafe { malloc_usable_size(large) } > PAGE * 5);
+        assert!(unsafe { malloc_usable_size(aligned) } >= PAGE * 2);
+        assert_eq!(unsafe { malloc_usable_size(null_mut()) }, 0);
+        unsafe {
+            free(small);
+            free(large);
+            free(aligned);
+        }
+    }
+
+    #[test]
+    fn nallocx_matches_mallocx() {
+        let flags = apfmalloc_lib::mallocx::mallocx_lg_align(5);
+        let size = nallocx(300, flags);
+        let ptr = mallocx(300, flags);
+        assert_eq!(ptr as usize % 32, 0);
+        assert_eq!(sallocx(ptr, 0), size);
+        unsafe { dallocx(ptr, 0) };
+        assert_eq!(nallocx(usize::MAX, 0), 0);
+    }
+
+    #[test]
+    fn memalign_family_aligns() {
+        let rounded = memalign(48, 8);
+        assert_eq!(rounded as usize % 64, 0, "Alignment should be rounded up to a power of 2");
+        assert!(memalign(usize::MAX, 8).is_null());
+        assert_eq!(errno::errno().0, libc::EINVAL);
+
+        let paged = valloc(100);
+        assert_eq!(paged as usize % PAGE, 0);
+        let whole_pages = pvalloc(PAGE + 1);
+        assert_eq!(whole_pages as usize % PAGE, 0);
+        assert!(unsafe { malloc_usable_size(whole_pages) } >= PAGE * 2);
+        let empty = pvalloc(0);
+        assert_eq!(empty as usize % PAGE, 0);
+        assert!(unsafe { malloc_usable_size(empty) } >= PAGE);
+        assert!(pvalloc(usize::MAX).is_null());
+        assert_eq!(errno::errno().0, libc::ENOMEM);
+        unsafe {
+            free(rounded);
+            free(paged);
+            free(whole_pages);
+            free(empty);
+        }
+    }
+
+    #[test]
+    fn reallocarray_checks_overflow() {
+        unsafe {
+            let ptr = reallocarray(null_mut(), 4, std::mem::size_of::<usize>()) as *mut usize;
+            assert!(!ptr.is_null());
+            *ptr = 16;
+            assert!(reallocarray(ptr as *mut c_void, usize::MAX, 2).is_null());
+            assert_eq!(errno::errno().0, libc::ENOMEM);
+            assert_eq!(*ptr, 16, "A failed reallocarray should leave the old block alone");
+
+            let grown = reallocarray(ptr as *mut c_void, 64, std::mem::size_of::<usize>()) as *mut usize;
+            assert_eq!(*grown, 16);
+            free(grown as *mut c_void);
+        }
+    }
+
     #[test]
     fn realloc_on_null() {
         let ptr = unsafe {
@@ -388,6 +753,20 @@ mod behavior {
 
     }
 
+    #[test]
+    fn malloc_trim_releases_memory() {
+        let blocks: Vec<*mut c_void> = (0..2000).map(|_| malloc(3000)).collect();
+        unsafe {
+            for &block in &blocks {
+                (block as *mut u8).write_bytes(1, 3000);
+            }
+            for &block in &blocks {
+                free(block);
+            }
+        }
+        assert_eq!(malloc_trim(0), 1);
+    }
+
 
 
 
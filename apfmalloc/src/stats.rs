@@ -0,0 +1,208 @@
+//! The glibc functions that report on the state of the heap, backed by the snapshots of the heap taken by
+//! [`apfmalloc_lib::stats`](../apfmalloc_lib/stats/index.html).
+//!
+//! The allocator has a single "arena" made up of the superblocks of every size class. Large allocations are given their
+//! own segment, and are reported as mmapped memory in the same way glibc reports its mmapped chunks.
+
+use std::ffi::c_void;
+use std::fmt::Write;
+
+use apfmalloc_lib::stats::{heap_stats, print_stats, HeapStats};
+
+/// The same layout as the glibc `struct mallinfo2`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Mallinfo2 {
+    /// Bytes mapped for superblocks
+    pub arena: usize,
+    /// Number of free blocks
+    pub ordblks: usize,
+    /// Number of free blocks held in thread caches
+    pub smblks: usize,
+    /// Number of large allocations
+    pub hblks: usize,
+    /// Bytes mapped for large allocations
+    pub hblkhd: usize,
+    /// Always 0
+    pub usmblks: usize,
+    /// Bytes of free blocks held in thread caches
+    pub fsmblks: usize,
+    /// Bytes in use in superblocks
+    pub uordblks: usize,
+    /// Bytes of free blocks in superblocks
+    pub fordblks: usize,
+    /// Always 0, as there is no memory at the top of the heap that could be trimmed
+    pub keepcost: usize,
+}
+
+impl From<&HeapStats> for Mallinfo2 {
+    fn from(stats: &HeapStats) -> Self {
+        let (free_blocks, cached_blocks) = stats.size_classes.iter().fold((0, 0), |(free, cached), class| {
+            (
+                free + class.thread_cache_blocks + class.central_blocks,
+                cached + class.thread_cache_blocks,
+            )
+        });
+
+        Mallinfo2 {
+            arena: stats.arena_bytes,
+            ordblks: free_blocks,
+            smblks: cached_blocks,
+            hblks: stats.mmapped_count,
+            hblkhd: stats.mmapped_bytes,
+            usmblks: 0,
+            fsmblks: stats.thread_cache_bytes,
+            uordblks: stats.in_use_bytes,
+            fordblks: stats.free_bytes(),
+            keepcost: 0,
+        }
+    }
+}
+
+/// Returns information about the memory held by the allocator
+#[no_mangle]
+pub extern "C" fn mallinfo2() -> Mallinfo2 {
+    Mallinfo2::from(&heap_stats())
+}
+
+/// Prints the memory held by the allocator to stderr, in the same format as glibc
+#[no_mangle]
+pub extern "C" fn malloc_stats() {
+    print_stats()
+}
+
+/// Writes the state of the heap as the XML document produced by glibc's `malloc_info`, with one `<size>` entry for the
+/// free blocks of every size class
+pub fn write_malloc_info<W: Write>(stats: &HeapStats, out: &mut W) -> std::fmt::Result {
+    let free_blocks: usize = stats
+        .size_classes
+        .iter()
+        .map(|class| class.thread_cache_blocks + class.central_blocks)
+        .sum();
+    let cached_blocks: usize = stats.size_classes.iter().map(|class| class.thread_cache_blocks).sum();
+    let system = stats.arena_bytes + stats.mmapped_bytes;
+    let max_system = stats.max_arena_bytes + stats.max_mmapped_bytes;
+
+    writeln!(out, "<malloc version=\"1\">")?;
+    writeln!(out, "<heap nr=\"0\">")?;
+    writeln!(out, "<sizes>")?;
+    let mut from = 1;
+    for class in stats.size_classes.iter().skip(1) {
+        let free = class.thread_cache_blocks + class.central_blocks;
+        writeln!(
+            out,
+            "  <size from=\"{}\" to=\"{}\" total=\"{}\" count=\"{}\"/>",
+            from,
+            class.block_size,
+            free * class.block_size,
+            free
+        )?;
+        from = class.block_size + 1;
+    }
+    writeln!(out, "</sizes>")?;
+    writeln!(
+        out,
+        "<total type=\"fast\" count=\"{}\" size=\"{}\"/>",
+        cached_blocks, stats.thread_cache_bytes
+    )?;
+    writeln!(
+        out,
+        "<total type=\"rest\" count=\"{}\" size=\"{}\"/>",
+        free_blocks - cached_blocks,
+        stats.central_free_bytes
+    )?;
+    writeln!(out, "<system type=\"current\" size=\"{}\"/>", stats.arena_bytes)?;
+    writeln!(out, "<system type=\"max\" size=\"{}\"/>", stats.max_arena_bytes)?;
+    writeln!(out, "<aspace type=\"total\" size=\"{}\"/>", stats.arena_bytes)?;
+    writeln!(out, "<aspace type=\"mprotect\" size=\"{}\"/>", stats.arena_bytes)?;
+    writeln!(out, "</heap>")?;
+    writeln!(
+        out,
+        "<total type=\"fast\" count=\"{}\" size=\"{}\"/>",
+        cached_blocks, stats.thread_cache_bytes
+    )?;
+    writeln!(
+        out,
+        "<total type=\"rest\" count=\"{}\" size=\"{}\"/>",
+        free_blocks - cached_blocks,
+        stats.central_free_bytes
+    )?;
+    writeln!(
+        out,
+        "<total type=\"mmap\" count=\"{}\" size=\"{}\"/>",
+        stats.mmapped_count, stats.mmapped_bytes
+    )?;
+    writeln!(out, "<system type=\"current\" size=\"{}\"/>", system)?;
+    writeln!(out, "<system type=\"max\" size=\"{}\"/>", max_system)?;
+    writeln!(out, "<aspace type=\"total\" size=\"{}\"/>", system)?;
+    writeln!(out, "<aspace type=\"mprotect\" size=\"{}\"/>", system)?;
+    writeln!(out, "</malloc>")
+}
+
+/// Writes the state of the heap as XML to the C `stream`. `options` must be 0.
+///
+/// Returns 0 on success. Otherwise, `errno` is set and -1 is returned.
+///
+/// # Safety
+/// `stream` must be NULL or an open `FILE`.
+#[no_mangle]
+pub unsafe extern "C" fn malloc_info(options: i32, stream: *mut libc::FILE) -> i32 {
+    if options != 0 || stream.is_null() {
+        errno::set_errno(errno::Errno(libc::EINVAL));
+        return -1;
+    }
+    let mut xml = String::new();
+    if write_malloc_info(&heap_stats(), &mut xml).is_err() {
+        return -1;
+    }
+    if libc::fwrite(xml.as_ptr() as *const c_void, 1, xml.len(), stream) != xml.len() {
+        return -1;
+    }
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{free, malloc};
+
+    #[test]
+    fn mallinfo_reports_use() {
+        let ptr = malloc(64);
+        let large = malloc(1 << 20);
+        let info = mallinfo2();
+        assert!(info.arena > 0);
+        assert!(info.uordblks >= 64);
+        assert!(info.hblks >= 1);
+        assert!(info.hblkhd >= 1 << 20);
+        assert!(info.arena >= info.uordblks);
+        unsafe {
+            free(ptr);
+            free(large);
+        }
+    }
+
+    #[test]
+    fn malloc_info_writes_xml() {
+        let ptr = malloc(64);
+        let mut xml = String::new();
+        write_malloc_info(&heap_stats(), &mut xml).unwrap();
+        assert!(xml.starts_with("<malloc version=\"1\">"));
+        assert!(xml.trim_end().ends_with("</malloc>"));
+        assert_eq!(
+            xml.matches("<size ").count(),
+            apfmalloc_lib::mem_info::MAX_SZ_IDX - 1,
+            "There should be an entry for every size class"
+        );
+        assert!(xml.contains("<size from=\"57\" to=\"64\""));
+
+        unsafe {
+            assert_eq!(malloc_info(1, std::ptr::null_mut()), -1);
+            let file = libc::tmpfile();
+            assert_eq!(malloc_info(0, file), 0);
+            assert!(libc::ftell(file) > 0);
+            libc::fclose(file);
+            free(ptr);
+        }
+    }
+}
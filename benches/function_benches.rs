@@ -1,10 +1,10 @@
 use criterion::{criterion_group, criterion_main, Criterion};
-use apfmalloc_lib::alloc::malloc_from_new_sb;
+use apfmalloc_lib::alloc::try_malloc_from_new_sb;
 use apfmalloc_lib::allocation_data::Descriptor;
 use apfmalloc_lib::mem_info::{MAX_SZ_IDX, PAGE};
 use apfmalloc_lib::pages::{page_alloc, page_free};
 use apfmalloc_lib::ptr::auto_ptr::AutoPtr;
-use apfmalloc_lib::thread_cache::{fill_cache, ThreadCacheBin};
+use apfmalloc_lib::thread_cache::{try_fill_cache, ThreadCacheBin};
 use std::io::{stdout, Write};
 use std::time::Duration;
 use std::time::Instant;
@@ -15,7 +15,7 @@ fn thread_cache_fill(c: &mut Criterion) {
     c.bench_function("cache fill", |b| {
         let cache = &mut tcache[1];
         b.iter(|| {
-            fill_cache(1, cache);
+            try_fill_cache(1, cache).unwrap();
             cache.pop_list(cache.peek_block(), cache.get_block_num());
         });
     });
@@ -27,7 +27,7 @@ fn alloc_from_super_block(c: &mut Criterion) {
         b.iter(|| {
             let mut cache = ThreadCacheBin::new();
             let mut block_num = 0;
-            malloc_from_new_sb(3, &mut cache, &mut block_num);
+            try_malloc_from_new_sb(3, &mut cache, &mut block_num).unwrap();
             let ptr = cache.peek_block();
             cache.pop_list(ptr, cache.get_block_num());
             page_free(ptr);
@@ -42,7 +42,7 @@ fn alloc_from_super_block_no_free(c: &mut Criterion) {
         b.iter(|| {
             let mut cache = ThreadCacheBin::new();
             let mut block_num = 0;
-            malloc_from_new_sb(3, &mut cache, &mut block_num);
+            try_malloc_from_new_sb(3, &mut cache, &mut block_num).unwrap();
             let ptr = cache.peek_block();
             cache.pop_list(ptr, cache.get_block_num());
             ptrs.push(ptr);
@@ -62,7 +62,7 @@ fn page_free_time(c: &mut Criterion) {
             let mut output = Duration::from_secs(0);
             let mut ptrs = vec![];
             for _ in 0..iters {
-                malloc_from_new_sb(3, &mut cache, &mut block_num);
+                try_malloc_from_new_sb(3, &mut cache, &mut block_num).unwrap();
                 let ptr = cache.peek_block();
                 ptrs.push(ptr);
                 cache.pop_list(ptr, cache.get_block_num());
@@ -1,16 +1,23 @@
 use crate::allocation_data::{
-    get_heaps, Anchor, Descriptor, DescriptorNode, ProcHeap, SuperBlockState,
+    Anchor, Descriptor, DescriptorNode, ProcHeap, SuperBlockState,
 };
-use crate::mem_info::{PAGE, PAGE_MASK};
-use crate::page_map::{PageInfo, S_PAGE_MAP};
+use crate::heap::{global_core, HeapCore};
+use crate::independent_collections::Array;
+use crate::lifetime::Lifetime;
+use crate::mem_info::{MAX_SZ_IDX, PAGE};
+use crate::page_map::PageInfo;
 use crate::size_classes::SIZE_CLASSES;
-use crate::thread_cache::ThreadCacheBin;
+use crate::stats;
+use crate::tags::current_heap;
+use crate::thread_cache::{next_block, ThreadCacheBin};
+use std::ffi::c_void;
 use std::ptr::null_mut;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use crate::pages::external_mem_reservation::{SegAllocator, Segment, SEGMENT_ALLOCATOR};
+use crate::pages::external_mem_reservation::{AllocationError, SegAllocator, Segment, SEGMENT_ALLOCATOR};
 
-pub fn list_pop_partial(heap: &mut ProcHeap) -> Option<&mut Descriptor> {
+/// Pops a superblock off the partial list of `heap`, which many threads can do at once
+pub(crate) fn pop_partial(heap: &ProcHeap) -> Option<&'static mut Descriptor> {
     let list = &heap.partial_list;
 
     loop {
@@ -120,8 +127,36 @@ pub fn heap_push_partial(desc: &'static mut Descriptor) {
     list_push_partial(desc)
 }
 
+pub fn list_pop_partial(heap: &mut ProcHeap) -> Option<&mut Descriptor> {
+    pop_partial(heap)
+}
+
 pub fn heap_pop_partial(heap: &mut ProcHeap) -> Option<&mut Descriptor> {
-    list_pop_partial(heap)
+    pop_partial(heap)
+}
+
+/// Takes every free block of a superblock that was popped from a partial list, and returns its anchor from before they
+/// were taken. A superblock that is EMPTY is reused as a whole, and no longer counts as EMPTY for its heap.
+fn take_partial_blocks(desc: &mut Descriptor) -> Anchor {
+    let max_count = desc.max_count;
+    loop {
+        let old_anchor = desc.anchor.load(Ordering::Acquire);
+        let mut new_anchor = old_anchor;
+        new_anchor.set_count(0);
+        new_anchor.set_avail(max_count as u64);
+        new_anchor.set_state(SuperBlockState::FULL);
+
+        if desc
+            .anchor
+            .compare_exchange(old_anchor, new_anchor, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            if old_anchor.state() == SuperBlockState::EMPTY {
+                unsafe { (*desc.proc_heap).empty_superblocks.fetch_sub(1, Ordering::AcqRel) };
+            }
+            return old_anchor;
+        }
+    }
 }
 
 pub fn malloc_from_partial(
@@ -129,81 +164,111 @@ pub fn malloc_from_partial(
     cache: &mut ThreadCacheBin,
     block_num: &mut usize,
 ) {
-    let heap = get_heaps().get_heap_at_mut(size_class_index);
-    let desc = heap_pop_partial(heap);
-
-    match desc {
-        None => {}
-        Some(desc) => {
-            //info!("Allocating blocks from a partial list...");
-            let old_anchor = desc.anchor.load(Ordering::Acquire);
-            let mut new_anchor: Anchor;
-
-            let max_count = desc.max_count;
-            let block_size = desc.block_size;
-
-            let super_block = &desc.super_block;
-
-            loop {
-                if old_anchor.state() == SuperBlockState::EMPTY {
-                    //info!("Retiring an empty descriptor");
-                    desc.retire();
-                    return malloc_from_partial(size_class_index, cache, block_num);
-                }
+    malloc_from_heap_partial(current_heap(size_class_index), cache, block_num)
+}
 
-                // debug_assert_eq!(old_anchor.state(), SuperBlockState::PARTIAL);
-                //info!("Found old anchor {:?}", old_anchor);
-                new_anchor = old_anchor;
-                new_anchor.set_count(0);
-                new_anchor.set_avail(max_count as u64);
-                new_anchor.set_state(SuperBlockState::FULL);
-
-                if desc
-                    .anchor
-                    .compare_exchange(old_anchor, new_anchor, Ordering::Acquire, Ordering::Relaxed)
-                    .is_ok()
-                {
-                    break;
-                }
-            }
+/// Pushes every free block of the first superblock on the partial list of `heap` to `cache`, which must be empty. If
+/// the list is empty, `cache` is left unchanged.
+pub(crate) fn malloc_from_heap_partial(heap: &ProcHeap, cache: &mut ThreadCacheBin, block_num: &mut usize) {
+    let desc = match pop_partial(heap) {
+        None => return,
+        Some(desc) => desc,
+    };
+    //info!("Allocating blocks from a partial list...");
+    let old_anchor = take_partial_blocks(desc);
 
-            let blocks_taken = old_anchor.count() as isize;
-            let avail = old_anchor.avail() as isize;
-            let super_block = super_block.as_ref().unwrap().get_ptr() as *mut u8;
-            assert!(
-                avail <= max_count as isize,
-                "Avail: {}, Count {}",
-                avail,
-                max_count
-            );
-            let block = unsafe { super_block.offset(avail * block_size as isize) };
-            assert_eq!(cache.get_block_num(), 0);
-            cache.push_list(block, blocks_taken as u32);
-            *block_num += 1;
-        }
-    }
+    let max_count = desc.max_count;
+    let block_size = desc.block_size;
+    let blocks_taken = old_anchor.count() as usize;
+    let avail = old_anchor.avail() as usize;
+    let super_block = desc.super_block.as_ref().unwrap().get_ptr() as *mut u8;
+    assert!(
+        avail <= max_count as usize,
+        "Avail: {}, Count {}",
+        avail,
+        max_count
+    );
+    let block = unsafe { super_block.add(avail * block_size as usize) };
+    assert_eq!(cache.get_block_num(), 0);
+    cache.push_list(block, blocks_taken as u32);
+    *block_num += blocks_taken;
 }
 
-pub fn malloc_from_new_sb(
+pub fn try_malloc_from_new_sb(
     size_class_index: usize,
     cache: &mut ThreadCacheBin,
     block_num: &mut usize,
-) {
-    let heap = get_heaps().get_heap_at_mut(size_class_index);
+) -> Result<(), AllocationError> {
+    let heap = current_heap(size_class_index);
+    let max_count = unsafe { SIZE_CLASSES[size_class_index].get_block_num() };
+    new_superblock(heap, cache, max_count)?;
+    *block_num += max_count;
+    Ok(())
+}
+
+/// Fills `cache` from a new superblock, panicking if it can't be mapped.
+#[deprecated(note = "use `try_malloc_from_new_sb()`, which returns the error instead of panicking")]
+pub fn malloc_from_new_sb(size_class_index: usize, cache: &mut ThreadCacheBin, block_num: &mut usize) {
+    try_malloc_from_new_sb(size_class_index, cache, block_num).expect("Could not map a superblock")
+}
+
+/// Maps a new superblock for the size class of `heap`, and pushes up to `count` of its blocks on top of `cache`. The
+/// descriptor of the superblock is registered in the page map before it is returned. If not all of the blocks were
+/// pushed, the rest are left in the superblock, which is pushed to the partial list of `heap`.
+///
+/// If the superblock or its descriptor can not be mapped, an error is returned and `cache` is left unchanged.
+pub(crate) fn new_superblock(
+    heap: &ProcHeap,
+    cache: &mut ThreadCacheBin,
+    count: usize,
+) -> Result<*mut Descriptor, AllocationError> {
+    new_superblock_in(global_core(), heap, cache, count)
+}
+
+/// Maps a new superblock in the same way as [`new_superblock()`](fn.new_superblock.html), for a heap of the instance
+/// `core`, which gives it its descriptor and registers it in its page map
+pub(crate) fn new_superblock_in(
+    core: &HeapCore,
+    heap: &ProcHeap,
+    cache: &mut ThreadCacheBin,
+    count: usize,
+) -> Result<*mut Descriptor, AllocationError> {
+    let size_class_index = heap.get_size_class_index();
     let sc = unsafe { &SIZE_CLASSES[size_class_index] };
 
-    let desc = unsafe { &mut *Descriptor::alloc() };
-    // debug_assert!(!desc.is_null());
+    let segment = SEGMENT_ALLOCATOR.allocate(sc.sb_size as usize)?;
+    let desc = match unsafe { core.descriptors.try_alloc() } {
+        Ok(desc) => unsafe { &mut *desc },
+        Err(e) => {
+            unsafe { SEGMENT_ALLOCATOR.deallocate(segment) };
+            return Err(e);
+        }
+    };
 
     let block_size = sc.block_size;
     let max_count = sc.get_block_num();
+    let taken = max_count.min(count);
+
+    // only the blocks of the global heap are checked
+    #[cfg(feature = "debug_checks")]
+    if core.is_global() {
+        if let Err(e) = desc.block_states.map(max_count) {
+            core.descriptors.retire(desc);
+            unsafe { SEGMENT_ALLOCATOR.deallocate(segment) };
+            return Err(e);
+        }
+    }
 
+    // set before any block of the superblock is handed out, so that no block of it is freed as a default block
+    if core.is_global() && (heap.tag != 0 || heap.lifetime == Lifetime::Long) {
+        SEGREGATED_CLASSES[size_class_index].store(true, Ordering::Release);
+    }
+
+    let super_block = segment.get_ptr() as *mut u8;
     desc.proc_heap = heap;
     desc.block_size = block_size;
     desc.max_count = max_count as u32;
-    desc.super_block = SEGMENT_ALLOCATOR.allocate(sc.sb_size as usize).ok();
-
-    let super_block = desc.super_block.as_ref().unwrap().get_ptr() as *mut u8;
+    desc.super_block = Some(segment);
 
     #[cfg(not(feature = "no_met_stack"))]
         {
@@ -217,7 +282,7 @@ pub fn malloc_from_new_sb(
         {
             unsafe {
                 // let mut ptr = super_block.add(block_size as usize * max_count - block_size as usize) as *mut *mut u8;
-                let mut last: *mut u8 = null_mut();
+                let mut last: *mut u8 = std::ptr::null_mut();
                 for block_num in (0..desc.max_count).rev() {
                     let current = super_block.add((block_size * block_num) as usize);
                     *(current as *mut *mut u8) = last;
@@ -227,153 +292,330 @@ pub fn malloc_from_new_sb(
             }
         }
 
-    let block = super_block;
-    cache.push_list(block, max_count as u32);
+    cache.push_fresh_list(super_block, taken as u32, block_size);
 
     let mut anchor: Anchor = Anchor::default();
-    anchor.set_avail(max_count as u64);
-    anchor.set_count(0);
-    anchor.set_state(SuperBlockState::FULL);
+    anchor.set_avail(taken as u64);
+    anchor.set_count((max_count - taken) as u64);
+    if taken == max_count {
+        anchor.set_state(SuperBlockState::FULL);
+    } else {
+        anchor.set_state(SuperBlockState::PARTIAL);
+    }
 
     desc.anchor.store(anchor, Ordering::SeqCst);
 
-    register_desc(desc);
-    *block_num += max_count;
+    core.page_map.register(desc);
+    let ptr = desc as *mut Descriptor;
+    if taken < max_count {
+        heap_push_partial(desc);
+    }
+    Ok(ptr)
 }
 
-/* WARNING -- ELIAS CODE -- WARNING */
+/// Set for each size class once the global instance has made a superblock of the class for a tag other than 0 or for
+/// long-lived blocks. Until then, every block of the class belongs to the default heap of the class.
+static SEGREGATED_CLASSES: [AtomicBool; MAX_SZ_IDX] = [const { AtomicBool::new(false) }; MAX_SZ_IDX];
 
-pub fn malloc_count_from_partial(
+/// Whether a block of the size class may belong to a heap other than its default one, in which case the thread cache it
+/// is freed to depends on its superblock
+#[inline]
+pub(crate) fn class_segregated(size_class_index: usize) -> bool {
+    SEGREGATED_CLASSES[size_class_index].load(Ordering::Acquire)
+}
+
+/// The most EMPTY superblocks a heap keeps on its partial list to be reused. The ones over this are given back to the
+/// OS as soon as they become EMPTY.
+pub(crate) const MAX_EMPTY_SUPERBLOCKS: usize = 2;
+
+/// Returns the `block_count` blocks linked from `head` to `tail` to their superblock. A superblock that was FULL is
+/// pushed to the partial list of its heap. Once all of the blocks of a superblock are free, it is marked as EMPTY, and
+/// stays on the partial list until it is either reused or released. Superblocks are released when their heap has more
+/// than [`MAX_EMPTY_SUPERBLOCKS`](constant.MAX_EMPTY_SUPERBLOCKS.html) EMPTY superblocks, and the rest are released by
+/// [`purge()`](../fn.purge.html).
+pub(crate) fn return_blocks(
+    desc: &'static mut Descriptor,
+    head: *mut u8,
+    tail: *mut u8,
+    block_count: u32,
     size_class_index: usize,
-    cache: &mut ThreadCacheBin,
-    block_num: &mut usize,
-    count: usize,
 ) {
-    let heap = get_heaps().get_heap_at_mut(size_class_index);
-    let desc = heap_pop_partial(heap);
+    return_blocks_in(global_core(), desc, head, tail, block_count, size_class_index)
+}
 
-    match desc {
-        None => {
-            return;
+/// Returns blocks to their superblock in the same way as [`return_blocks()`](fn.return_blocks.html), for a superblock
+/// of the instance `core`
+pub(crate) fn return_blocks_in(
+    core: &HeapCore,
+    desc: &'static mut Descriptor,
+    head: *mut u8,
+    tail: *mut u8,
+    block_count: u32,
+    size_class_index: usize,
+) {
+    let super_block = desc.super_block.as_ref().unwrap().get_ptr() as *mut u8;
+    let block_size = desc.block_size as usize;
+    let index = compute_index(super_block, head, size_class_index);
+    // Once the blocks are published, a superblock that was PARTIAL can be taken off of the list as EMPTY and reused,
+    // so nothing is read from the descriptor after the exchange
+    let heap = unsafe { &*desc.proc_heap };
+    let max_count = desc.max_count as u64;
+
+    let (old_anchor, emptied) = loop {
+        let old_anchor = desc.anchor.load(Ordering::Acquire);
+        unsafe {
+            // update avail
+            *(tail as *mut *mut u8) = super_block.add(old_anchor.avail() as usize * block_size);
         }
-        Some(desc) => {
-            //info!("Allocating blocks from a partial list...");
-            let old_anchor = desc.anchor.load(Ordering::Acquire);
-            let mut new_anchor: Anchor;
-
-            let max_count = desc.max_count;
-            let block_size = desc.block_size;
-
-            let super_block = &desc.super_block;
-
-            let avail = old_anchor.avail() as isize;
-            let new_avail = match avail + (count as isize) < max_count as isize {
-                true => avail + count as isize,
-                false => max_count as isize,
-            };
-
-            loop {
-                if old_anchor.state() == SuperBlockState::EMPTY {
-                    //info!("Retiring an empty descriptor");
-                    desc.retire();
-                    return malloc_from_partial(size_class_index, cache, block_num);
-                }
 
-                // debug_assert_eq!(old_anchor.state(), SuperBlockState::PARTIAL);
-                //info!("Found old anchor {:?}", old_anchor);
-                new_anchor = old_anchor;
-                new_anchor.set_count(0);
-                new_anchor.set_avail(new_avail as u64);
-                new_anchor.set_state(SuperBlockState::FULL);
-
-                if desc
-                    .anchor
-                    .compare_exchange(old_anchor, new_anchor, Ordering::Acquire, Ordering::Relaxed)
-                    .is_ok()
-                {
-                    break;
-                }
-            }
+        let mut new_anchor = old_anchor;
+        new_anchor.set_avail(index as u64);
+        let count = old_anchor.count() + block_count as u64;
+        new_anchor.set_count(count);
+        if count == max_count {
+            new_anchor.set_state(SuperBlockState::EMPTY);
+        } else {
+            new_anchor.set_state(SuperBlockState::PARTIAL);
+        }
 
-            assert!(
-                avail <= max_count as isize,
-                "Avail: {}, Count {}",
-                avail,
-                max_count
-            );
-
-            let c = new_avail - avail;
-            let super_block = super_block.as_ref().unwrap().get_ptr() as *mut u8;
-            for i in 0..c {
-                let block =
-                    unsafe { super_block.offset((avail + i as isize) * block_size as isize) };
-                cache.push_block(block);
-                *block_num += 1;
-            }
+        if desc
+            .anchor
+            .compare_exchange_weak(old_anchor, new_anchor, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            break (old_anchor, new_anchor.state() == SuperBlockState::EMPTY);
         }
+    };
+
+    // a superblock that wasn't FULL is already on the partial list
+    if old_anchor.state() == SuperBlockState::FULL {
+        heap_push_partial(desc)
+    }
+    if emptied && heap.empty_superblocks.fetch_add(1, Ordering::AcqRel) >= MAX_EMPTY_SUPERBLOCKS as isize {
+        release_empty_superblocks_in(core, heap, MAX_EMPTY_SUPERBLOCKS);
     }
 }
 
-pub fn malloc_count_from_new_sb(
-    size_class_index: usize,
-    cache: &mut ThreadCacheBin,
-    block_num: &mut usize,
-    count: usize,
-) {
-    let heap = get_heaps().get_heap_at_mut(size_class_index);
-    let sc = unsafe { &SIZE_CLASSES[size_class_index] };
+/// Unmaps every EMPTY superblock on the partial list of `heap`, and returns the number of bytes that were given back to
+/// the OS. The rest of the superblocks are put back on the list.
+pub(crate) fn release_empty_superblocks(heap: &'static ProcHeap) -> usize {
+    release_empty_superblocks_in(global_core(), heap, 0)
+}
 
-    let desc = unsafe { &mut *Descriptor::alloc() };
-    // debug_assert!(!desc.is_null());
+/// Unmaps the EMPTY superblocks of `heap` in the same way as
+/// [`release_empty_superblocks()`](fn.release_empty_superblocks.html), for a heap of the instance `core`, except for
+/// the first `keep` of them
+pub(crate) fn release_empty_superblocks_in(core: &HeapCore, heap: &'static ProcHeap, keep: usize) -> usize {
+    let size_class_index = heap.get_size_class_index();
+    let mut released = 0;
+    let mut keep = keep;
+    let mut kept: Option<DescriptorNode> = None;
+
+    // Superblocks taken off of the list belong to this thread, so an EMPTY one can not be reused while it is released
+    while let Some(desc) = pop_partial(heap) {
+        let empty = desc.anchor.load(Ordering::Acquire).state() == SuperBlockState::EMPTY;
+        if empty && keep == 0 {
+            if let Some(segment) = desc.super_block.take() {
+                core.page_map.unregister(Some(heap), &segment);
+                if core.is_global() {
+                    stats::superblock_unmapped(size_class_index);
+                }
+                released += segment.len();
+                unsafe {
+                    SEGMENT_ALLOCATOR.deallocate(segment);
+                }
+            }
+            heap.empty_superblocks.fetch_sub(1, Ordering::AcqRel);
+            core.descriptors.retire(desc);
+        } else {
+            if empty {
+                keep -= 1;
+            }
+            desc.next_partial.store(kept, Ordering::Relaxed);
+            kept = Some(DescriptorNode::from(desc as *mut Descriptor));
+        }
+    }
 
-    let block_size = sc.block_size;
-    let max_count = sc.get_block_num();
+    while let Some(desc) = kept.and_then(|node| node.get_desc()) {
+        kept = desc.next_partial.load(Ordering::Relaxed);
+        heap_push_partial(desc);
+    }
 
-    let c = max_count.min(count);
+    released
+}
 
-    desc.proc_heap = heap;
-    desc.block_size = block_size;
-    desc.max_count = max_count as u32;
-    desc.super_block = SEGMENT_ALLOCATOR.allocate(sc.block_size as usize * c).ok();
+/// Gives the pages of the superblocks on the partial list of `heap` that only hold free blocks back to the OS, keeping
+/// the superblocks themselves, and returns the number of bytes that were resident in those pages.
+pub(crate) fn trim_partial_superblocks(heap: &'static ProcHeap) -> usize {
+    // Every superblock is taken off of the list first, as trimming one pushes it back
+    let mut popped: Option<DescriptorNode> = None;
+    while let Some(desc) = pop_partial(heap) {
+        desc.next_partial.store(popped, Ordering::Relaxed);
+        popped = Some(DescriptorNode::from(desc as *mut Descriptor));
+    }
 
-    let super_block = desc.super_block.as_ref().unwrap().get_ptr() as *mut u8;
+    let mut free = Array::<bool>::new();
+    let mut pages = Array::<bool>::new();
+    let mut released = 0;
+    while let Some(desc) = popped.and_then(|node| node.get_desc()) {
+        popped = desc.next_partial.load(Ordering::Relaxed);
+        let old_anchor = take_partial_blocks(desc);
+        if old_anchor.count() > 0 {
+            released += unsafe { trim_free_blocks(desc, old_anchor, &mut free, &mut pages) };
+        }
+    }
+    released
+}
 
+/// Releases the pages of a superblock that are only made up of the free blocks taken from it with `old_anchor`, then
+/// gives the blocks back to the superblock. Returns the number of bytes that were resident in the released pages.
+///
+/// The free blocks are linked again in the order of their addresses. A released page reads as zero, which links a block
+/// to the block right after it, so a page is only released if the blocks that start in it are followed by another free
+/// block. `free` and `pages` are only kept between calls so that they don't have to be mapped again.
+unsafe fn trim_free_blocks(
+    desc: &'static mut Descriptor,
+    old_anchor: Anchor,
+    free: &mut Array<bool>,
+    pages: &mut Array<bool>,
+) -> usize {
+    let super_block = desc.super_block.as_ref().unwrap().get_ptr() as *mut u8;
+    let block_size = desc.block_size as usize;
+    let max_count = desc.max_count as usize;
+    let page_count = max_count * block_size / PAGE;
+    let count = old_anchor.count() as u32;
+
+    free.grow(max_count);
+    free[..max_count].iter_mut().for_each(|free| *free = false);
+    let mut block = super_block.add(old_anchor.avail() as usize * block_size);
+    for _ in 0..count {
+        free[(block as usize - super_block as usize) / block_size] = true;
+        block = next_block(block, desc.block_size);
+    }
 
+    // whether the block at `index` has to be linked with a pointer instead of a 0
+    let needs_link =
+        |free: &Array<bool>, index: usize| cfg!(feature = "no_met_stack") || index + 1 == max_count || !free[index + 1];
+    pages.grow(page_count);
+    for (page, releasable) in pages[..page_count].iter_mut().enumerate() {
+        let start = page * PAGE;
+        let first = start / block_size;
+        let last = (start + PAGE - 1) / block_size;
+        let first_start = start.div_ceil(block_size);
+        *releasable = (first..=last).all(|index| free[index])
+            && (first_start..=last).all(|index| !needs_link(free, index));
+    }
 
-    #[cfg(not(feature = "no_met_stack"))]
-        {
-            unsafe {
-                // Gets a pointer to the last block and sets it to 0xFF...F
-                let ptr = super_block.add(block_size as usize * c - block_size as usize);
-                *(ptr as *mut usize) = std::usize::MAX;
-            }
+    let mut released = 0;
+    let mut page = 0;
+    while page < page_count {
+        let run = pages[..page_count][page..].iter().take_while(|&&releasable| releasable).count();
+        if run > 0 {
+            let start = super_block.add(page * PAGE) as *mut c_void;
+            released += SEGMENT_ALLOCATOR.release_pages(start, run * PAGE);
         }
-    #[cfg(feature = "no_met_stack")]
-        {
-            unsafe {
-                // let mut ptr = super_block.add(block_size as usize * max_count - block_size as usize) as *mut *mut u8;
-                let mut last: *mut u8 = null_mut();
-                for block_num in (0..c).rev() {
-                    let current = super_block.add((block_size as usize * block_num) as usize);
-                    *(current as *mut *mut u8) = last;
-                    last = current;
-                    // ptr = current as *mut *mut u8;
-                }
+        page += run + 1;
+    }
+
+    let in_released_page = |index: usize| {
+        let page = index * block_size / PAGE;
+        page < page_count && pages[page]
+    };
+    let mut previous: Option<usize> = None;
+    for index in (0..max_count).filter(|&index| free[index]) {
+        if let Some(previous) = previous {
+            if !in_released_page(previous) {
+                let link = if needs_link(free, previous) {
+                    super_block.add(index * block_size)
+                } else {
+                    null_mut()
+                };
+                *(super_block.add(previous * block_size) as *mut *mut u8) = link;
             }
         }
+        previous = Some(index);
+    }
 
-    let block = super_block;
-    cache.push_list(block, c as u32);
+    let head = super_block.add(free[..max_count].iter().position(|&free| free).unwrap() * block_size);
+    let tail = super_block.add(previous.unwrap() * block_size);
+    let size_class_index = (*desc.proc_heap).get_size_class_index();
+    return_blocks(desc, head, tail, count, size_class_index);
+    released
+}
 
-    let mut anchor: Anchor = Anchor::default();
-    anchor.set_avail(c as u64);
-    anchor.set_count(0);
-    anchor.set_state(SuperBlockState::FULL);
+/* WARNING -- ELIAS CODE -- WARNING */
 
-    desc.anchor.store(anchor, Ordering::SeqCst);
+pub fn malloc_count_from_partial(
+    size_class_index: usize,
+    cache: &mut ThreadCacheBin,
+    block_num: &mut usize,
+    count: usize,
+) {
+    malloc_count_from_partial_in(global_core(), current_heap(size_class_index), cache, block_num, count)
+}
 
-    register_desc(desc);
-    *block_num += max_count;
+/// Pushes up to `count` free blocks of the first superblock on the partial list of `heap`, a heap of the instance
+/// `core`, to `cache`. The blocks that are not needed are returned to the superblock.
+pub(crate) fn malloc_count_from_partial_in(
+    core: &HeapCore,
+    heap: &ProcHeap,
+    cache: &mut ThreadCacheBin,
+    block_num: &mut usize,
+    count: usize,
+) {
+    let desc = match pop_partial(heap) {
+        None => return,
+        Some(desc) => desc,
+    };
+    let old_anchor = take_partial_blocks(desc);
+
+    let block_size = desc.block_size;
+    let super_block = desc.super_block.as_ref().unwrap().get_ptr() as *mut u8;
+    let available = old_anchor.count() as usize;
+    let taken = available.min(count);
+
+    let mut block = unsafe { super_block.add(old_anchor.avail() as usize * block_size as usize) };
+    for _ in 0..taken {
+        let next = next_block(block, block_size);
+        cache.push_block(block);
+        block = next;
+    }
+    *block_num += taken;
+
+    // The blocks that weren't needed go back to the superblock
+    if taken < available {
+        let head = block;
+        let mut tail = head;
+        for _ in 1..available - taken {
+            tail = next_block(tail, block_size);
+        }
+        return_blocks_in(core, desc, head, tail, (available - taken) as u32, heap.get_size_class_index());
+    }
+}
+
+pub fn try_malloc_count_from_new_sb(
+    size_class_index: usize,
+    cache: &mut ThreadCacheBin,
+    block_num: &mut usize,
+    count: usize,
+) -> Result<(), AllocationError> {
+    let heap = current_heap(size_class_index);
+    let max_count = unsafe { SIZE_CLASSES[size_class_index].get_block_num() };
+
+    new_superblock(heap, cache, count)?;
+    *block_num += max_count.min(count);
+    Ok(())
+}
+
+/// Fills `cache` with up to `count` blocks from a new superblock, panicking if it can't be mapped.
+#[deprecated(note = "use `try_malloc_count_from_new_sb()`, which returns the error instead of panicking")]
+pub fn malloc_count_from_new_sb(
+    size_class_index: usize,
+    cache: &mut ThreadCacheBin,
+    block_num: &mut usize,
+    count: usize,
+) {
+    try_malloc_count_from_new_sb(size_class_index, cache, block_num, count).expect("Could not map a superblock")
 }
 
 /* END ELIAS CODE */
@@ -384,53 +626,19 @@ pub fn update_page_map(
     desc: Option<&mut Descriptor>,
     size_class_index: usize,
 ) {
-    if ptr.is_null() {
-        panic!("Pointer should not be null");
-    }
-
-    let mut info: PageInfo = PageInfo::default();
-    info.set_ptr(
-        desc.map_or(null_mut(), |d| d as *mut Descriptor),
-        size_class_index,
-    );
-    if heap.is_none() {
-        unsafe {
-            S_PAGE_MAP.set_page_info(ptr, info);
-            return;
-        }
-    }
-
-    let heap = heap.unwrap();
-    let sb_size = heap.get_size_class().sb_size;
-    assert_eq!(
-        sb_size & PAGE_MASK as u32,
-        0,
-        "sb_size must be a multiple of a page"
-    );
-    for index in 0..(sb_size / PAGE as u32) {
-        unsafe {
-            S_PAGE_MAP.set_page_info(ptr.offset((index * PAGE as u32) as isize), info.clone())
-        }
-    }
+    global_core().page_map.update(heap.map(|heap| &*heap), ptr, desc, size_class_index)
 }
 
 pub fn register_desc(desc: &mut Descriptor) {
-    let heap = if desc.proc_heap.is_null() {
-        None
-    } else {
-        Some(unsafe { &mut *desc.proc_heap })
-    };
-    let ptr = desc.super_block.as_ref().unwrap().get_ptr() as *mut u8;
-    let size_class_index = 0;
-    update_page_map(heap, ptr, Some(desc), size_class_index);
+    global_core().page_map.register(desc)
 }
 
 pub fn unregister_desc(heap: Option<&mut ProcHeap>, super_block: &Segment) {
-    update_page_map(heap, super_block.get_ptr() as *mut u8, None, 0)
+    global_core().page_map.unregister(heap.map(|heap| &*heap), super_block)
 }
 
 pub fn get_page_info_for_ptr<T: ?Sized>(ptr: *const T) -> PageInfo {
-    unsafe { S_PAGE_MAP.get_page_info(ptr) }.clone()
+    global_core().page_map.get_page_info(ptr)
 }
 
 macro_rules! size_classes_match {
@@ -501,7 +709,7 @@ pub fn compute_index(super_block: *mut u8, block: *mut u8, size_class_index: usi
     let _sc_block_size = sc.block_size;
     debug_assert!(block >= super_block);
     debug_assert!(block < unsafe { super_block.offset(sc.sb_size as isize) });
-    let diff = block as u32 - super_block as u32;
+    let diff = (block as usize - super_block as usize) as u32;
     let mut index = 0;
     let _found = size_classes_match![
         index,
@@ -565,7 +773,7 @@ mod test {
         });
 
         let cache = &mut tcache[1];
-        malloc_from_new_sb(1, cache, &mut 0);
+        try_malloc_from_new_sb(1, cache, &mut 0).unwrap();
         assert!(cache.block_num > 0);
     }
 }
@@ -11,7 +11,9 @@ pub enum SuperBlockState {
 mod desc;
 mod proc_heap;
 pub use desc::{Descriptor, DescriptorNode};
-pub use proc_heap::{get_heaps, Heaps, ProcHeap};
+pub(crate) use desc::DescriptorPool;
+#[allow(deprecated)]
+pub use proc_heap::{get_heaps, global_heaps, Heaps, ProcHeap};
 
 impl From<u64> for SuperBlockState {
     fn from(u: u64) -> Self {
@@ -1,13 +1,17 @@
-use std::ptr::null_mut;
+use std::ptr::{null, null_mut};
 
 use atomic::{Atomic, Ordering};
+use spin::Mutex;
 
 use crate::allocation_data::proc_heap::ProcHeap;
+use crate::arena::ArenaHeap;
+#[cfg(feature = "debug_checks")]
+use crate::debug_checks::BlockStates;
 use crate::independent_collections::Array;
-use crate::mem_info::{CACHE_LINE_MASK, DESCRIPTOR_BLOCK_SZ};
-use crate::pages::external_mem_reservation::Segment;
-use crate::pages::page_alloc;
-use crate::AVAILABLE_DESC;
+use crate::mem_info::{CACHE_LINE_MASK, DESCRIPTOR_BLOCK_SZ, MAX_SZ};
+use crate::pages::external_mem_reservation::{AllocationError, Segment};
+use crate::heap::global_core;
+use crate::pages::{page_alloc, page_free};
 
 use super::Anchor;
 
@@ -75,9 +79,14 @@ pub struct Descriptor {
     pub next_partial: Atomic<Option<DescriptorNode>>,
     pub anchor: Atomic<Anchor>,
     pub super_block: Option<Segment>,
-    pub proc_heap: *mut ProcHeap,
+    pub proc_heap: *const ProcHeap,
     pub block_size: u32,
     pub max_count: u32,
+    /// The arena the superblock belongs to, or null if it belongs to the global heaps
+    pub arena: *const ArenaHeap,
+    /// Which blocks of the superblock are allocated, used to find double frees
+    #[cfg(feature = "debug_checks")]
+    pub block_states: BlockStates,
 }
 
 //
@@ -89,54 +98,127 @@ impl Default for Descriptor {
             next_partial: Atomic::new(None),
             anchor: Atomic::new(Anchor::default()),
             super_block: None,
-            proc_heap: null_mut(),
+            proc_heap: null(),
             block_size: 0,
             max_count: 0,
+            arena: null(),
+            #[cfg(feature = "debug_checks")]
+            block_states: BlockStates::default(),
         }
     }
 }
 
-/*
-/// The intitial descriptor holder
-struct DescriptorHolder {}
+impl Descriptor {
+    /// Gives the descriptor back to the descriptors of the global heap, so that it can be reused
+    pub fn retire(&'static mut self) {
+        global_core().descriptors.retire(self)
+    }
 
+    /// Whether the descriptor belongs to a large allocation instead of a superblock
+    pub fn is_large(&self) -> bool {
+        self.block_size as usize > MAX_SZ
+    }
 
-lazy_static! {
-static ref DESCRIPTORS_SPACE
+    /// Calls `f` on every descriptor of the global heap that has a superblock or large allocation. Retired descriptors,
+    /// and descriptors that were taken but not yet given a segment, are skipped.
+    ///
+    /// # Safety
+    /// No descriptor may be given or take a segment while this runs.
+    pub(crate) unsafe fn for_each_live<F: FnMut(&'static Descriptor)>(f: F) {
+        global_core().descriptors.for_each_live(f)
+    }
 
+    pub unsafe fn alloc() -> *mut Descriptor {
+        Self::try_alloc().expect("Creating a descriptor block failed")
+    }
+
+    /// Takes a descriptor from the descriptors of the global heap. Returns an error if a new block of descriptors was
+    /// needed and could not be mapped.
+    ///
+    /// # Safety
+    /// The descriptor must be given back with [`retire()`](#method.retire) once it is no longer used.
+    pub unsafe fn try_alloc() -> Result<*mut Descriptor, AllocationError> {
+        global_core().descriptors.try_alloc()
+    }
 }
 
- */
+/// The descriptors of a heap. Descriptors are mapped in blocks, which are kept until the pool is released, and the
+/// descriptors that are not in use are kept on a list.
+pub(crate) struct DescriptorPool {
+    available: Mutex<DescriptorNode>,
+    /// The start of every block of descriptors that has been mapped, so every descriptor of the pool is in one of them
+    blocks: Mutex<Array<usize>>,
+}
 
-impl Descriptor {
-    pub fn retire(&'static mut self) {
-        self.block_size = 0;
-        let mut avail = AVAILABLE_DESC.lock();
+impl DescriptorPool {
+    pub(crate) const fn new() -> Self {
+        Self {
+            available: Mutex::new(DescriptorNode::new()),
+            blocks: Mutex::new(Array::new()),
+        }
+    }
+
+    /// Puts a descriptor that is no longer used back on the list of available descriptors
+    pub(crate) fn retire(&self, desc: &'static mut Descriptor) {
+        #[cfg(feature = "debug_checks")]
+        desc.block_states.unmap();
+        desc.block_size = 0;
+        desc.arena = null();
+        let mut avail = self.available.lock();
         let old_head = *avail;
         let mut new_head: DescriptorNode = DescriptorNode::default();
-        self.next_free.store(Some(old_head), Ordering::Release);
+        desc.next_free.store(Some(old_head), Ordering::Release);
 
-        new_head.set(Some(self), old_head.get_counter() + 1);
+        new_head.set(Some(desc), old_head.get_counter() + 1);
         *avail = new_head;
-        /*
-           if {
-               AVAILABLE_DESC
-                   .compare_exchange_weak(old_head, new_head, Ordering::Acquire, Ordering::Release)
-                   .is_ok()
-           } {
-               break;
-           }
+    }
 
-        */
+    /// Gives every block of descriptors back to the OS
+    ///
+    /// # Safety
+    /// None of the descriptors of the pool may be used again.
+    pub(crate) unsafe fn release(&self) {
+        let mut blocks = self.blocks.lock();
+        let mapped: &[usize] = &blocks;
+        for &block in mapped {
+            page_free(block as *const u8);
+        }
+        *blocks = Array::new();
+        *self.available.lock() = DescriptorNode::new();
     }
 
-    pub unsafe fn alloc() -> *mut Descriptor {
-        let mut avail = AVAILABLE_DESC.lock();
+    /// Calls `f` on every descriptor that has a superblock or large allocation. Retired descriptors, and descriptors
+    /// that were taken but not yet given a segment, are skipped.
+    ///
+    /// # Safety
+    /// No descriptor may be given or take a segment while this runs.
+    pub(crate) unsafe fn for_each_live<F: FnMut(&'static Descriptor)>(&self, mut f: F) {
+        let count = DESCRIPTOR_BLOCK_SZ / std::mem::size_of::<Descriptor>();
+        let blocks = self.blocks.lock();
+        let blocks: &[usize] = &blocks;
+        for &block in blocks {
+            for index in 0..count {
+                let desc = &*(block as *const Descriptor).add(index);
+                if desc.super_block.is_some() {
+                    f(desc);
+                }
+            }
+        }
+    }
+
+    /// Takes a descriptor from the list of available descriptors, mapping a new block of descriptors if the list is
+    /// empty. Returns an error if the block could not be mapped.
+    ///
+    /// # Safety
+    /// The descriptor must be given back to this pool with [`retire()`](#method.retire) once it is no longer used.
+    pub(crate) unsafe fn try_alloc(&self) -> Result<*mut Descriptor, AllocationError> {
+        let mut avail = self.available.lock();
         let old_head = *avail; //AVAILABLE_DESC.load(Ordering::Acquire);
 
         let desc = old_head.get_desc();
         if desc.is_none() {
-            let page = page_alloc(DESCRIPTOR_BLOCK_SZ).expect("Creating a descriptor block failed");
+            let page = page_alloc(DESCRIPTOR_BLOCK_SZ)?;
+            self.blocks.lock().push(page as usize);
             let count = DESCRIPTOR_BLOCK_SZ / std::mem::size_of::<Descriptor>();
             let mut ptr = Array::<Descriptor>::from_ptr(
                 page as *mut Descriptor,
@@ -183,7 +265,7 @@ impl Descriptor {
             *avail = new_head;
             // }
 
-            page as *mut Descriptor
+            Ok(page as *mut Descriptor)
 
         /*
                    //let ret = ptr as *mut MaybeUninit<Descriptor>;
@@ -274,7 +356,7 @@ impl Descriptor {
             }
              */
             *avail = new_head.unwrap_or(DescriptorNode::new());
-            desc
+            Ok(desc as *mut Descriptor)
         }
     }
 }
@@ -1,18 +1,30 @@
 use crate::allocation_data::DescriptorNode;
+use crate::lifetime::{Lifetime, LIFETIMES};
 use crate::mem_info::MAX_SZ_IDX;
 use crate::size_classes::{SizeClassData, SIZE_CLASSES};
 use std::ptr::slice_from_raw_parts_mut;
 
+use crate::heap::{global_core, global_heaps_mut, map_global_heaps};
+use crate::pages::external_mem_reservation::AllocationError;
 use crate::single_access::SingleAccess;
 use atomic::Atomic;
 use bitfield::size_of;
 use memmap::MmapMut;
 use std::mem::MaybeUninit;
+use std::sync::atomic::AtomicIsize;
 
 #[repr(align(64))]
 pub struct ProcHeap {
     pub partial_list: Atomic<Option<DescriptorNode>>,
     pub size_class_index: usize,
+    /// The [allocation tag](../tags/index.html) that the superblocks of this heap are charged to
+    pub tag: u16,
+    /// The [lifetime](../lifetime/index.html) of the blocks in the superblocks of this heap
+    pub lifetime: Lifetime,
+    /// The number of EMPTY superblocks on the partial list, which are kept to be reused. A superblock is only counted
+    /// after it is marked as EMPTY, so another thread can take it off of the count first, and the count can be below
+    /// zero for a moment. It is never above the real number.
+    pub empty_superblocks: AtomicIsize,
 }
 
 impl ProcHeap {
@@ -21,6 +33,9 @@ impl ProcHeap {
         ProcHeap {
             partial_list: ptr,
             size_class_index,
+            tag: 0,
+            lifetime: Lifetime::Short,
+            empty_superblocks: AtomicIsize::new(0),
         }
     }
 
@@ -29,6 +44,9 @@ impl ProcHeap {
         ProcHeap {
             partial_list: ptr,
             size_class_index,
+            tag: 0,
+            lifetime: Lifetime::Short,
+            empty_superblocks: AtomicIsize::new(0),
         }
     }
 
@@ -44,6 +62,9 @@ impl ProcHeap {
         Self {
             partial_list: Atomic::new(None),
             size_class_index: 0,
+            tag: 0,
+            lifetime: Lifetime::Short,
+            empty_superblocks: AtomicIsize::new(0),
         }
     }
 }
@@ -62,24 +83,32 @@ impl Default for ProcHeap {
 pub struct Heaps(Option<MmapMut>);
 
 impl Heaps {
-    const fn uninit() -> Self {
+    pub(crate) const fn uninit() -> Self {
         Heaps(None)
     }
 
-    fn as_heaps_mut(&mut self) -> &mut [ProcHeap] {
-        unsafe {
-            let map = &mut self.0.as_mut().unwrap()[0];
-            let ptr: *mut ProcHeap = (map as *mut u8) as *mut ProcHeap;
-            std::slice::from_raw_parts_mut(ptr, MAX_SZ_IDX)
+    /// Maps the heaps of every size class and lifetime
+    pub(crate) fn map() -> Result<Self, AllocationError> {
+        let len = size_of::<ProcHeap>() * MAX_SZ_IDX * LIFETIMES;
+        let mut map = MmapMut::map_anon(len).map_err(|_| AllocationError::AllocationFailed(len, errno::errno()))?;
+        let ptr = map.as_mut_ptr() as *mut MaybeUninit<ProcHeap>;
+        let slice = unsafe { &mut *slice_from_raw_parts_mut(ptr, MAX_SZ_IDX * LIFETIMES) };
+
+        // The heaps of short-lived blocks come first, followed by the heaps of long-lived blocks
+        for (index, proc) in slice.iter_mut().enumerate() {
+            *proc = MaybeUninit::new(ProcHeap {
+                lifetime: Lifetime::ALL[index / MAX_SZ_IDX],
+                ..ProcHeap::new_none(index % MAX_SZ_IDX)
+            })
         }
+        Ok(Heaps(Some(map)))
     }
 
-    #[allow(unused)]
     fn as_heaps(&self) -> &[ProcHeap] {
         unsafe {
             let map = &self.0.as_ref().unwrap()[0];
             let ptr = map as *const u8 as *const ProcHeap;
-            std::slice::from_raw_parts(ptr, MAX_SZ_IDX)
+            std::slice::from_raw_parts(ptr, MAX_SZ_IDX * LIFETIMES)
         }
     }
 
@@ -89,39 +118,41 @@ impl Heaps {
         // self.0[index].borrow()
     }
 
-    pub fn get_heap_at_mut(&mut self, index: usize) -> &mut ProcHeap {
-        &mut self.as_heaps_mut()[index]
-        //self.0[index].borrow_mut()
+    /// The heap of the `index` size class for blocks with the given `lifetime`
+    pub fn get_heap_for(&self, lifetime: Lifetime, index: usize) -> &ProcHeap {
+        &self.as_heaps()[lifetime as usize * MAX_SZ_IDX + index]
     }
-}
 
-static mut HEAPS: Heaps = Heaps::uninit();
+    /// The heap of the `index` size class for short-lived blocks. Other threads use the heap at the same time, so the
+    /// reference is not unique.
+    #[deprecated(note = "use `get_heap_at()`, as the heaps are shared between threads")]
+    pub fn get_heap_at_mut(&mut self, index: usize) -> &mut ProcHeap {
+        unsafe { &mut *self.heap_ptr(Lifetime::Short, index) }
+    }
 
-unsafe fn init_heaps() {
-    let mut map = MmapMut::map_anon(size_of::<ProcHeap>() * MAX_SZ_IDX)
-        .expect("Should be able to get the map");
-    let ptr = map.as_mut_ptr() as *mut MaybeUninit<ProcHeap>;
-    let slice = &mut *slice_from_raw_parts_mut(ptr, MAX_SZ_IDX);
+    /// A pointer to the heap of the `index` size class for blocks with the given `lifetime`. The partial list of a heap
+    /// is only changed with atomics, so the heap can be used by many threads through it.
+    pub(crate) fn heap_ptr(&self, lifetime: Lifetime, index: usize) -> *mut ProcHeap {
+        &self.as_heaps()[lifetime as usize * MAX_SZ_IDX + index] as *const ProcHeap as *mut ProcHeap
+    }
 
-    for (index, proc) in slice.into_iter().enumerate() {
-        *proc = MaybeUninit::new(ProcHeap::new_none(index))
+    /// Whether `heap` is one of these heaps
+    pub(crate) fn contains(&self, heap: *const ProcHeap) -> bool {
+        self.as_heaps().as_ptr_range().contains(&heap)
     }
-    HEAPS = Heaps(Some(map))
 }
 
-pub fn get_heaps() -> &'static mut Heaps {
+/// The heaps of the global instance, which are mapped the first time this is called
+pub fn global_heaps() -> &'static Heaps {
     static HEAPS_INIT_S: SingleAccess = SingleAccess::new();
 
-    unsafe {
-        /*
-        if !HEAP_INIT.compare_and_swap(false, true, Ordering::Acquire) {
-            init_heaps();
-            //HEAP_INIT.store(true, Ordering::Release)
-        }
-
-         */
-        HEAPS_INIT_S.with(|| init_heaps());
+    HEAPS_INIT_S.with(|| unsafe { map_global_heaps() });
+    &global_core().heaps
+}
 
-        &mut HEAPS
-    }
+/// The heaps of the global instance. Other threads use them at the same time, so the reference is not unique.
+#[deprecated(note = "use `global_heaps()`, as the heaps are shared between threads")]
+pub fn get_heaps() -> &'static mut Heaps {
+    global_heaps();
+    unsafe { global_heaps_mut() }
 }
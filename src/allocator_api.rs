@@ -0,0 +1,209 @@
+//! An implementation of the unstable [`Allocator`](core::alloc::Allocator) trait, so that collections can allocate
+//! from apfmalloc without it being the global allocator. Requires a nightly compiler and the `allocator_api` feature.
+//!
+//! # Example
+//! ```
+//! #![feature(allocator_api)]
+//! use apfmalloc_lib::Apf;
+//!
+//! let mut vec: Vec<usize, Apf> = Vec::with_capacity_in(16, Apf);
+//! vec.extend(0..16);
+//! assert_eq!(vec.iter().sum::<usize>(), 120);
+//! ```
+
+use core::alloc::{AllocError, Allocator, Layout};
+use core::ffi::c_void;
+use core::ptr::NonNull;
+
+use crate::mem_info::PAGE;
+use crate::{
+    aligned_alloc_with_state, do_free_sized, fits_in_place, remap_large, usable_size, zero_allocation, ZeroState,
+};
+
+/// The apfmalloc allocator, for use with collections that take an [`Allocator`](core::alloc::Allocator).
+///
+/// Every allocation is given the whole block it is placed in, so the slices returned by this allocator can be longer
+/// than requested. Growing or shrinking an allocation only moves it when the new size doesn't fit in the block.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Apf;
+
+impl Apf {
+    fn allocate_block(layout: Layout) -> Result<(NonNull<u8>, usize, ZeroState), AllocError> {
+        if layout.size() == 0 {
+            return Ok((dangling(layout), 0, ZeroState::Zeroed));
+        }
+        let (ptr, state) = aligned_alloc_with_state(layout.align(), layout.size());
+        let ptr = NonNull::new(ptr).ok_or(AllocError)?;
+        let usable = usable_size(ptr.as_ptr()).unwrap_or_else(|| layout.size());
+        Ok((ptr, usable, state))
+    }
+
+    /// Changes the size of an allocation, keeping it in place if the new layout fits in the block
+    unsafe fn resize(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if old_layout.size() != 0 && new_layout.size() != 0 {
+            if let Some(usable) = usable_size(ptr.as_ptr()) {
+                if new_layout.align() <= PAGE {
+                    if let Some(new) = remap_large(ptr.as_ptr() as *mut c_void, new_layout.size()) {
+                        let new = NonNull::new_unchecked(new as *mut u8);
+                        let new_usable = usable_size(new.as_ptr()).unwrap_or_else(|| new_layout.size());
+                        // the pages added by the remap are fresh, but the rest of the old segment may not be
+                        if zeroed && new_layout.size() > old_layout.size() {
+                            new.as_ptr()
+                                .add(old_layout.size())
+                                .write_bytes(0, usable.min(new_usable) - old_layout.size());
+                        }
+                        return Ok(NonNull::slice_from_raw_parts(new, new_usable));
+                    }
+                }
+                if fits_in_place(ptr.as_ptr(), usable, new_layout.size(), new_layout.align()) {
+                    if zeroed && new_layout.size() > old_layout.size() {
+                        ptr.as_ptr()
+                            .add(old_layout.size())
+                            .write_bytes(0, usable - old_layout.size());
+                    }
+                    return Ok(NonNull::slice_from_raw_parts(ptr, usable));
+                }
+            }
+        }
+
+        let new = if zeroed {
+            self.allocate_zeroed(new_layout)?
+        } else {
+            self.allocate(new_layout)?
+        };
+        core::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new.as_ptr() as *mut u8,
+            old_layout.size().min(new_layout.size()),
+        );
+        self.deallocate(ptr, old_layout);
+        Ok(new)
+    }
+}
+
+fn dangling(layout: Layout) -> NonNull<u8> {
+    unsafe { NonNull::new_unchecked(layout.align() as *mut u8) }
+}
+
+unsafe impl Allocator for Apf {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let (ptr, usable, _) = Self::allocate_block(layout)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, usable))
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let (ptr, usable, state) = Self::allocate_block(layout)?;
+        unsafe { zero_allocation(ptr.as_ptr(), usable, state) };
+        Ok(NonNull::slice_from_raw_parts(ptr, usable))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            do_free_sized(ptr.as_ptr(), layout.size(), layout.align())
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mem_info::MAX_SZ;
+
+    #[test]
+    fn vec_in_apf() {
+        let mut vec: Vec<usize, Apf> = Vec::new_in(Apf);
+        for i in 0..10_000 {
+            vec.push(i);
+        }
+        assert_eq!(vec.iter().sum::<usize>(), 10_000 * 9_999 / 2);
+        vec.truncate(10);
+        vec.shrink_to_fit();
+        assert_eq!(vec, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn allocate_returns_block_size() {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let block = Apf.allocate(layout).unwrap();
+        assert_eq!(block.len(), 112);
+        unsafe { Apf.deallocate(block.cast(), layout) };
+
+        let zero = Apf.allocate(Layout::new::<()>()).unwrap();
+        assert_eq!(zero.len(), 0);
+        unsafe { Apf.deallocate(zero.cast(), Layout::new::<()>()) };
+    }
+
+    #[test]
+    fn grow_and_shrink_in_place() {
+        unsafe {
+            let small = Layout::from_size_align(70, 8).unwrap();
+            let block = Apf.allocate(small).unwrap();
+            let ptr = block.cast::<u8>();
+            let grown = Layout::from_size_align(block.len(), 8).unwrap();
+            assert_eq!(Apf.grow(ptr, small, grown).unwrap().cast(), ptr);
+            let moved = Layout::from_size_align(block.len() + 1, 8).unwrap();
+            let new = Apf.grow(ptr, grown, moved).unwrap().cast::<u8>();
+            assert_ne!(new, ptr);
+
+            let large = Layout::from_size_align(MAX_SZ * 4, 8).unwrap();
+            let ptr = Apf.grow(new, moved, large).unwrap().cast::<u8>();
+            let smaller = Layout::from_size_align(MAX_SZ * 2, 8).unwrap();
+            assert_eq!(Apf.shrink(ptr, large, smaller).unwrap().cast(), ptr);
+            let tiny = Layout::from_size_align(PAGE / 2, 8).unwrap();
+            let new = Apf.shrink(ptr, smaller, tiny).unwrap().cast::<u8>();
+            assert_ne!(new, ptr, "A large block should not be kept for a small size");
+            Apf.deallocate(new, tiny);
+        }
+    }
+
+    #[test]
+    fn zeroed_memory() {
+        unsafe {
+            let layout = Layout::from_size_align(48, 16).unwrap();
+            let block = Apf.allocate(layout).unwrap();
+            block.cast::<u8>().as_ptr().write_bytes(0xff, block.len());
+            Apf.deallocate(block.cast(), layout);
+
+            let block = Apf.allocate_zeroed(layout).unwrap();
+            assert!(block.as_ref().iter().all(|&b| b == 0));
+
+            let bigger = Layout::from_size_align(MAX_SZ * 2, 16).unwrap();
+            let grown = Apf.grow_zeroed(block.cast(), layout, bigger).unwrap();
+            assert!(grown.as_ref().iter().all(|&b| b == 0));
+            Apf.deallocate(grown.cast(), bigger);
+        }
+    }
+}
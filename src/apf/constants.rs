@@ -4,9 +4,23 @@ pub const INIT_HISTOGRAM_LENGTH: usize = 1 << 5;
 // pub const REUSE_BURST_LENGTH: usize = 1000;
 // pub const REUSE_HIBERNATION_PERIOD: usize = 2000;
 pub const USE_ALLOCATION_CLOCK: bool = true;
-use crate::thread_cache::no_tuning;
-lazy_static::lazy_static! {
-pub static ref TARGET_APF: usize = no_tuning(|| option_env!("TARGET_APF").map(|apf| apf.parse::<usize>().unwrap_or(2500)).unwrap_or(2500));
-pub static ref REUSE_BURST_LENGTH: usize = no_tuning(|| option_env!("BURST_LENGTH").map(|apf| apf.parse::<usize>().unwrap_or(300)).unwrap_or(300));
-pub static ref REUSE_HIBERNATION_PERIOD: usize = no_tuning(|| option_env!("HIBERNATION_PERIOD").map(|apf| apf.parse::<usize>().unwrap_or(*REUSE_BURST_LENGTH*2)).unwrap_or(*REUSE_BURST_LENGTH*2));
+// The target APF, burst length and hibernation period are configured at runtime, see the config module
+pub use crate::config::target_apf;
+
+/// The type of [`TARGET_APF`](static.TARGET_APF.html), which dereferences to the configured target APF
+pub struct TargetApf {
+    _private: (),
 }
+
+impl std::ops::Deref for TargetApf {
+    type Target = usize;
+
+    fn deref(&self) -> &usize {
+        crate::config::fixed_target_apf()
+    }
+}
+
+/// The target APF, which this was before it could be configured at runtime. Reading it fixes the configuration, as the
+/// first allocation does.
+#[deprecated(note = "use `apf::target_apf()`, or `config::set_target_apf()` to change it")]
+pub static TARGET_APF: TargetApf = TargetApf { _private: () };
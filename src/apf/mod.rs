@@ -6,8 +6,10 @@ use crate::apf::reuse_counter::ReuseCounter;
 use gnuplot::{Caption, Color, Figure};
 
 mod constants;
-use crate::apf::constants::{REUSE_BURST_LENGTH, REUSE_HIBERNATION_PERIOD, USE_ALLOCATION_CLOCK};
-pub use constants::TARGET_APF;
+use crate::apf::constants::USE_ALLOCATION_CLOCK;
+#[allow(deprecated)]
+pub use constants::{target_apf, TargetApf, TARGET_APF};
+use crate::config::ApfConfig;
 
 pub mod histogram;
 // pub mod timescale_functions;
@@ -29,6 +31,7 @@ pub struct ApfTuner<'a> {
     time: usize,
     fetch_count: usize,
     _dapf: usize,
+    target_apf: usize,
     check: fn(usize) -> u32,
     get: fn(usize, usize) -> bool,
     ret: fn(usize, u32) -> bool,
@@ -43,14 +46,27 @@ impl ApfTuner<'_> {
         get: fn(usize, usize) -> bool,
         ret: fn(usize, u32) -> bool,
         use_record: bool,
+    ) -> ApfTuner<'a> {
+        Self::with_config(id, check, get, ret, use_record, ApfConfig::global())
+    }
+
+    /// A tuner with the given settings instead of those of the global instance
+    pub fn with_config<'a>(
+        id: usize,
+        check: fn(usize) -> u32,
+        get: fn(usize, usize) -> bool,
+        ret: fn(usize, u32) -> bool,
+        use_record: bool,
+        config: ApfConfig,
     ) -> ApfTuner<'a> {
         ApfTuner {
             id,
             l_counter: LivenessCounter::new(),
-            r_counter: ReuseCounter::new(*REUSE_BURST_LENGTH, *REUSE_HIBERNATION_PERIOD),
+            r_counter: ReuseCounter::new(config.burst_length, config.hibernation_period),
             time: 0,
             fetch_count: 0,
             _dapf: 0,
+            target_apf: config.target_apf,
             check,
             get,
             ret,
@@ -62,11 +78,31 @@ impl ApfTuner<'_> {
         }
     }
 
+    /// A tuner with the given settings that doesn't move any blocks itself. It is only given allocations and frees with
+    /// [`count_malloc()`](#method.count_malloc) and [`count_free()`](#method.count_free), which return how many blocks
+    /// the caller should move.
+    pub fn detached<'a>(id: usize, config: ApfConfig) -> ApfTuner<'a> {
+        Self::with_config(id, |_| 0, |_, _| false, |_, _| false, false, config)
+    }
+
     pub fn set_id(&mut self, id: usize) {
         self.id = id;
     }
 
     pub fn malloc(&mut self, ptr: *mut u8) -> bool {
+        let free_blocks = (self.check)(self.id);
+        match self.count_malloc(ptr, free_blocks) {
+            Some(demand) => {
+                (self.get)(self.id, demand);
+                true
+            }
+            None => free_blocks != 0,
+        }
+    }
+
+    /// Counts the allocation of `ptr` from a cache that has `free_blocks` free blocks left, and returns how many blocks
+    /// should be fetched into the cache, if it is out of free blocks and the demand is known
+    pub fn count_malloc(&mut self, ptr: *mut u8, free_blocks: u32) -> Option<usize> {
         // dbg!("malloc");
         self.time += 1;
 
@@ -79,30 +115,17 @@ impl ApfTuner<'_> {
         self.r_counter.inc_timer();
 
         // If out of free blocks, fetch
-        let free_blocks = (self.check)(self.id);
-        if free_blocks == 0 {
-            let demand;
-
-            match self.demand(self.calculate_dapf().into()) {
-                Some(d) => {
-                    if self.record.is_some() {
-                        let dapf = self.calculate_dapf();
-                        let time = self.time;
-                        self.record.as_mut().map(|rec| rec.push((time, dapf)));
-                    }
-
-                    demand = d;
-                }
-                None => {
-                    return false;
-                }
-            }
-
-            (self.get)(self.id, demand.ceil() as usize);
-            self.count_fetch();
+        if free_blocks != 0 {
+            return None;
         }
-
-        return true;
+        let demand = self.demand(self.calculate_dapf().into())?;
+        if self.record.is_some() {
+            let dapf = self.calculate_dapf();
+            let time = self.time;
+            self.record.as_mut().map(|rec| rec.push((time, dapf)));
+        }
+        self.count_fetch();
+        Some(demand.ceil() as usize)
     }
 
     // Processes free event.
@@ -110,6 +133,20 @@ impl ApfTuner<'_> {
     // Ret function returns number of slots to central reserve
     // Returns true if demand can be calculated (reuse counter has completed a burst), false if not
     pub fn free(&mut self, ptr: *mut u8) -> bool {
+        let free_blocks = (self.check)(self.id);
+        match self.count_free(ptr, free_blocks) {
+            Some(0) => true,
+            Some(count) => {
+                (self.ret)(self.id, count);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Counts the free of `ptr` to a cache that holds `free_blocks` free blocks, and returns how many of them should be
+    /// returned from the cache, which is 0 unless it holds too many. Returns `None` if the demand is not known yet.
+    pub fn count_free(&mut self, ptr: *mut u8, free_blocks: u32) -> Option<u32> {
         self.r_counter.free(ptr as usize);
         if !USE_ALLOCATION_CLOCK {
             self.r_counter.inc_timer();
@@ -118,19 +155,14 @@ impl ApfTuner<'_> {
             self.l_counter.free();
         }
 
-        let d = self.demand(self.calculate_dapf().into());
-
-        if d.is_none() || d.unwrap() < 0.0 {
-            return false;
-        }
-        let demand = d.unwrap(); // Safe
+        let demand = self.demand(self.calculate_dapf().into()).filter(|&d| d >= 0.0)?;
 
         // If too many free blocks, return some
-        if (self.check)(self.id) as f32 >= 2.0 * demand + 1.0 {
-            let ceil = demand.ceil() as u32;
-            (self.ret)(self.id, ceil + 1);
+        if free_blocks as f32 >= 2.0 * demand + 1.0 {
+            Some(demand.ceil() as u32 + 1)
+        } else {
+            Some(0)
         }
-        true
     }
 
 
@@ -143,10 +175,11 @@ impl ApfTuner<'_> {
     }
 
     fn calculate_dapf(&self) -> usize {
-        if self.time >= *TARGET_APF * (self.fetch_count + 1) {
-            *TARGET_APF
+        let target_apf = self.target_apf;
+        if self.time >= target_apf * (self.fetch_count + 1) {
+            target_apf
         } else {
-            *TARGET_APF * (self.fetch_count + 1) - self.time
+            target_apf * (self.fetch_count + 1) - self.time
         }
     }
 
@@ -0,0 +1,529 @@
+//! Arenas are heaps that are kept apart from the global heaps, and whose memory is all given back at once.
+//!
+//! An [`Arena`] has its own set of [`ProcHeap`]s and maps its own superblocks. Its free blocks are kept in the arena
+//! instead of a thread cache, so none of its blocks are ever handed out by [`do_malloc()`](../fn.do_malloc.html). This
+//! lets dropping the arena unmap every superblock without freeing each block. Every descriptor records the arena it
+//! belongs to, so [`do_free()`](../fn.do_free.html) also returns an arena block to its arena.
+//!
+//! # Example
+//! ```
+//! use apfmalloc_lib::arena::Arena;
+//!
+//! let arena = Arena::new().expect("Could not create the arena");
+//! let nodes: Vec<*mut u64> = (0..1000).map(|_| arena.alloc(64, 8) as *mut u64).collect();
+//! unsafe {
+//!     nodes[0].write(10);
+//!     arena.free(nodes[0] as *mut u8);
+//! }
+//! // Everything else is given back in one go
+//! arena.destroy();
+//! ```
+
+use std::ops::Deref;
+use std::ptr::{null_mut, NonNull};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::alloc::{get_page_info_for_ptr, new_superblock, unregister_desc};
+use crate::allocation_data::{Descriptor, DescriptorNode, ProcHeap};
+use crate::iterate::heap_guard;
+use crate::mem_info::{align_size, MAX_SZ_IDX, PAGE};
+use crate::pages::external_mem_reservation::{AllocationError, SegAllocator, Segment, SEGMENT_ALLOCATOR};
+use crate::size_classes::get_size_class;
+use crate::thread_cache::ThreadCacheBin;
+use crate::{allocate_large, free_large, init_malloc, is_power_of_two, stats, MallocError, MALLOC_INIT_S};
+
+static LIVE_ARENAS: AtomicUsize = AtomicUsize::new(0);
+
+/// The most arenas that can have an index at the same time
+pub const MAX_ARENAS: usize = 64;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ARENA: AtomicPtr<ArenaHeap> = AtomicPtr::new(null_mut());
+/// The arenas that can be picked by index, such as with [`mallocx_arena()`](../mallocx/fn.mallocx_arena.html)
+static ARENA_INDEX: [AtomicPtr<ArenaHeap>; MAX_ARENAS] = [NO_ARENA; MAX_ARENAS];
+
+/// The number of threads using the arena at each index, which dropping the arena waits for
+static ARENA_USERS: [AtomicUsize; MAX_ARENAS] = [const { AtomicUsize::new(0) }; MAX_ARENAS];
+
+/// Gets the arena with the index `index`, if it exists. The arena can not be destroyed until the returned
+/// [`PinnedArena`] is dropped.
+pub(crate) fn arena_at(index: usize) -> Option<PinnedArena> {
+    let users = ARENA_USERS.get(index)?;
+    // counted before the arena is looked up, so that an arena that is being dropped either is seen as gone here, or
+    // waits for this use to end
+    users.fetch_add(1, Ordering::SeqCst);
+    match unsafe { ARENA_INDEX[index].load(Ordering::SeqCst).as_ref() } {
+        Some(heap) => Some(PinnedArena { index, heap }),
+        None => {
+            users.fetch_sub(1, Ordering::Release);
+            None
+        }
+    }
+}
+
+/// An arena that was picked by its index, and is kept alive while this exists
+pub(crate) struct PinnedArena {
+    index: usize,
+    heap: &'static ArenaHeap,
+}
+
+impl Deref for PinnedArena {
+    type Target = ArenaHeap;
+
+    fn deref(&self) -> &ArenaHeap {
+        self.heap
+    }
+}
+
+impl Drop for PinnedArena {
+    fn drop(&mut self) {
+        ARENA_USERS[self.index].fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Whether any arena exists. While one does, a block can not be returned to a thread cache without first checking that
+/// its descriptor doesn't belong to an arena, which [`arena_of()`](fn.arena_of.html) does.
+#[inline]
+pub(crate) fn arenas_exist() -> bool {
+    LIVE_ARENAS.load(Ordering::Acquire) != 0
+}
+
+/// Gets the arena that the allocation at `ptr` belongs to, if any
+pub(crate) fn arena_of<T: ?Sized>(ptr: *const T) -> Option<&'static ArenaHeap> {
+    if !arenas_exist() {
+        return None;
+    }
+    let desc = get_page_info_for_ptr(ptr).get_desc()?;
+    unsafe { (*desc).arena.as_ref() }
+}
+
+/// An isolated heap. Allocations are made with [`alloc()`](#method.alloc), and can be freed one at a time with either
+/// [`free()`](#method.free) or [`do_free()`](../fn.do_free.html). When the arena is dropped, every allocation still in
+/// it is freed at once.
+///
+/// Arenas can be shared between threads, but every allocation and free takes the lock of the arena. Dropping an arena
+/// waits for the allocations that picked it by its [index](#method.index) and are still running, but every allocation
+/// of the arena is invalid once it is gone.
+pub struct Arena {
+    heap: NonNull<ArenaHeap>,
+}
+
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+impl Arena {
+    /// Creates a new, empty arena. Fails if the memory for the state of the arena could not be mapped.
+    pub fn new() -> Result<Self, AllocationError> {
+        MALLOC_INIT_S.with(|| unsafe { init_malloc() });
+
+        let segment = SEGMENT_ALLOCATOR.allocate(page_ceiling!(std::mem::size_of::<ArenaHeap>()))?;
+        let ptr = segment.get_ptr() as *mut ArenaHeap;
+        unsafe {
+            ptr.write(ArenaHeap {
+                index: None,
+                inner: Mutex::new(ArenaInner {
+                    heaps: std::array::from_fn(ProcHeap::new_none),
+                    bins: [ThreadCacheBin::new(); MAX_SZ_IDX],
+                    live_blocks: [0; MAX_SZ_IDX],
+                }),
+                segment,
+            });
+        }
+        // Counted before it can be picked by its index, so that a free never skips the page map while one of its blocks
+        // could be handed out
+        LIVE_ARENAS.fetch_add(1, Ordering::AcqRel);
+        // The state is written before it is published, so the index is set to the slot about to be tried, as it can't
+        // be changed once another thread can see the arena
+        let published = ARENA_INDEX.iter().enumerate().any(|(index, slot)| {
+            unsafe { (*ptr).index = Some(index) };
+            slot.compare_exchange(null_mut(), ptr, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+        });
+        if !published {
+            unsafe { (*ptr).index = None };
+        }
+
+        Ok(Arena {
+            heap: unsafe { NonNull::new_unchecked(ptr) },
+        })
+    }
+
+    /// Takes back ownership of the arena with the index `index`, such as one that was given to C code by its index.
+    /// Returns `None` if there is no arena with that index.
+    ///
+    /// # Safety
+    ///
+    /// No other `Arena` may own the arena with this index, as both would destroy it.
+    pub unsafe fn from_index(index: usize) -> Option<Arena> {
+        let heap = ARENA_INDEX.get(index)?.load(Ordering::Acquire);
+        Some(Arena {
+            heap: NonNull::new(heap)?,
+        })
+    }
+
+    fn heap(&self) -> &ArenaHeap {
+        unsafe { self.heap.as_ref() }
+    }
+
+    /// The index of the arena, which picks it in the flags of [`do_mallocx()`](../mallocx/fn.do_mallocx.html). Only
+    /// the first [`MAX_ARENAS`](constant.MAX_ARENAS.html) arenas alive at once are given an index.
+    pub fn index(&self) -> Option<usize> {
+        self.heap().index
+    }
+
+    /// Allocates a space in memory of length `size` from the arena, that is aligned to `align`. `align` must be a power
+    /// of 2.
+    ///
+    /// If the allocation fails, a NULL pointer is returned.
+    pub fn alloc(&self, size: usize, align: usize) -> *mut u8 {
+        self.heap().alloc(size, align).map_or(null_mut(), NonNull::as_ptr)
+    }
+
+    /// Allocates from the arena in the same way as [`alloc()`](#method.alloc), but returns the reason the allocation
+    /// failed instead of a NULL pointer.
+    pub fn try_alloc(&self, size: usize, align: usize) -> Result<NonNull<u8>, MallocError> {
+        self.heap().alloc(size, align)
+    }
+
+    /// Returns an allocation to the arena, which is the same as calling [`do_free()`](../fn.do_free.html) on it. A free
+    /// to a `NULL` pointer has no effect.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated from this arena, and not already freed.
+    pub unsafe fn free(&self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        let info = get_page_info_for_ptr(ptr);
+        let desc = match info.get_desc() {
+            Some(desc) => &mut *desc,
+            None => return,
+        };
+        debug_assert_eq!(
+            desc.arena,
+            self.heap.as_ptr() as *const ArenaHeap,
+            "{:?} was not allocated from this arena",
+            ptr
+        );
+        self.heap().free(ptr, desc, info.get_size_class_index().unwrap_or(0))
+    }
+
+    /// Frees every allocation in the arena at once, and gives all of its memory back to the OS. This is the same as
+    /// dropping the arena.
+    pub fn destroy(self) {}
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe {
+            let heap = self.heap.as_ptr();
+            if let Some(index) = (*heap).index {
+                ARENA_INDEX[index].store(null_mut(), Ordering::SeqCst);
+                // waits for the threads that picked the arena by its index before it was taken out of the index
+                while ARENA_USERS[index].load(Ordering::SeqCst) != 0 {
+                    std::hint::spin_loop();
+                }
+            }
+            (*heap).release();
+            let segment = std::ptr::read(&(*heap).segment);
+            SEGMENT_ALLOCATOR.deallocate(segment);
+        }
+        LIVE_ARENAS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The state of an [`Arena`](struct.Arena.html). It is stored in a segment of its own so that descriptors can point to
+/// it.
+pub struct ArenaHeap {
+    index: Option<usize>,
+    inner: Mutex<ArenaInner>,
+    /// The segment this state is stored in
+    segment: Segment,
+}
+
+struct ArenaInner {
+    /// The superblocks of each size class are kept on the partial list of the heap of that class, and large allocations
+    /// on the list of the heap at index 0. The lists are linked both ways, so that a large allocation is taken off of
+    /// its list without walking it: a descriptor held by an arena is not on the free list of descriptors, so its
+    /// `next_free` links to the previous descriptor of its list instead.
+    heaps: [ProcHeap; MAX_SZ_IDX],
+    /// The free blocks of each size class
+    bins: [ThreadCacheBin; MAX_SZ_IDX],
+    /// The number of blocks of each size class that are in use
+    live_blocks: [usize; MAX_SZ_IDX],
+}
+
+impl ArenaInner {
+    /// Adds a superblock to the list of the heap at `index`
+    fn link(&mut self, index: usize, desc: &mut Descriptor) {
+        let list = &self.heaps[index].partial_list;
+        let node = Some(DescriptorNode::from(desc as *mut Descriptor));
+        let head = list.load(Ordering::Relaxed);
+        if let Some(head) = head.and_then(|node| node.get_desc()) {
+            head.next_free.store(node, Ordering::Relaxed);
+        }
+        desc.next_partial.store(head, Ordering::Relaxed);
+        desc.next_free.store(None, Ordering::Relaxed);
+        list.store(node, Ordering::Relaxed);
+    }
+
+    /// Removes a superblock from the list of the heap at `index`
+    fn unlink(&mut self, index: usize, desc: &Descriptor) {
+        let prev = desc.next_free.load(Ordering::Relaxed);
+        let next = desc.next_partial.load(Ordering::Relaxed);
+        match prev.and_then(|node| node.get_desc()) {
+            Some(prev) => prev.next_partial.store(next, Ordering::Relaxed),
+            None => self.heaps[index].partial_list.store(next, Ordering::Relaxed),
+        }
+        if let Some(next) = next.and_then(|node| node.get_desc()) {
+            next.next_free.store(prev, Ordering::Relaxed);
+        }
+    }
+
+    /// Takes the first superblock off of the list of the heap at `index`
+    fn pop(&mut self, index: usize) -> Option<&'static mut Descriptor> {
+        let desc = self.heaps[index].partial_list.load(Ordering::Relaxed)?.get_desc()?;
+        self.unlink(index, desc);
+        Some(desc)
+    }
+}
+
+impl ArenaHeap {
+    /// Allocates `size` bytes aligned to `align` from the arena
+    pub(crate) fn alloc(&self, size: usize, align: usize) -> Result<NonNull<u8>, MallocError> {
+        if !is_power_of_two(align) {
+            return Err(MallocError::InvalidAlignment(align));
+        }
+        if size > isize::MAX as usize {
+            return Err(MallocError::SizeOverflow(size));
+        }
+        let size = align_size(size, align);
+
+        let _guard = heap_guard();
+        if size > PAGE {
+            let (ptr, desc) = allocate_large(size, align, 0)?;
+            desc.arena = self;
+            self.inner.lock().link(0, desc);
+            return Ok(ptr);
+        }
+
+        let size_class_index = get_size_class(size);
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        if inner.bins[size_class_index].get_block_num() == 0 {
+            let bin = &mut inner.bins[size_class_index];
+            let desc = unsafe { &mut *new_superblock(&inner.heaps[size_class_index], bin, usize::MAX)? };
+            desc.arena = self;
+            inner.link(size_class_index, desc);
+        }
+
+        let ptr = inner.bins[size_class_index].pop_block();
+        #[cfg(feature = "debug_checks")]
+        crate::debug_checks::block_allocated(ptr);
+        inner.live_blocks[size_class_index] += 1;
+        Ok(unsafe { NonNull::new_unchecked(ptr) })
+    }
+
+    /// Returns a block to the arena, or unmaps it if it is a large allocation
+    pub(crate) unsafe fn free(&self, ptr: *mut u8, desc: &'static mut Descriptor, size_class_index: usize) {
+        let _guard = heap_guard();
+        let mut inner = self.inner.lock();
+        if size_class_index == 0 {
+            inner.unlink(0, desc);
+            drop(inner);
+            free_large(desc);
+        } else {
+            #[cfg(feature = "debug_checks")]
+            crate::debug_checks::block_freed(ptr);
+            inner.bins[size_class_index].push_block(ptr);
+            inner.live_blocks[size_class_index] -= 1;
+        }
+    }
+
+    /// Adds the number of free blocks of each size class that the arena holds to `counts`
+    pub(crate) fn count_free_blocks(&self, counts: &mut [usize; MAX_SZ_IDX]) {
+        let inner = self.inner.lock();
+        for (count, bin) in counts.iter_mut().zip(inner.bins.iter()) {
+            *count += bin.block_num as usize;
+        }
+    }
+
+    /// Calls `f` on every free block of the arena
+    pub(crate) fn for_each_free_block<F: FnMut(*mut u8)>(&self, mut f: F) {
+        let inner = self.inner.lock();
+        for bin in inner.bins.iter() {
+            bin.for_each_block(&mut f);
+        }
+    }
+
+    /// Unmaps every superblock and large allocation of the arena
+    unsafe fn release(&self) {
+        let _guard = heap_guard();
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+
+        while let Some(desc) = inner.pop(0) {
+            if let Some(segment) = desc.super_block.take() {
+                unregister_desc(None, &segment);
+                stats::large_unmapped(segment.len());
+                SEGMENT_ALLOCATOR.deallocate(segment);
+            }
+            desc.retire();
+        }
+
+        for size_class_index in 1..MAX_SZ_IDX {
+            while let Some(desc) = inner.pop(size_class_index) {
+                if let Some(segment) = desc.super_block.take() {
+                    unregister_desc(Some(&mut inner.heaps[size_class_index]), &segment);
+                    stats::superblock_unmapped(size_class_index);
+                    SEGMENT_ALLOCATOR.deallocate(segment);
+                }
+                desc.retire();
+            }
+            inner.live_blocks[size_class_index] = 0;
+            inner.bins[size_class_index] = ThreadCacheBin::new();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mallocx::{do_mallocx, mallocx_arena};
+    use crate::mem_info::MAX_SZ;
+    use crate::{do_free, do_realloc};
+    use std::ffi::c_void;
+
+    #[test]
+    fn alloc_and_free() {
+        let arena = Arena::new().unwrap();
+        let ptrs: Vec<*mut u8> = (0..100).map(|_| arena.alloc(48, 16)).collect();
+        for &ptr in &ptrs {
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 16, 0);
+            let desc = get_page_info_for_ptr(ptr).get_desc().unwrap();
+            assert_eq!(unsafe { (*desc).arena }, arena.heap.as_ptr() as *const ArenaHeap);
+        }
+        unsafe {
+            arena.free(ptrs[10]);
+            assert_eq!(
+                arena.alloc(48, 16),
+                ptrs[10],
+                "The freed block should be reused by the arena"
+            );
+            // do_free returns the block to the arena as well
+            do_free(ptrs[20]);
+            assert_eq!(arena.alloc(48, 16), ptrs[20]);
+        }
+    }
+
+    #[test]
+    fn destroy_unmaps_everything() {
+        let arena = Arena::new().unwrap();
+        let small = arena.alloc(100, 8);
+        let large = arena.alloc(MAX_SZ * 4, 8);
+        let aligned = arena.alloc(PAGE * 2, PAGE * 4);
+        assert_eq!(aligned as usize % (PAGE * 4), 0);
+        let heap = arena.heap.as_ptr() as *const ArenaHeap;
+        unsafe {
+            small.write_bytes(1, 100);
+            large.write_bytes(1, MAX_SZ * 4);
+            aligned.write_bytes(1, PAGE * 2);
+        }
+
+        arena.destroy();
+        for ptr in [small, large, aligned] {
+            if let Some(desc) = get_page_info_for_ptr(ptr).get_desc() {
+                assert_ne!(unsafe { (*desc).arena }, heap, "{:?} is still registered to the arena", ptr);
+            }
+        }
+    }
+
+    #[test]
+    fn destroy_while_allocating_by_index() {
+        for _ in 0..20 {
+            let arena = Arena::new().unwrap();
+            let index = arena.index().unwrap();
+            let allocating = std::thread::spawn(move || {
+                let mut allocations = 0;
+                // stops once the arena is gone, or after a while if the arena of another test took over the index
+                while !do_mallocx(64, mallocx_arena(index)).is_null() && allocations < 100_000 {
+                    allocations += 1;
+                }
+            });
+            std::thread::yield_now();
+            arena.destroy();
+            allocating.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn large_free_and_realloc() {
+        let arena = Arena::new().unwrap();
+        let first = arena.alloc(MAX_SZ * 2, 8);
+        let second = arena.alloc(MAX_SZ * 3, 8);
+        unsafe {
+            arena.free(first);
+            if let Some(desc) = get_page_info_for_ptr(first).get_desc() {
+                assert!((*desc).arena.is_null());
+            }
+
+            let small = arena.alloc(64, 8);
+            small.write_bytes(7, 64);
+            let moved = do_realloc(small as *mut c_void, 1024) as *mut u8;
+            assert_ne!(moved, small);
+            assert_eq!(*moved.add(63), 7);
+            assert!(
+                arena_of(moved).is_some(),
+                "A reallocated arena block should stay in its arena"
+            );
+            assert!(arena_of(second).is_some());
+        }
+    }
+
+    #[test]
+    fn large_frees_in_any_order() {
+        let arena = Arena::new().unwrap();
+        let large: Vec<*mut u8> = (0..4).map(|i| arena.alloc(MAX_SZ * (i + 2), 8)).collect();
+        let listed = || {
+            let inner = unsafe { arena.heap.as_ref() }.inner.lock();
+            let mut listed = Vec::new();
+            let mut node = inner.heaps[0].partial_list.load(Ordering::Relaxed);
+            while let Some(desc) = node.and_then(|node| node.get_desc()) {
+                listed.push(desc.super_block.as_ref().unwrap().get_ptr() as *mut u8);
+                node = desc.next_partial.load(Ordering::Relaxed);
+            }
+            listed
+        };
+        unsafe {
+            arena.free(large[1]);
+            arena.free(large[3]);
+            assert_eq!(listed(), [large[2], large[0]]);
+            arena.free(large[0]);
+            assert_eq!(listed(), [large[2]]);
+            arena.free(large[2]);
+        }
+        assert!(listed().is_empty());
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let arena = Arena::new().unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..1000 {
+                        let ptr = arena.alloc(i % 512 + 1, 8);
+                        assert!(!ptr.is_null());
+                        if i % 2 == 0 {
+                            unsafe { arena.free(ptr) };
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
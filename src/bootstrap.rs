@@ -1,11 +1,12 @@
-use std::process::exit;
+use std::ffi::c_void;
 use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use spin::Mutex;
 
 use crate::independent_collections::Array;
 use crate::mem_info::MAX_SZ_IDX;
-use crate::pages::external_mem_reservation::{SegAllocator, Segment, SEGMENT_ALLOCATOR};
+use crate::pages::external_mem_reservation::{AllocationError, SegAllocator, Segment, SEGMENT_ALLOCATOR};
 use crate::thread_cache::ThreadCacheBin;
 
 #[allow(unused)]
@@ -25,6 +26,30 @@ pub fn set_use_bootstrap(val: bool) {
     *_use_bootstrap.lock() = val;
 }
 
+/// Set once anything has been allocated from the bootstrap reserve, so that a free that skips the page map only has to
+/// search the reserve when the pointer could have come from it
+static BOOTSTRAP_USED: AtomicBool = AtomicBool::new(false);
+
+/// The lowest start and highest end of the segments mapped for the bootstrap reserve, so that most pointers can be
+/// ruled out without taking its lock
+static RESERVE_START: AtomicUsize = AtomicUsize::new(usize::MAX);
+static RESERVE_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Checks whether a pointer was allocated from the bootstrap reserve
+pub fn ptr_in_bootstrap_reserve<T: ?Sized>(ptr: *const T) -> bool {
+    BOOTSTRAP_USED.load(Ordering::Acquire)
+        && ptr_in_bootstrap_range(ptr)
+        && unsafe { (*std::ptr::addr_of!(bootstrap_reserve)).lock().ptr_in_bootstrap(ptr) }
+}
+
+/// Checks whether a pointer lies between the start of the first and the end of the last segment of the bootstrap
+/// reserve, without taking its lock. Memory that was mapped between its segments is in the range as well, so this only
+/// rules pointers out.
+pub(crate) fn ptr_in_bootstrap_range<T: ?Sized>(ptr: *const T) -> bool {
+    let addr = ptr as *const u8 as usize;
+    addr >= RESERVE_START.load(Ordering::Acquire) && addr < RESERVE_END.load(Ordering::Acquire)
+}
+
 pub struct BootstrapReserve {
     mem: Array<Segment>,
     next: *mut u8,
@@ -66,34 +91,50 @@ impl BootstrapReserve {
             }
         }
          */
-        let mem = SEGMENT_ALLOCATOR
-            .allocate(self.max)
-            .unwrap_or_else(|_| exit(-1));
-        self.next = mem.get_ptr() as *mut u8;
-        self.avail = self.max;
-        self.mem.push(mem);
+        // If the reserve can't be mapped yet, it is mapped by the first allocation from it instead
+        let _ = unsafe { self.add_new_segment(self.max) };
     }
 
-    unsafe fn add_new_segment(&mut self, request_size: usize) {
+    unsafe fn add_new_segment(&mut self, request_size: usize) -> Result<(), AllocationError> {
         let size = self.max.max(request_size);
-        let mem = SEGMENT_ALLOCATOR
-            .allocate(size)
-            .unwrap_or_else(|_| exit(-1));
+        let mem = SEGMENT_ALLOCATOR.allocate(size)?;
+        RESERVE_START.fetch_min(mem.get_ptr() as usize, Ordering::AcqRel);
+        RESERVE_END.fetch_max(mem.get_ptr() as usize + mem.len(), Ordering::AcqRel);
         self.next = mem.get_ptr() as *mut u8;
         self.avail = size;
         self.mem.push(mem);
+        Ok(())
     }
 
-    pub unsafe fn allocate(&mut self, size: usize) -> *mut u8 {
+    /// Takes `size` bytes from the reserve, mapping a new segment if there isn't enough left
+    pub unsafe fn allocate(&mut self, size: usize) -> Result<*mut u8, AllocationError> {
+        BOOTSTRAP_USED.store(true, Ordering::Release);
         if size > self.avail {
-            //return null_mut();
-            self.add_new_segment(size);
+            self.add_new_segment(size)?;
         }
 
         let ret = self.next;
-        self.next = self.next.offset(size as isize);
+        self.next = self.next.add(size);
         self.avail -= size;
-        ret
+        Ok(ret)
+    }
+
+    /// Gives the pages of the current segment that come `pad` bytes or more after the next allocation back to the OS,
+    /// and returns the number of bytes that were resident in them. Memory from the reserve is never reused once it is
+    /// freed, and the rest of a segment that was replaced by a new one has never been handed out, so the end of the
+    /// current segment is the only part of the reserve that can be given back.
+    pub unsafe fn trim(&mut self, pad: usize) -> usize {
+        let segment = match self.mem.len().checked_sub(1).and_then(|last| self.mem.get(last)) {
+            Some(segment) => segment,
+            None => return 0,
+        };
+        // the mapping of the segment goes to the end of its last page
+        let end = page_ceiling!(segment.get_ptr() as usize + segment.len());
+        let start = page_ceiling!((self.next as usize).saturating_add(pad).min(end));
+        if start >= end {
+            return 0;
+        }
+        SEGMENT_ALLOCATOR.release_pages(start as *mut c_void, end - start)
     }
 
     #[allow(unused)]
@@ -0,0 +1,405 @@
+//! Runtime configuration of the APF tuning.
+//!
+//! The values can be set in two ways, both of which must happen before the first allocation:
+//! 1. Programmatically, using the setters in this module
+//! 2. With the `APFMALLOC_CONF` environment variable, which is a comma separated list of `key:value` pairs, such as
+//!    `APFMALLOC_CONF="target_apf:4000,burst_length:500,use_apf:false"`
+//!
+//! A value set with a setter takes precedence over the same value in `APFMALLOC_CONF`. Any value that isn't set
+//! either way falls back to the value of the matching compile time environment variable (`TARGET_APF`, `BURST_LENGTH`,
+//! `HIBERNATION_PERIOD` and `USE_APF`), and then to the built in default.
+//!
+//! The recognized keys are:
+//!
+//! | Key                  | Value                                                               | Default             |
+//! |----------------------|---------------------------------------------------------------------|---------------------|
+//! | `target_apf`         | The target allocations per fetch of the thread caches               | 2500                |
+//! | `burst_length`       | The length of a burst in the reuse counter                          | 300                 |
+//! | `hibernation_period` | The length of the hibernation period in the reuse counter           | 2 * `burst_length`  |
+//! | `use_apf`            | Whether the APF tuners are used to size the thread caches           | true                |
+//!
+//! `APFMALLOC_CONF` is read while the allocator is initializing, so it is parsed without any heap allocation. Unknown
+//! keys and malformed values are reported on stderr, and otherwise ignored.
+
+use std::ffi::CStr;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+/// The name of the environment variable read when the allocator is initialized
+pub const CONF_ENV_VAR: &str = "APFMALLOC_CONF";
+
+const DEFAULT_TARGET_APF: usize = 2500;
+const DEFAULT_BURST_LENGTH: usize = 300;
+
+const TARGET_APF_SET: usize = 1;
+const BURST_LENGTH_SET: usize = 1 << 1;
+const HIBERNATION_PERIOD_SET: usize = 1 << 2;
+const USE_APF_SET: usize = 1 << 3;
+
+// A value of 0 means that the value has not been set yet
+static TARGET_APF: AtomicUsize = AtomicUsize::new(0);
+static BURST_LENGTH: AtomicUsize = AtomicUsize::new(0);
+static HIBERNATION_PERIOD: AtomicUsize = AtomicUsize::new(0);
+static USE_APF: AtomicBool = AtomicBool::new(true);
+
+/// The values which were set programmatically, and so can not be overridden by the environment
+static EXPLICITLY_SET: AtomicUsize = AtomicUsize::new(0);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+/// Prevents a setter from racing with the initialization of the allocator
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// The reasons a configuration value can be rejected
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ConfigError {
+    /// The allocator has already been initialized, so the configuration can no longer change
+    AlreadyInitialized,
+    /// The value can not be 0
+    ZeroValue(&'static str),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::AlreadyInitialized => {
+                write!(f, "the allocator has already been initialized")
+            }
+            ConfigError::ZeroValue(key) => write!(f, "{} must be greater than 0", key),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A single setting of the configuration
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Setting {
+    TargetApf(usize),
+    BurstLength(usize),
+    HibernationPeriod(usize),
+    UseApf(bool),
+}
+
+impl Setting {
+    fn mask(&self) -> usize {
+        match self {
+            Setting::TargetApf(_) => TARGET_APF_SET,
+            Setting::BurstLength(_) => BURST_LENGTH_SET,
+            Setting::HibernationPeriod(_) => HIBERNATION_PERIOD_SET,
+            Setting::UseApf(_) => USE_APF_SET,
+        }
+    }
+
+    fn store(&self) {
+        match *self {
+            Setting::TargetApf(v) => TARGET_APF.store(v, Ordering::Release),
+            Setting::BurstLength(v) => BURST_LENGTH.store(v, Ordering::Release),
+            Setting::HibernationPeriod(v) => HIBERNATION_PERIOD.store(v, Ordering::Release),
+            Setting::UseApf(v) => USE_APF.store(v, Ordering::Release),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ParseError {
+    UnknownKey,
+    InvalidValue,
+}
+
+fn parse_usize(value: &[u8]) -> Option<usize> {
+    std::str::from_utf8(value)
+        .ok()?
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|&v| v > 0)
+}
+
+fn parse_bool(value: &[u8]) -> Option<bool> {
+    match value.trim_ascii() {
+        b"true" | b"1" => Some(true),
+        b"false" | b"0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_setting(key: &[u8], value: &[u8]) -> Result<Setting, ParseError> {
+    let setting = match key {
+        b"target_apf" => parse_usize(value).map(Setting::TargetApf),
+        b"burst_length" => parse_usize(value).map(Setting::BurstLength),
+        b"hibernation_period" => parse_usize(value).map(Setting::HibernationPeriod),
+        b"use_apf" => parse_bool(value).map(Setting::UseApf),
+        _ => return Err(ParseError::UnknownKey),
+    };
+    setting.ok_or(ParseError::InvalidValue)
+}
+
+/// Writes a warning to stderr without allocating
+fn warn(parts: &[&[u8]]) {
+    for part in parts {
+        unsafe {
+            libc::write(2, part.as_ptr() as *const libc::c_void, part.len() as _);
+        }
+    }
+}
+
+/// Parses a configuration string, calling `apply` on every valid setting in the string and `warn` with the parts of a
+/// message for every invalid one
+fn parse_conf<F: FnMut(Setting), W: FnMut(&[&[u8]])>(conf: &[u8], mut apply: F, mut warn: W) {
+    for option in conf
+        .split(|&c| c == b',')
+        .map(<[u8]>::trim_ascii)
+        .filter(|o| !o.is_empty())
+    {
+        let mut split = option.splitn(2, |&c| c == b':');
+        let key = split.next().unwrap_or(&[]).trim_ascii();
+        let value = split.next().unwrap_or(&[]).trim_ascii();
+        match parse_setting(key, value) {
+            Ok(setting) => apply(setting),
+            Err(ParseError::UnknownKey) => warn(&[
+                b"apfmalloc: unknown key in ",
+                CONF_ENV_VAR.as_bytes(),
+                b": ",
+                key,
+                b"\n",
+            ]),
+            Err(ParseError::InvalidValue) => warn(&[
+                b"apfmalloc: invalid value for ",
+                key,
+                b" in ",
+                CONF_ENV_VAR.as_bytes(),
+                b": ",
+                value,
+                b"\n",
+            ]),
+        }
+    }
+}
+
+fn compile_time_usize(value: Option<&'static str>) -> Option<usize> {
+    value.and_then(|v| parse_usize(v.as_bytes()))
+}
+
+fn resolved(value: &AtomicUsize, default: impl FnOnce() -> usize) -> usize {
+    match value.load(Ordering::Acquire) {
+        0 => default(),
+        v => v,
+    }
+}
+
+/// Reads `APFMALLOC_CONF` and fixes the configuration. Only called by `init_malloc`
+pub(crate) fn init() {
+    let _guard = CONFIG_LOCK.lock();
+    if INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+
+    if option_env!("USE_APF") == Some("false") && EXPLICITLY_SET.load(Ordering::Acquire) & USE_APF_SET == 0 {
+        USE_APF.store(false, Ordering::Release);
+    }
+
+    // getenv needs a nul terminated name, which is built on the stack as this can't allocate
+    let mut name = [0u8; CONF_ENV_VAR.len() + 1];
+    name[..CONF_ENV_VAR.len()].copy_from_slice(CONF_ENV_VAR.as_bytes());
+    let conf = unsafe { libc::getenv(name.as_ptr() as *const libc::c_char) };
+    if !conf.is_null() {
+        let conf = unsafe { CStr::from_ptr(conf) }.to_bytes();
+        let explicit = EXPLICITLY_SET.load(Ordering::Acquire);
+        parse_conf(
+            conf,
+            |setting| {
+                if explicit & setting.mask() == 0 {
+                    setting.store()
+                }
+            },
+            warn,
+        );
+    }
+
+    // Resolve the defaults now, so that the getters never have to
+    TARGET_APF.store(target_apf(), Ordering::Release);
+    BURST_LENGTH.store(burst_length(), Ordering::Release);
+    HIBERNATION_PERIOD.store(hibernation_period(), Ordering::Release);
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+fn set(setting: Setting) -> Result<(), ConfigError> {
+    let _guard = CONFIG_LOCK.lock();
+    if INITIALIZED.load(Ordering::Acquire) {
+        return Err(ConfigError::AlreadyInitialized);
+    }
+    setting.store();
+    EXPLICITLY_SET.fetch_or(setting.mask(), Ordering::AcqRel);
+    Ok(())
+}
+
+/// Whether the configuration can still be changed, which is true until the first allocation
+pub fn is_configurable() -> bool {
+    !INITIALIZED.load(Ordering::Acquire)
+}
+
+/// Sets the target allocations per fetch of the thread caches
+pub fn set_target_apf(target_apf: usize) -> Result<(), ConfigError> {
+    if target_apf == 0 {
+        return Err(ConfigError::ZeroValue("target_apf"));
+    }
+    set(Setting::TargetApf(target_apf))
+}
+
+/// Sets the length of a burst in the reuse counter of the APF tuners
+pub fn set_burst_length(burst_length: usize) -> Result<(), ConfigError> {
+    if burst_length == 0 {
+        return Err(ConfigError::ZeroValue("burst_length"));
+    }
+    set(Setting::BurstLength(burst_length))
+}
+
+/// Sets the length of the hibernation period in the reuse counter of the APF tuners
+pub fn set_hibernation_period(hibernation_period: usize) -> Result<(), ConfigError> {
+    if hibernation_period == 0 {
+        return Err(ConfigError::ZeroValue("hibernation_period"));
+    }
+    set(Setting::HibernationPeriod(hibernation_period))
+}
+
+/// Sets whether the APF tuners are used to decide how many blocks are moved into the thread caches
+pub fn set_use_apf(use_apf: bool) -> Result<(), ConfigError> {
+    set(Setting::UseApf(use_apf))
+}
+
+/// The target allocations per fetch of the thread caches
+pub fn target_apf() -> usize {
+    resolved(&TARGET_APF, || {
+        compile_time_usize(option_env!("TARGET_APF")).unwrap_or(DEFAULT_TARGET_APF)
+    })
+}
+
+/// The target allocations per fetch once the configuration is fixed, which it is after this returns. Backs the
+/// deprecated [`apf::TARGET_APF`](../apf/static.TARGET_APF.html).
+pub(crate) fn fixed_target_apf() -> &'static usize {
+    init();
+    // The value is never stored to again once the configuration is fixed
+    unsafe { &*TARGET_APF.as_ptr() }
+}
+
+/// The length of a burst in the reuse counter of the APF tuners
+pub fn burst_length() -> usize {
+    resolved(&BURST_LENGTH, || {
+        compile_time_usize(option_env!("BURST_LENGTH")).unwrap_or(DEFAULT_BURST_LENGTH)
+    })
+}
+
+/// The length of the hibernation period in the reuse counter of the APF tuners
+pub fn hibernation_period() -> usize {
+    resolved(&HIBERNATION_PERIOD, || {
+        compile_time_usize(option_env!("HIBERNATION_PERIOD")).unwrap_or_else(|| burst_length() * 2)
+    })
+}
+
+/// Whether the APF tuners are used
+pub fn use_apf() -> bool {
+    USE_APF.load(Ordering::Acquire)
+}
+
+/// The settings of a set of APF tuners. The global instance of the allocator is tuned with the values of this module,
+/// and any other [instance](../heap/struct.HeapConfig.html) can be given settings of its own.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ApfConfig {
+    /// The target allocations per fetch of the caches
+    pub target_apf: usize,
+    /// The length of a burst in the reuse counter of the tuners
+    pub burst_length: usize,
+    /// The length of the hibernation period in the reuse counter of the tuners
+    pub hibernation_period: usize,
+}
+
+impl ApfConfig {
+    /// The settings of the global instance. Reading them fixes the configuration, as the first allocation does.
+    pub fn global() -> Self {
+        init();
+        Self {
+            target_apf: target_apf(),
+            burst_length: burst_length(),
+            hibernation_period: hibernation_period(),
+        }
+    }
+}
+
+impl Default for ApfConfig {
+    /// The built in defaults, which `APFMALLOC_CONF` and the setters of this module have no effect on
+    fn default() -> Self {
+        Self {
+            target_apf: DEFAULT_TARGET_APF,
+            burst_length: DEFAULT_BURST_LENGTH,
+            hibernation_period: DEFAULT_BURST_LENGTH * 2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(conf: &str) -> Vec<Setting> {
+        parse_with_warnings(conf).0
+    }
+
+    fn parse_with_warnings(conf: &str) -> (Vec<Setting>, Vec<String>) {
+        let mut settings = Vec::new();
+        let mut warnings = Vec::new();
+        parse_conf(
+            conf.as_bytes(),
+            |s| settings.push(s),
+            |parts| warnings.push(String::from_utf8(parts.concat()).unwrap()),
+        );
+        (settings, warnings)
+    }
+
+    #[test]
+    fn parses_conf_string() {
+        assert_eq!(
+            parse("target_apf:4000,burst_length:500,hibernation_period:700,use_apf:false"),
+            vec![
+                Setting::TargetApf(4000),
+                Setting::BurstLength(500),
+                Setting::HibernationPeriod(700),
+                Setting::UseApf(false)
+            ]
+        );
+        assert_eq!(parse(""), vec![]);
+        assert_eq!(parse("use_apf:1,"), vec![Setting::UseApf(true)]);
+        assert_eq!(
+            parse("use_apf: false, target_apf : 4000 "),
+            vec![Setting::UseApf(false), Setting::TargetApf(4000)]
+        );
+    }
+
+    #[test]
+    fn skips_invalid_settings() {
+        assert_eq!(
+            parse_with_warnings("target_ap:4000,burst_length:500"),
+            (
+                vec![Setting::BurstLength(500)],
+                vec![format!("apfmalloc: unknown key in {}: target_ap\n", CONF_ENV_VAR)]
+            )
+        );
+        assert_eq!(parse("target_apf:0,target_apf:-4,target_apf,use_apf:no"), vec![]);
+        assert_eq!(parse_setting(b"huge_pages", b"true"), Err(ParseError::UnknownKey));
+        assert_eq!(parse_setting(b"target_apf", b"many"), Err(ParseError::InvalidValue));
+    }
+
+    #[test]
+    fn setters_fail_after_init() {
+        unsafe { crate::do_free(crate::do_malloc(8)) };
+        assert!(!is_configurable());
+        assert_eq!(set_target_apf(100), Err(ConfigError::AlreadyInitialized));
+        assert_eq!(set_use_apf(false), Err(ConfigError::AlreadyInitialized));
+        assert_eq!(set_burst_length(0), Err(ConfigError::ZeroValue("burst_length")));
+        assert!(target_apf() > 0);
+        assert!(hibernation_period() > 0);
+        #[allow(deprecated)]
+        let deprecated = *crate::apf::TARGET_APF;
+        assert_eq!(deprecated, target_apf());
+    }
+}
@@ -0,0 +1,238 @@
+//! Double free detection, enabled by the `debug_checks` feature.
+//!
+//! A double free normally corrupts the list of free blocks of a thread cache or a superblock, and the program only
+//! crashes much later, somewhere unrelated to the free. With this feature, every descriptor of a superblock of the
+//! global heap points to a bitmap, with one bit for each block of the superblock that is set while the block is handed
+//! out.
+//! The bit is set when a block leaves a thread cache, an arena or the central reserve for the program, and is checked
+//! and cleared when the block is freed, so the second free of a block aborts with the pointer, its size class and the
+//! address of its descriptor.
+//!
+//! Blocks moved between the thread caches and their superblocks by `fill_cache`, `flush_cache` and the APF `fetch` and
+//! `ret` paths stay free the whole time, so their bits are left alone. The bitmap is mapped along with the superblock,
+//! and unmapped when the descriptor is retired. The bits are flipped atomically, so the checks take no lock. Large
+//! allocations, the bootstrap reserve and the blocks of [heap instances](../heap/index.html) are not checked.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::alloc::get_page_info_for_ptr;
+use crate::allocation_data::Descriptor;
+use crate::pages::external_mem_reservation::{AllocationError, SegAllocator, Segment, SEGMENT_ALLOCATOR};
+
+/// A change to the state of a block that is not possible unless a block was freed twice or a free list is corrupted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlockError {
+    /// The block was freed while it was already free
+    DoubleFree,
+    /// The block was handed out while it was still allocated
+    AlreadyAllocated,
+    /// The pointer is not the start of a block of the superblock
+    NotBlockStart,
+}
+
+impl BlockError {
+    fn message(&self) -> &'static [u8] {
+        match self {
+            BlockError::DoubleFree => b"double free",
+            BlockError::AlreadyAllocated => b"allocation of a block that is still in use",
+            BlockError::NotBlockStart => b"free of a pointer inside of a block",
+        }
+    }
+}
+
+/// The bitmap of the blocks of a superblock, kept in its descriptor. A descriptor without a bitmap is not checked.
+#[derive(Debug, Default)]
+pub struct BlockStates {
+    bitmap: Option<Segment>,
+}
+
+impl BlockStates {
+    /// Maps a bitmap for a superblock of `max_count` blocks, with every block free
+    pub(crate) fn map(&mut self, max_count: usize) -> Result<(), AllocationError> {
+        let size = max_count.div_ceil(64) * std::mem::size_of::<AtomicU64>();
+        let segment = SEGMENT_ALLOCATOR.allocate(size)?;
+        unsafe { std::ptr::write_bytes(segment.get_ptr() as *mut u8, 0, size) };
+        self.bitmap = Some(segment);
+        Ok(())
+    }
+
+    /// Unmaps the bitmap of a descriptor that is being retired, as all of its blocks are free again
+    pub(crate) fn unmap(&mut self) {
+        if let Some(segment) = self.bitmap.take() {
+            unsafe { SEGMENT_ALLOCATOR.deallocate(segment) };
+        }
+    }
+
+    /// Flips the bit of the block at `index` to `allocated`. Returns an error, and leaves the bit alone, if it already
+    /// was.
+    fn flip(&self, index: usize, allocated: bool) -> Result<(), BlockError> {
+        let bitmap = match &self.bitmap {
+            Some(segment) => segment.get_ptr() as *const AtomicU64,
+            None => return Ok(()),
+        };
+        let word = unsafe { &*bitmap.add(index / 64) };
+        let mask = 1u64 << (index % 64);
+        let old = word.fetch_xor(mask, Ordering::AcqRel);
+        if (old & mask != 0) == allocated {
+            word.fetch_xor(mask, Ordering::AcqRel);
+            return Err(if allocated {
+                BlockError::AlreadyAllocated
+            } else {
+                BlockError::DoubleFree
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Marks the block at `ptr` of the superblock of `desc` as allocated. Returns an error if it already was.
+fn allocated(desc: &Descriptor, ptr: *const u8) -> Result<(), BlockError> {
+    set(desc, ptr, true)
+}
+
+/// Marks the block at `ptr` of the superblock of `desc` as free. Returns an error if it already was, or if `ptr` is not
+/// the start of a block.
+fn freed(desc: &Descriptor, ptr: *const u8) -> Result<(), BlockError> {
+    set(desc, ptr, false)
+}
+
+fn set(desc: &Descriptor, ptr: *const u8, allocated: bool) -> Result<(), BlockError> {
+    let super_block = match desc.super_block.as_ref() {
+        Some(segment) => segment.get_ptr() as usize,
+        None => return Ok(()),
+    };
+    let offset = (ptr as usize).wrapping_sub(super_block);
+    let block_size = desc.block_size as usize;
+    if offset % block_size != 0 || offset / block_size >= desc.max_count as usize {
+        return Err(BlockError::NotBlockStart);
+    }
+    desc.block_states.flip(offset / block_size, allocated)
+}
+
+/// Marks a block of the global heap that is about to be handed out as allocated, aborting if it already was
+pub(crate) fn block_allocated(ptr: *const u8) {
+    check(ptr, true)
+}
+
+/// Marks a block of the global heap that is being freed as free, aborting if it already was
+pub(crate) fn block_freed(ptr: *const u8) {
+    check(ptr, false)
+}
+
+fn check(ptr: *const u8, allocated: bool) {
+    let info = get_page_info_for_ptr(ptr);
+    let (desc, size_class_index) = match (info.get_desc(), info.get_size_class_index()) {
+        (Some(desc), Some(size_class_index)) if size_class_index != 0 => (unsafe { &*desc }, size_class_index),
+        _ => return,
+    };
+    let result = if allocated {
+        self::allocated(desc, ptr)
+    } else {
+        freed(desc, ptr)
+    };
+    if let Err(error) = result {
+        report(error, ptr, size_class_index, desc)
+    }
+}
+
+/// Writes `value` in hexadecimal to the end of `buf`, returning the digits
+fn hex(value: usize, buf: &mut [u8; 16]) -> &[u8] {
+    let mut start = buf.len();
+    let mut value = value;
+    loop {
+        start -= 1;
+        buf[start] = b"0123456789abcdef"[value & 0xf];
+        value >>= 4;
+        if value == 0 {
+            return &buf[start..];
+        }
+    }
+}
+
+/// Writes `value` in decimal to the end of `buf`, returning the digits
+fn decimal(value: usize, buf: &mut [u8; 20]) -> &[u8] {
+    let mut start = buf.len();
+    let mut value = value;
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            return &buf[start..];
+        }
+    }
+}
+
+/// Writes the error straight to stderr, as the heap can not be trusted any more, and aborts
+#[cold]
+fn report(error: BlockError, ptr: *const u8, size_class_index: usize, desc: &Descriptor) -> ! {
+    let (mut ptr_buf, mut desc_buf, mut class_buf) = ([0; 16], [0; 16], [0; 20]);
+    let parts: [&[u8]; 9] = [
+        b"apfmalloc: ",
+        error.message(),
+        b" at 0x",
+        hex(ptr as usize, &mut ptr_buf),
+        b" (size class ",
+        decimal(size_class_index, &mut class_buf),
+        b", descriptor 0x",
+        hex(desc as *const Descriptor as usize, &mut desc_buf),
+        b")\n",
+    ];
+    for part in &parts {
+        unsafe {
+            libc::write(2, part.as_ptr() as *const libc::c_void, part.len() as _);
+        }
+    }
+    std::process::abort()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{do_free, do_malloc, get_page_info_for_ptr};
+
+    #[test]
+    fn double_free_is_found() {
+        let ptr = do_malloc(48);
+        let desc = unsafe { &*get_page_info_for_ptr(ptr).get_desc().unwrap() };
+
+        assert_eq!(allocated(desc, ptr), Err(BlockError::AlreadyAllocated));
+        assert_eq!(freed(desc, ptr), Ok(()));
+        assert_eq!(freed(desc, ptr), Err(BlockError::DoubleFree));
+        assert_eq!(freed(desc, unsafe { ptr.add(1) }), Err(BlockError::NotBlockStart));
+        assert_eq!(allocated(desc, ptr), Ok(()));
+        unsafe { do_free(ptr) };
+    }
+
+    #[test]
+    fn a_new_bitmap_has_every_block_free() {
+        let mut states = BlockStates::default();
+        assert_eq!(states.flip(3, false), Ok(()), "A descriptor without a bitmap is not checked");
+
+        states.map(100).unwrap();
+        assert_eq!(states.flip(99, false), Err(BlockError::DoubleFree));
+        assert_eq!(states.flip(99, true), Ok(()));
+        assert_eq!(states.flip(99, true), Err(BlockError::AlreadyAllocated));
+        states.unmap();
+        states.map(100).unwrap();
+        assert_eq!(states.flip(99, true), Ok(()));
+        states.unmap();
+    }
+
+    #[test]
+    fn blocks_are_tracked_through_the_thread_cache() {
+        // enough blocks to flush the thread cache to the superblocks and fill it again, which would abort if the bits
+        // of the freed blocks were not cleared
+        for _ in 0..2 {
+            let ptrs: Vec<*mut u8> = (0..5000).map(|_| do_malloc(24)).collect();
+            for &ptr in &ptrs {
+                let desc = unsafe { &*get_page_info_for_ptr(ptr).get_desc().unwrap() };
+                assert_eq!(allocated(desc, ptr), Err(BlockError::AlreadyAllocated));
+            }
+            for &ptr in &ptrs {
+                unsafe { do_free(ptr) };
+            }
+            crate::thread_cache_flush();
+        }
+    }
+}
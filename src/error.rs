@@ -0,0 +1,60 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use crate::pages::external_mem_reservation::AllocationError;
+
+/// The reason an allocation from [`try_malloc()`](fn.try_malloc.html),
+/// [`try_aligned_alloc()`](fn.try_aligned_alloc.html) or [`try_realloc()`](fn.try_realloc.html) failed
+#[derive(Debug)]
+pub enum MallocError {
+    /// The alignment was not a power of 2
+    InvalidAlignment(usize),
+    /// The size, once padded for its alignment and rounded up to whole pages, is too big to be allocated
+    SizeOverflow(usize),
+    /// The memory for the allocation could not be mapped. This holds the `errno` the mapping failed with.
+    MapFailed(AllocationError),
+    /// The allocation would have gone over a configured limit
+    LimitExceeded { requested: usize, limit: usize },
+    /// A setting of a [`HeapConfig`](heap/struct.HeapConfig.html) was out of its range, so the instance was not created
+    InvalidConfig { setting: &'static str, value: usize },
+    /// The thread cache of the calling thread has already been destroyed, because the thread is exiting
+    ThreadExited,
+}
+
+/// The same error as [`MallocError`](enum.MallocError.html), under the name `AllocError`. Code that also uses
+/// `core::alloc::AllocError` names it `MallocError` instead.
+pub type AllocError = MallocError;
+
+impl Display for MallocError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MallocError::InvalidAlignment(align) => write!(f, "alignment {} is not a power of 2", align),
+            MallocError::SizeOverflow(size) => write!(f, "size {} is too big to be allocated", size),
+            MallocError::MapFailed(error) => write!(f, "could not map memory: {}", error),
+            MallocError::LimitExceeded { requested, limit } => {
+                write!(
+                    f,
+                    "allocating {} bytes would go over the limit of {} bytes",
+                    requested, limit
+                )
+            }
+            MallocError::InvalidConfig { setting, value } => write!(f, "{} can not be {}", setting, value),
+            MallocError::ThreadExited => write!(f, "the thread cache of the thread has been destroyed"),
+        }
+    }
+}
+
+impl std::error::Error for MallocError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MallocError::MapFailed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<AllocationError> for MallocError {
+    fn from(error: AllocationError) -> Self {
+        MallocError::MapFailed(error)
+    }
+}
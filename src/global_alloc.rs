@@ -0,0 +1,143 @@
+//! A [`GlobalAlloc`](std::alloc::GlobalAlloc) that can be configured where it is declared.
+//!
+//! [`ApfAlloc`](struct.ApfAlloc.html) is built with `const fn`s, so it can be put straight into a `#[global_allocator]`
+//! static. Its settings are given to [`config`](../config/index.html) on the first allocation made through it, before
+//! the allocator is initialized, so they take precedence over `APFMALLOC_CONF` in the same way as the setters of that
+//! module. Settings that are not set fall back to `APFMALLOC_CONF` and the defaults.
+//!
+//! # Example
+//! ```
+//! use apfmalloc_lib::ApfAlloc;
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: ApfAlloc = ApfAlloc::new().target_apf(4000).use_apf(true).stats(false);
+//!
+//! let vec: Vec<u64> = (0..100).collect();
+//! assert_eq!(vec.iter().sum::<u64>(), 4950);
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout};
+
+use crate::config;
+use crate::single_access::SingleAccess;
+use crate::stats::print_stats;
+use crate::{do_aligned_alloc, do_aligned_alloc_zeroed, do_aligned_realloc, do_free_sized};
+
+/// The apfmalloc allocator, with the settings it applies before its first allocation. Created with
+/// [`ApfAlloc::new()`](#method.new), and configured with its builder methods, which are all `const fn`s so that it can
+/// be declared as the `#[global_allocator]`. Settings that are not set fall back to `APFMALLOC_CONF` and the defaults,
+/// as described in [`config`](config/index.html).
+pub struct ApfAlloc {
+    /// A value of 0 means that the setting is left as it is
+    target_apf: usize,
+    burst_length: usize,
+    hibernation_period: usize,
+    use_apf: Option<bool>,
+    stats: bool,
+    configure: SingleAccess,
+}
+
+impl ApfAlloc {
+    /// An allocator that leaves the configuration as it is
+    pub const fn new() -> Self {
+        Self {
+            target_apf: 0,
+            burst_length: 0,
+            hibernation_period: 0,
+            use_apf: None,
+            stats: false,
+            configure: SingleAccess::new(),
+        }
+    }
+
+    /// Sets the target allocations per fetch of the thread caches. A value of 0 leaves it as it is.
+    pub const fn target_apf(self, target_apf: usize) -> Self {
+        Self { target_apf, ..self }
+    }
+
+    /// Sets the length of a burst in the reuse counter of the APF tuners. A value of 0 leaves it as it is.
+    pub const fn burst_length(self, burst_length: usize) -> Self {
+        Self { burst_length, ..self }
+    }
+
+    /// Sets the length of the hibernation period in the reuse counter of the APF tuners. A value of 0 leaves it as it
+    /// is.
+    pub const fn hibernation_period(self, hibernation_period: usize) -> Self {
+        Self {
+            hibernation_period,
+            ..self
+        }
+    }
+
+    /// Sets whether the APF tuners are used to size the thread caches
+    pub const fn use_apf(self, use_apf: bool) -> Self {
+        Self {
+            use_apf: Some(use_apf),
+            ..self
+        }
+    }
+
+    /// Sets whether [`print_stats()`](stats/fn.print_stats.html) is called when the program exits
+    pub const fn stats(self, stats: bool) -> Self {
+        Self { stats, ..self }
+    }
+
+    /// Applies the settings, if this is the first allocation made through this allocator. Settings are ignored if the
+    /// allocator was already initialized by an allocation made some other way.
+    #[inline]
+    fn configure(&self) {
+        self.configure.with_then(
+            || {
+                if self.target_apf != 0 {
+                    let _ = config::set_target_apf(self.target_apf);
+                }
+                if self.burst_length != 0 {
+                    let _ = config::set_burst_length(self.burst_length);
+                }
+                if self.hibernation_period != 0 {
+                    let _ = config::set_hibernation_period(self.hibernation_period);
+                }
+                if let Some(use_apf) = self.use_apf {
+                    let _ = config::set_use_apf(use_apf);
+                }
+            },
+            // registering the handler can allocate, which has to happen after the other threads are let through
+            || {
+                if self.stats {
+                    unsafe { libc::atexit(print_stats_at_exit) };
+                }
+            },
+        );
+    }
+}
+
+impl Default for ApfAlloc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for ApfAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.configure();
+        do_aligned_alloc(layout.align(), layout.size())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        do_free_sized(ptr, layout.size(), layout.align())
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.configure();
+        do_aligned_alloc_zeroed(layout.align(), layout.size())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.configure();
+        do_aligned_realloc(ptr, layout.align(), new_size)
+    }
+}
+
+extern "C" fn print_stats_at_exit() {
+    print_stats()
+}
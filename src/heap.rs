@@ -0,0 +1,703 @@
+//! Instances of the allocator, which each own their heaps and the descriptors of their superblocks.
+//!
+//! [`do_malloc()`](../fn.do_malloc.html) and the rest of the crate use the global instance, which is also available as
+//! [`ApfHeap::global()`](struct.ApfHeap.html#method.global). An instance created with
+//! [`ApfHeap::new()`](struct.ApfHeap.html#method.new) has its own heaps and descriptors. It maps its own superblocks,
+//! so its allocations must be freed through it, and dropping it gives all of its memory back to the OS at once,
+//! including the allocations that were never freed.
+//!
+//! Instead of a cache for every thread, the threads using an instance share a few caches, which are set up with a
+//! [`HeapConfig`](struct.HeapConfig.html). The caches of an instance are sized by APF tuners of their own, with the
+//! settings in its `HeapConfig`, or only by its cache limit if it has none.
+//!
+//! # Limitations
+//! Instances are not fully isolated from each other:
+//! - The global instance has a page map of its own, but every other instance registers its superblocks in a single
+//!   page map they share, rather than one of their own. It is reserved when the first of them is created and kept
+//!   until the process exits, and each instance checks that a descriptor it finds in it is one of its own.
+//! - The size classes, the bootstrap reserve and the segment holder of the `pages` module
+//!   (`SEGMENT_ALLOCATOR`) are process wide, and every instance uses them.
+//! - [Allocation tags](../tags/index.html) and [lifetime hints](../lifetime/index.html) only apply to the global
+//!   instance, so an instance ignores the tag and lifetime of the thread allocating from it.
+//! - [`stats`](../stats/index.html) only counts the memory of the global instance.
+//! - The double free checks of the `debug_checks` feature don't cover the blocks of an instance.
+//!
+//! # Example
+//! ```
+//! use apfmalloc_lib::config::ApfConfig;
+//! use apfmalloc_lib::heap::{ApfHeap, HeapConfig};
+//!
+//! let config = HeapConfig {
+//!     caches: 2,
+//!     cache_limit: Some(64),
+//!     apf: Some(ApfConfig { target_apf: 500, ..ApfConfig::default() }),
+//! };
+//! let heap = ApfHeap::with_config(config).unwrap();
+//! let ptr = heap.malloc(100);
+//! assert!(heap.owns(ptr));
+//! unsafe { heap.free(ptr) };
+//! let leaked = heap.malloc(5000);
+//! // `leaked` is given back along with the rest of the memory of the heap
+//! drop(heap);
+//! ```
+
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::ptr::{null_mut, NonNull};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::alloc::{
+    malloc_count_from_partial_in, malloc_from_heap_partial, new_superblock_in, release_empty_superblocks_in,
+};
+use crate::allocation_data::{DescriptorPool, Heaps, ProcHeap};
+use crate::apf::ApfTuner;
+use crate::config::ApfConfig;
+use crate::iterate::{cache_guard, heap_guard};
+use crate::lifetime::Lifetime;
+use crate::mem_info::{align_size, MAX_SZ_IDX, PAGE};
+use crate::page_map::{PageInfo, PageMap};
+#[allow(deprecated)]
+use crate::page_map::S_PAGE_MAP;
+use crate::pages::external_mem_reservation::{AllocationError, SegAllocator, Segment, SEGMENT_ALLOCATOR};
+use crate::size_classes::{get_size_class, SIZE_CLASSES};
+use crate::thread_cache::{flush_blocks_in, ThreadCacheBin};
+use crate::{
+    allocate_large_in, do_free, do_malloc, do_realloc, fits_in_place, free_large_in, init_malloc, is_power_of_two,
+    purge, try_aligned_alloc, usable_size_in, MallocError, MALLOC_INIT_S,
+};
+
+/// The state that every instance of the allocator owns
+pub(crate) struct HeapCore {
+    /// The heaps that keep the partial superblocks of each size class
+    pub(crate) heaps: Heaps,
+    /// The descriptors of the superblocks and large allocations
+    pub(crate) descriptors: DescriptorPool,
+    /// The page map the superblocks and large allocations are registered in, which every instance other than the
+    /// global one shares
+    pub(crate) page_map: &'static PageMap<'static>,
+}
+
+impl HeapCore {
+    const fn new(page_map: &'static PageMap<'static>) -> Self {
+        Self {
+            heaps: Heaps::uninit(),
+            descriptors: DescriptorPool::new(),
+            page_map,
+        }
+    }
+
+    /// Whether this is the state of the global instance, the only one counted in [`stats`](../stats/index.html)
+    #[inline]
+    pub(crate) fn is_global(&self) -> bool {
+        std::ptr::eq(self, global_core())
+    }
+
+    /// The entry of the page of `ptr` in the page map, which is left empty if the page belongs to another instance
+    #[inline]
+    pub(crate) fn page_info<T: ?Sized>(&self, ptr: *const T) -> PageInfo {
+        let info = self.page_map.get_page_info(ptr);
+        match info.get_desc() {
+            Some(desc) if !self.is_global() && !self.heaps.contains(unsafe { (*desc).proc_heap }) => {
+                PageInfo::default()
+            }
+            _ => info,
+        }
+    }
+}
+
+/// The page map shared by every instance other than the global one. It is mapped when the first of them is created,
+/// and kept until the process exits.
+static mut SHARED_PAGE_MAP: PageMap<'static> = PageMap::new();
+/// Whether the shared page map has been mapped
+static SHARED_PAGE_MAP_MAPPED: Mutex<bool> = Mutex::new(false);
+
+/// The page map shared by every instance other than the global one, which is mapped the first time this is called
+fn shared_page_map() -> Result<&'static PageMap<'static>, AllocationError> {
+    let mut mapped = SHARED_PAGE_MAP_MAPPED.lock();
+    if !*mapped {
+        unsafe { (*std::ptr::addr_of_mut!(SHARED_PAGE_MAP)).try_init()? };
+        *mapped = true;
+    }
+    Ok(unsafe { &*std::ptr::addr_of!(SHARED_PAGE_MAP) })
+}
+
+/// The state of the global instance. Its heaps are mapped by
+/// [`global_heaps()`](../allocation_data/fn.global_heaps.html), and its page map when the allocator is initialized.
+#[allow(deprecated)]
+static mut GLOBAL_CORE: HeapCore = HeapCore::new(unsafe { &*std::ptr::addr_of!(S_PAGE_MAP) });
+
+/// The state of the global instance. Everything in it that changes once it is mapped is behind atomics or locks.
+#[inline]
+pub(crate) fn global_core() -> &'static HeapCore {
+    unsafe { &*std::ptr::addr_of!(GLOBAL_CORE) }
+}
+
+/// Maps the page map of the global instance
+///
+/// # Safety
+/// Must only be called once, while the allocator is initialized and no other thread can use the global instance.
+#[allow(deprecated)]
+pub(crate) unsafe fn map_global_page_map() {
+    (*std::ptr::addr_of_mut!(S_PAGE_MAP)).init()
+}
+
+/// The heaps of the global instance, for the deprecated [`get_heaps()`](../allocation_data/fn.get_heaps.html)
+///
+/// # Safety
+/// The heaps must have been mapped. Other threads use them at the same time, so the reference is not unique.
+pub(crate) unsafe fn global_heaps_mut() -> &'static mut Heaps {
+    &mut (*std::ptr::addr_of_mut!(GLOBAL_CORE)).heaps
+}
+
+/// Maps the heaps of the global instance
+///
+/// # Safety
+/// Must only be called once, before any other thread can use the heaps of the global instance.
+pub(crate) unsafe fn map_global_heaps() {
+    (*std::ptr::addr_of_mut!(GLOBAL_CORE)).heaps = Heaps::map().expect("Should be able to get the map")
+}
+
+/// The most caches the threads using an instance can be spread over
+pub const MAX_CACHES: usize = 16;
+
+/// How an instance other than the global one caches free blocks
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HeapConfig {
+    /// The number of caches the threads using the instance are spread over, from 1 to
+    /// [`MAX_CACHES`](constant.MAX_CACHES.html). More caches means less waiting on their locks, but more free blocks
+    /// held in caches.
+    pub caches: usize,
+    /// The most free blocks of a size class each cache holds before half of them are given back to their superblocks,
+    /// or `None` to hold as many as a superblock of the size class has. With `Some(0)`, every block is given straight
+    /// back.
+    pub cache_limit: Option<usize>,
+    /// The settings of the APF tuners that decide how many blocks each cache fetches and gives back, within its
+    /// `cache_limit`, or `None` to only give blocks back once a cache is over its limit. None of the settings can be 0.
+    pub apf: Option<ApfConfig>,
+}
+
+impl Default for HeapConfig {
+    fn default() -> Self {
+        Self {
+            caches: 4,
+            cache_limit: None,
+            apf: Some(ApfConfig::default()),
+        }
+    }
+}
+
+static NEXT_CACHE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static cache_index: Cell<usize> = const { Cell::new(usize::MAX) };
+}
+
+/// The cache the calling thread uses in every instance, before it is wrapped to the number of caches of the instance
+fn thread_cache_index() -> usize {
+    cache_index
+        .try_with(|index| {
+            if index.get() == usize::MAX {
+                index.set(NEXT_CACHE.fetch_add(1, Ordering::Relaxed));
+            }
+            index.get()
+        })
+        .unwrap_or(0)
+}
+
+/// An instance of the allocator. The global instance is used by every function of the crate outside of this module,
+/// and any other instance is independent of it, and of every other instance.
+///
+/// An instance other than the global one can be shared between threads, and frees everything that was allocated from
+/// it when it is dropped.
+pub struct ApfHeap {
+    /// The state of the instance, or `None` for the global instance
+    owned: Option<NonNull<OwnedHeap>>,
+}
+
+unsafe impl Send for ApfHeap {}
+unsafe impl Sync for ApfHeap {}
+
+static GLOBAL_HEAP: ApfHeap = ApfHeap { owned: None };
+
+impl ApfHeap {
+    /// The global instance, which [`do_malloc()`](../fn.do_malloc.html) and the rest of the crate allocate from
+    pub fn global() -> &'static ApfHeap {
+        &GLOBAL_HEAP
+    }
+
+    /// Creates a new instance with the default [`HeapConfig`](struct.HeapConfig.html)
+    ///
+    /// The first instance reserves the page map that every instance other than the global one shares, which covers the
+    /// whole address space with one entry per page. Only the pages of it that are used are backed by memory, but the
+    /// map takes 2 TiB of address space, and creating the first instance fails if it can't be reserved.
+    pub fn new() -> Result<ApfHeap, MallocError> {
+        Self::with_config(HeapConfig::default())
+    }
+
+    /// Creates a new instance that caches free blocks as set by `config`. Fails with
+    /// [`MallocError::InvalidConfig`](../enum.MallocError.html#variant.InvalidConfig) if the number of caches is not
+    /// between 1 and [`MAX_CACHES`](constant.MAX_CACHES.html) or one of the APF settings is 0, or if the state of the
+    /// instance or the [shared page map](#method.new) could not be mapped.
+    pub fn with_config(config: HeapConfig) -> Result<ApfHeap, MallocError> {
+        if config.caches == 0 || config.caches > MAX_CACHES {
+            return Err(MallocError::InvalidConfig {
+                setting: "caches",
+                value: config.caches,
+            });
+        }
+        if let Some(apf) = config.apf {
+            let settings = [
+                ("target_apf", apf.target_apf),
+                ("burst_length", apf.burst_length),
+                ("hibernation_period", apf.hibernation_period),
+            ];
+            if let Some(&(setting, value)) = settings.iter().find(|&&(_, value)| value == 0) {
+                return Err(MallocError::InvalidConfig { setting, value });
+            }
+        }
+        MALLOC_INIT_S.with(|| unsafe { init_malloc() });
+
+        let mut core = HeapCore::new(shared_page_map()?);
+        core.heaps = Heaps::map()?;
+        let segment = SEGMENT_ALLOCATOR.allocate(page_ceiling!(std::mem::size_of::<OwnedHeap>()))?;
+        let ptr = segment.get_ptr() as *mut OwnedHeap;
+        unsafe {
+            ptr.write(OwnedHeap {
+                core,
+                config,
+                caches: std::array::from_fn(|_| Mutex::new(SharedCache::new())),
+                segment,
+            });
+        }
+        Ok(ApfHeap {
+            owned: Some(unsafe { NonNull::new_unchecked(ptr) }),
+        })
+    }
+
+    fn owned(&self) -> Option<&OwnedHeap> {
+        self.owned.map(|heap| unsafe { &*heap.as_ptr() })
+    }
+
+    /// Whether this is the global instance
+    pub fn is_global(&self) -> bool {
+        self.owned.is_none()
+    }
+
+    /// How the instance caches free blocks, or `None` for the global instance, which has a cache for every thread
+    pub fn config(&self) -> Option<HeapConfig> {
+        self.owned().map(|heap| heap.config)
+    }
+
+    /// Allocates a space in memory of length `size` from the instance.
+    ///
+    /// If the allocation fails, a NULL pointer is returned.
+    pub fn malloc(&self, size: usize) -> *mut u8 {
+        match self.owned() {
+            Some(heap) => heap.alloc(size, 1).map_or(null_mut(), NonNull::as_ptr),
+            None => do_malloc(size),
+        }
+    }
+
+    /// Allocates a space in memory of length `size` from the instance, that is aligned to `align`, and returns the
+    /// reason the allocation failed if it does. `align` must be a power of 2.
+    pub fn try_alloc(&self, size: usize, align: usize) -> Result<NonNull<u8>, MallocError> {
+        match self.owned() {
+            Some(heap) => heap.alloc(size, align),
+            None => try_aligned_alloc(align, size),
+        }
+    }
+
+    /// Resizes the allocation at `ptr` to `size` bytes, moving it if it doesn't fit in its block. If it is moved, the
+    /// contents are copied and `ptr` is freed. A NULL pointer is the same as calling [`malloc()`](#method.malloc).
+    ///
+    /// If the allocation fails, a NULL pointer is returned and `ptr` is left as it was.
+    ///
+    /// # Safety
+    /// `ptr` must be NULL, or an allocation of this instance that has not been freed.
+    pub unsafe fn realloc(&self, ptr: *mut u8, size: usize) -> *mut u8 {
+        match self.owned() {
+            Some(heap) => heap.realloc(ptr, size).map_or(null_mut(), NonNull::as_ptr),
+            None => do_realloc(ptr as *mut c_void, size) as *mut u8,
+        }
+    }
+
+    /// Frees an allocation of this instance. A free to a `NULL` pointer has no effect.
+    ///
+    /// # Safety
+    /// `ptr` must be an allocation of this instance that has not already been freed.
+    pub unsafe fn free(&self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        match self.owned() {
+            Some(heap) => heap.free(ptr),
+            None => do_free(ptr),
+        }
+    }
+
+    /// Whether `ptr` points anywhere into a superblock or large allocation of this instance. The block it points into
+    /// may have been freed.
+    pub fn owns<T: ?Sized>(&self, ptr: *const T) -> bool {
+        self.core().page_info(ptr).get_desc().is_some()
+    }
+
+    /// The number of bytes that can be used at `ptr`, if it is an allocation of this instance
+    pub fn usable_size<T: ?Sized>(&self, ptr: *const T) -> Option<usize> {
+        usable_size_in(self.core(), ptr)
+    }
+
+    /// Gives every superblock of the instance whose blocks are all free back to the OS, and returns the number of bytes
+    /// that were released. For the global instance, this is [`purge()`](../fn.purge.html). For any other instance, the
+    /// blocks held in its caches are given back to their superblocks first.
+    pub fn purge(&self) -> usize {
+        match self.owned() {
+            Some(heap) => heap.purge(),
+            None => purge(),
+        }
+    }
+
+    fn core(&self) -> &HeapCore {
+        match self.owned() {
+            Some(heap) => &heap.core,
+            None => global_core(),
+        }
+    }
+}
+
+impl Drop for ApfHeap {
+    fn drop(&mut self) {
+        if let Some(heap) = self.owned {
+            unsafe {
+                let heap = heap.as_ptr();
+                (*heap).release();
+                let segment = std::ptr::read(&(*heap).segment);
+                std::ptr::drop_in_place(heap);
+                SEGMENT_ALLOCATOR.deallocate(segment);
+            }
+        }
+    }
+}
+
+/// The state of an instance other than the global one. It is kept in a segment of its own, so that creating an instance
+/// does not allocate from the global one.
+struct OwnedHeap {
+    core: HeapCore,
+    config: HeapConfig,
+    caches: [Mutex<SharedCache>; MAX_CACHES],
+    /// The segment this state is stored in
+    segment: Segment,
+}
+
+/// A cache of an instance, which the threads using the instance share
+struct SharedCache {
+    bins: [ThreadCacheBin; MAX_SZ_IDX],
+    /// The tuner of each bin, which are made on the first allocation from the cache if the instance is tuned
+    tuners: Vec<ApfTuner<'static>>,
+}
+
+impl SharedCache {
+    const fn new() -> Self {
+        Self {
+            bins: [ThreadCacheBin::new(); MAX_SZ_IDX],
+            tuners: Vec::new(),
+        }
+    }
+}
+
+impl OwnedHeap {
+    fn heap(&self, size_class_index: usize) -> &'static ProcHeap {
+        unsafe { &*self.core.heaps.heap_ptr(Lifetime::Short, size_class_index) }
+    }
+
+    /// The cache of the calling thread
+    fn cache(&self) -> &Mutex<SharedCache> {
+        &self.caches[thread_cache_index() % self.config.caches]
+    }
+
+    fn cache_limit(&self, size_class_index: usize) -> usize {
+        self.config
+            .cache_limit
+            .unwrap_or(unsafe { SIZE_CLASSES[size_class_index].cache_block_num } as usize)
+    }
+
+    fn alloc(&self, size: usize, align: usize) -> Result<NonNull<u8>, MallocError> {
+        if !is_power_of_two(align) {
+            return Err(MallocError::InvalidAlignment(align));
+        }
+        if size > isize::MAX as usize {
+            return Err(MallocError::SizeOverflow(size));
+        }
+        let size = align_size(size, align);
+        if size > PAGE {
+            return allocate_large_in(&self.core, self.heap(0), size, align, 0).map(|(ptr, _)| ptr);
+        }
+
+        let size_class_index = get_size_class(size);
+        let _cache_guard = cache_guard();
+        let mut cache = self.cache().lock();
+        let SharedCache { bins, tuners } = &mut *cache;
+        let bin = &mut bins[size_class_index];
+        if bin.get_block_num() == 0 {
+            self.fill(size_class_index, bin)?;
+        }
+        let ptr = bin.pop_block();
+
+        if let Some(apf) = self.config.apf {
+            if tuners.is_empty() {
+                tuners.extend((0..MAX_SZ_IDX).map(|index| ApfTuner::detached(index, apf)));
+            }
+            let demand = tuners[size_class_index].count_malloc(ptr, bin.get_block_num());
+            let count = demand.map_or(0, |count| count.min(self.cache_limit(size_class_index)));
+            if count > 0 {
+                self.fetch(size_class_index, bin, count);
+            }
+        }
+        Ok(unsafe { NonNull::new_unchecked(ptr) })
+    }
+
+    /// Moves up to `count` blocks into a bin, as its tuner asked for. The blocks come from a partial superblock if
+    /// there is one, and from new superblocks otherwise. If a superblock can't be mapped, the bin is left short, and is
+    /// filled as usual once it runs out.
+    fn fetch(&self, size_class_index: usize, bin: &mut ThreadCacheBin, count: usize) {
+        let _guard = heap_guard();
+        let heap = self.heap(size_class_index);
+        let max_count = unsafe { SIZE_CLASSES[size_class_index].get_block_num() };
+        let mut block_num = 0;
+        malloc_count_from_partial_in(&self.core, heap, bin, &mut block_num, count);
+        while block_num < count {
+            let missing = count - block_num;
+            if new_superblock_in(&self.core, heap, bin, missing).is_err() {
+                break;
+            }
+            block_num += max_count.min(missing);
+        }
+    }
+
+    /// Fills an empty cache bin with the free blocks of a partial superblock, or of a new superblock
+    fn fill(&self, size_class_index: usize, bin: &mut ThreadCacheBin) -> Result<(), MallocError> {
+        let _guard = heap_guard();
+        let heap = self.heap(size_class_index);
+        let mut block_num = 0;
+        malloc_from_heap_partial(heap, bin, &mut block_num);
+        if block_num == 0 {
+            new_superblock_in(&self.core, heap, bin, usize::MAX)?;
+        }
+        bin.block_size = Some(unsafe { SIZE_CLASSES[size_class_index].block_size });
+        Ok(())
+    }
+
+    unsafe fn free(&self, ptr: *mut u8) {
+        let info = self.core.page_info(ptr);
+        let desc = match info.get_desc() {
+            Some(desc) => &mut *desc,
+            None => return,
+        };
+        match info.get_size_class_index() {
+            None | Some(0) => free_large_in(&self.core, desc),
+            Some(size_class_index) => {
+                let _cache_guard = cache_guard();
+                let mut cache = self.cache().lock();
+                let SharedCache { bins, tuners } = &mut *cache;
+                let bin = &mut bins[size_class_index];
+                // the tuners are made by the first allocation from the cache, which may have been from another cache
+                if let Some(tuner) = tuners.get_mut(size_class_index) {
+                    match tuner.count_free(ptr, bin.get_block_num()) {
+                        Some(count) if count > 0 => flush_blocks_in(&self.core, size_class_index, bin, count),
+                        _ => {}
+                    }
+                }
+                bin.push_block(ptr);
+
+                let limit = self.cache_limit(size_class_index);
+                if bin.get_block_num() as usize > limit {
+                    let count = bin.get_block_num() - (limit / 2) as u32;
+                    flush_blocks_in(&self.core, size_class_index, bin, count);
+                }
+            }
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, size: usize) -> Result<NonNull<u8>, MallocError> {
+        let old_size = match usable_size_in(&self.core, ptr) {
+            Some(old_size) => old_size,
+            None => return self.alloc(size, 1),
+        };
+        if fits_in_place(ptr, old_size, size, 1) {
+            return Ok(NonNull::new_unchecked(ptr));
+        }
+        let ret = self.alloc(size, 1)?;
+        std::ptr::copy_nonoverlapping(ptr, ret.as_ptr(), old_size.min(size));
+        self.free(ptr);
+        Ok(ret)
+    }
+
+    fn purge(&self) -> usize {
+        for cache in self.caches.iter().take(self.config.caches) {
+            let mut cache = cache.lock();
+            for (size_class_index, bin) in cache.bins.iter_mut().enumerate().skip(1) {
+                flush_blocks_in(&self.core, size_class_index, bin, bin.get_block_num());
+                bin.block_size = None;
+            }
+        }
+
+        let _guard = heap_guard();
+        (1..MAX_SZ_IDX)
+            .map(|size_class_index| release_empty_superblocks_in(&self.core, self.heap(size_class_index), 0))
+            .sum()
+    }
+
+    /// Unmaps every superblock and large allocation of the instance, along with its descriptors, and clears their
+    /// pages in the shared page map
+    unsafe fn release(&mut self) {
+        let _guard = heap_guard();
+        let page_map = self.core.page_map;
+        self.core.descriptors.for_each_live(|desc| {
+            // The descriptors are unmapped along with their pool, so their segments don't need to be taken
+            let segment = std::ptr::read(desc.super_block.as_ref().unwrap());
+            let heap = if desc.is_large() { None } else { Some(&*desc.proc_heap) };
+            page_map.unregister(heap, &segment);
+            SEGMENT_ALLOCATOR.deallocate(segment);
+        });
+        self.core.descriptors.release();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::alloc::get_page_info_for_ptr;
+    use crate::mem_info::MAX_SZ;
+
+    #[test]
+    fn instances_are_independent() {
+        let first = ApfHeap::new().unwrap();
+        let second = ApfHeap::with_config(HeapConfig {
+            caches: 1,
+            cache_limit: Some(0),
+            apf: None,
+        })
+        .unwrap();
+
+        let small = first.malloc(100);
+        let large = first.try_alloc(MAX_SZ * 3, PAGE * 4).unwrap().as_ptr();
+        let other = second.malloc(100);
+        assert_eq!(large as usize % (PAGE * 4), 0);
+        assert!(first.owns(small) && first.owns(large) && second.owns(other));
+        assert!(first.owns(small.wrapping_add(50)), "Any pointer into a block is owned");
+        assert!(!second.owns(small) && !first.owns(other));
+        assert!(
+            get_page_info_for_ptr(small).get_desc().is_none(),
+            "The global page map doesn't know about other instances"
+        );
+        assert!(std::ptr::eq(first.core().page_map, second.core().page_map));
+        assert!(first.usable_size(small).unwrap() >= 100);
+        assert!(first.usable_size(large).unwrap() >= MAX_SZ * 3);
+
+        unsafe {
+            small.write_bytes(3, 100);
+            let grown = first.realloc(small, 2000);
+            assert_eq!(*grown.add(99), 3);
+            assert!(first.owns(grown));
+            first.free(grown);
+            second.free(other);
+        }
+        assert!(
+            second.purge() > 0,
+            "Nothing is cached, so the superblock of `other` is empty"
+        );
+
+        let leaked: Vec<*mut u8> = (0..1000).map(|i| first.malloc((i % 30 + 1) * 100)).collect();
+        drop(first);
+        drop(leaked);
+    }
+
+    #[test]
+    fn many_instances_share_a_page_map() {
+        let heaps: Vec<ApfHeap> = (0..100).map(|_| ApfHeap::new().unwrap()).collect();
+        let ptrs: Vec<*mut u8> = heaps.iter().map(|heap| heap.malloc(100)).collect();
+        for (index, heap) in heaps.iter().enumerate() {
+            assert!(heap.owns(ptrs[index]));
+            assert!(!heap.owns(ptrs[(index + 1) % ptrs.len()]), "A block of another instance is not owned");
+            assert_eq!(heap.usable_size(ptrs[(index + 1) % ptrs.len()]), None);
+        }
+    }
+
+    #[test]
+    fn threads_share_an_instance() {
+        // tuned with short bursts, so that the tuners fetch and give back blocks during the test
+        let heap = ApfHeap::with_config(HeapConfig {
+            caches: 2,
+            cache_limit: Some(16),
+            apf: Some(ApfConfig {
+                target_apf: 20,
+                burst_length: 10,
+                hibernation_period: 20,
+            }),
+        })
+        .unwrap();
+        let sent: Vec<usize> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|i| {
+                    let heap = &heap;
+                    scope.spawn(move || {
+                        let kept: Vec<usize> = (0..500).map(|j| heap.malloc(64 + (i + j) % 200) as usize).collect();
+                        kept.iter()
+                            .step_by(2)
+                            .for_each(|&ptr| unsafe { heap.free(ptr as *mut u8) });
+                        kept.into_iter().skip(1).step_by(2).collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+        });
+        assert!(sent.iter().all(|&ptr| heap.owns(ptr as *const u8)));
+        // Freed by a different thread than the one that allocated them
+        sent.into_iter().for_each(|ptr| unsafe { heap.free(ptr as *mut u8) });
+        assert!(heap.purge() > 0);
+    }
+
+    #[test]
+    fn global_instance_uses_the_global_functions() {
+        let heap = ApfHeap::global();
+        assert!(heap.is_global());
+        assert_eq!(heap.config(), None);
+        let ptr = heap.malloc(64);
+        assert!(heap.owns(ptr));
+        assert_eq!(get_page_info_for_ptr(ptr), global_core().page_map.get_page_info(ptr));
+        unsafe { heap.free(ptr) };
+        assert!(matches!(
+            ApfHeap::with_config(HeapConfig {
+                caches: 0,
+                ..HeapConfig::default()
+            }),
+            Err(MallocError::InvalidConfig { setting: "caches", value: 0 })
+        ));
+        assert!(matches!(
+            ApfHeap::with_config(HeapConfig {
+                apf: Some(ApfConfig {
+                    burst_length: 0,
+                    ..ApfConfig::default()
+                }),
+                ..HeapConfig::default()
+            }),
+            Err(MallocError::InvalidConfig { setting: "burst_length", value: 0 })
+        ));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_names_find_the_global_state() {
+        use crate::allocation_data::{get_heaps, global_heaps};
+        use crate::page_map::S_PAGE_MAP;
+
+        let ptr = do_malloc(64);
+        let heap = get_heaps().get_heap_at_mut(1) as *const ProcHeap;
+        assert!(std::ptr::eq(heap, global_heaps().get_heap_at(1)));
+        let info = unsafe { (*std::ptr::addr_of!(S_PAGE_MAP)).get_page_info(ptr) };
+        assert_eq!(info, get_page_info_for_ptr(ptr));
+        assert!(info.get_desc().is_some());
+        unsafe { do_free(ptr) };
+    }
+}
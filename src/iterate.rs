@@ -0,0 +1,489 @@
+//! Walking the heap to find every block that is allocated, and stopping other threads from changing the heap while it
+//! is walked.
+//!
+//! [`iterate_heap()`](fn.iterate_heap.html) goes through the descriptor of every superblock and large allocation. A
+//! block of a superblock is allocated unless it is in the free list of its superblock, in a thread cache, or in the
+//! free blocks of an [`Arena`](../arena/struct.Arena.html). Memory handed out by the bootstrap reserve is not part of
+//! the heap, and is never reported.
+//!
+//! # Example
+//! ```
+//! use apfmalloc_lib::do_malloc;
+//! use apfmalloc_lib::iterate::{iterate_heap, IterationMode};
+//!
+//! let ptr = do_malloc(100);
+//! let mut found = false;
+//! unsafe {
+//!     iterate_heap(IterationMode::Concurrent, |block, size| {
+//!         found |= block == ptr && size >= 100;
+//!     });
+//! }
+//! assert!(found);
+//! ```
+
+use std::cell::Cell;
+use std::sync::atomic::{compiler_fence, fence, AtomicBool, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::allocation_data::Descriptor;
+use crate::arena::ArenaHeap;
+use crate::independent_collections::Array;
+use crate::mem_info::PAGE;
+use crate::thread_cache::{for_each_own_cache, for_each_registered_cache, next_block};
+use crate::{init_malloc, MALLOC_INIT_S};
+
+/// Using a thread cache bin
+const CACHE: usize = 0;
+/// Changing the central reserve, a superblock or an arena
+const HEAP: usize = 1;
+
+/// The number of [`Activity`](struct.Activity.html) counters of a thread
+const ACTIVITIES: usize = 2;
+
+/// Whether a thread is in the middle of each activity. The counters of a thread are only changed by that thread, and
+/// are alone on their cache line, so marking an activity never contends with other threads. Threads that stop the heap
+/// wait for the counters of every thread to drop to zero.
+///
+/// A thread marks an activity with a plain store, and then checks whether the heap is stopped. The thread stopping the
+/// heap raises its flag and then reads the counters, so a barrier is needed between the store and the load on both
+/// sides. Where `membarrier` can be used, the thread stopping the heap puts that barrier on every other thread with it,
+/// and the threads marking their activities only keep the compiler from reordering them.
+#[repr(align(64))]
+struct Activity([AtomicUsize; ACTIVITIES]);
+
+impl Activity {
+    const fn new() -> Self {
+        Self([const { AtomicUsize::new(0) }; ACTIVITIES])
+    }
+}
+
+/// The activity of the calling thread, which is never dropped, so that it can be used until the thread is gone
+struct ThreadActivity {
+    /// The counters of the thread, once they are registered
+    counters: Activity,
+    /// How many times the thread is nested in each activity
+    depths: [Cell<usize>; ACTIVITIES],
+    /// 0 until the counters are registered, 1 while they are being registered, 2 once they are, and 3 once the thread
+    /// is exiting and they were removed
+    registration: Cell<u8>,
+}
+
+/// Removes the counters of a thread from the registered counters once it exits
+struct ActivityRegistration;
+
+impl Drop for ActivityRegistration {
+    fn drop(&mut self) {
+        ACTIVITY.with(|thread| {
+            let counters = &thread.counters as *const Activity as usize;
+            let mut registered = REGISTERED_ACTIVITIES.lock();
+            if let Some(index) = (0..registered.len()).find(|&index| registered[index] == counters) {
+                let last = registered.pop().unwrap();
+                registered.swap(index, last);
+            }
+            thread.registration.set(3);
+        })
+    }
+}
+
+thread_local! {
+    static ACTIVITY: ThreadActivity = const {
+        ThreadActivity {
+            counters: Activity::new(),
+            depths: [const { Cell::new(0) }; ACTIVITIES],
+            registration: Cell::new(0),
+        }
+    };
+    static ACTIVITY_REGISTRATION: ActivityRegistration = const { ActivityRegistration };
+}
+
+/// The counters of every thread that has used the allocator
+static REGISTERED_ACTIVITIES: Mutex<Array<usize>> = Mutex::new(Array::new());
+/// The counters of threads that have none of their own, because they are registering theirs or exiting
+static SHARED_ACTIVITY: Activity = Activity::new();
+
+/// The activity of the thread that disabled the heap, or 0 while it is enabled. A thread that is not in the middle of
+/// an activity waits before starting one while the heap is disabled, unless it is the thread that disabled it.
+static DISABLER: AtomicUsize = AtomicUsize::new(0);
+/// Set while a thread keeps the central reserve, the superblocks and the arenas still. A thread waits before changing
+/// them, unless it is already doing so.
+static HELD_STILL: AtomicBool = AtomicBool::new(false);
+
+impl ThreadActivity {
+    /// The counters the thread marks its activities in, registering its own the first time
+    fn counters(&self) -> &'static Activity {
+        if self.registration.get() == 0 {
+            // Registering can allocate, which uses the shared counters in the meantime
+            self.registration.set(1);
+            let registered = ACTIVITY_REGISTRATION
+                .try_with(|_| REGISTERED_ACTIVITIES.lock().push(&self.counters as *const Activity as usize))
+                .is_ok();
+            self.registration.set(if registered { 2 } else { 3 });
+        }
+        if self.registration.get() == 2 {
+            // the counters are removed from the registered counters before they are gone
+            unsafe { &*(&self.counters as *const Activity) }
+        } else {
+            &SHARED_ACTIVITY
+        }
+    }
+}
+
+/// Set once the process is registered for `membarrier`, so that the threads stopping the heap put a barrier on every
+/// other thread, and marking an activity needs none. It is only set while the allocator is initialized.
+static MEMBARRIER: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_os = "linux")]
+const MEMBARRIER_CMD_PRIVATE_EXPEDITED: libc::c_long = 1 << 3;
+#[cfg(target_os = "linux")]
+const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED: libc::c_long = 1 << 4;
+
+/// Registers the process for `membarrier`, if the kernel has it. Called while the allocator is initialized.
+#[cfg(target_os = "linux")]
+pub(crate) fn register_membarrier() {
+    let membarrier = |cmd: libc::c_long| unsafe { libc::syscall(libc::SYS_membarrier, cmd, 0) == 0 };
+    if membarrier(MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED) && membarrier(MEMBARRIER_CMD_PRIVATE_EXPEDITED) {
+        MEMBARRIER.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Registers the process for `membarrier`, if the kernel has it. Called while the allocator is initialized.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn register_membarrier() {}
+
+/// The barrier between raising a flag and reading the counters of every thread
+fn barrier_all_threads() {
+    fence(Ordering::SeqCst);
+    #[cfg(target_os = "linux")]
+    if MEMBARRIER.load(Ordering::Acquire) {
+        unsafe { libc::syscall(libc::SYS_membarrier, MEMBARRIER_CMD_PRIVATE_EXPEDITED, 0) };
+    }
+}
+
+/// Marks the calling thread as being in the middle of an activity until it is dropped
+pub(crate) struct ActivityGuard {
+    activity: usize,
+    /// The counter that was raised, or `None` if the thread was already in the middle of the activity
+    counter: Option<&'static AtomicUsize>,
+    /// Whether the counter is shared with other threads, and has to be changed atomically
+    shared: bool,
+}
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        match self.counter {
+            Some(counter) if self.shared => {
+                counter.fetch_sub(1, Ordering::Release);
+            }
+            Some(counter) => counter.store(0, Ordering::Release),
+            None => {}
+        }
+        let activity = self.activity;
+        ACTIVITY.with(|thread| thread.depths[activity].set(thread.depths[activity].get() - 1));
+    }
+}
+
+/// Marks the calling thread as being in the middle of `activity`, first waiting while the heap is disabled or, to
+/// change the heap, held still
+fn enter(activity: usize) -> ActivityGuard {
+    ACTIVITY.with(|thread| {
+        if thread.depths[activity].get() > 0 {
+            thread.depths[activity].set(thread.depths[activity].get() + 1);
+            return ActivityGuard {
+                activity,
+                counter: None,
+                shared: false,
+            };
+        }
+        // a thread in the middle of another activity is waited on by the thread disabling the heap, so it can not wait
+        // for it in turn
+        let idle = thread.depths.iter().all(|depth| depth.get() == 0);
+        let counters = thread.counters();
+        let counter = &counters.0[activity];
+        let shared = std::ptr::eq(counters, &SHARED_ACTIVITY);
+        let me = thread as *const ThreadActivity as usize;
+        let blocked = || {
+            let disabler = DISABLER.load(Ordering::Acquire);
+            (idle && disabler != 0 && disabler != me) || (activity == HEAP && HELD_STILL.load(Ordering::Acquire))
+        };
+        loop {
+            if shared {
+                counter.fetch_add(1, Ordering::SeqCst);
+            } else {
+                counter.store(1, Ordering::Relaxed);
+                if MEMBARRIER.load(Ordering::Relaxed) {
+                    compiler_fence(Ordering::SeqCst);
+                } else {
+                    fence(Ordering::SeqCst);
+                }
+            }
+            if !blocked() {
+                thread.depths[activity].set(1);
+                return ActivityGuard {
+                    activity,
+                    counter: Some(counter),
+                    shared,
+                };
+            }
+            if shared {
+                counter.fetch_sub(1, Ordering::Release);
+            } else {
+                counter.store(0, Ordering::Release);
+            }
+            while blocked() {
+                std::hint::spin_loop();
+            }
+        }
+    })
+}
+
+/// Waits until no thread is in the middle of any of `activities`. Threads that have not started one by the time the
+/// flag they wait on was raised never do.
+fn wait_for(activities: &[usize]) {
+    let registered = REGISTERED_ACTIVITIES.lock();
+    let registered: &[usize] = &registered;
+    let shared = &SHARED_ACTIVITY as *const Activity as usize;
+    for &counters in registered.iter().chain(std::iter::once(&shared)) {
+        let counters = unsafe { &*(counters as *const Activity) };
+        for &activity in activities {
+            while counters.0[activity].load(Ordering::Acquire) != 0 {
+                std::hint::spin_loop();
+            }
+        }
+    }
+}
+
+/// Keeps the heap from being disabled or walked while the central reserve, a superblock or an arena is changed. Only
+/// the counters of the calling thread are written, and nothing is written if it is already changing the heap.
+#[inline]
+pub(crate) fn heap_guard() -> ActivityGuard {
+    enter(HEAP)
+}
+
+/// Keeps every other thread from changing the central reserve, a superblock or an arena until the guard is dropped
+pub(crate) struct HeapStillGuard;
+
+impl Drop for HeapStillGuard {
+    fn drop(&mut self) {
+        HELD_STILL.store(false, Ordering::Release);
+    }
+}
+
+/// Keeps every other thread from changing the central reserve, a superblock or an arena until the guard is dropped. If
+/// the heap is disabled, it is already still and nothing is held.
+pub(crate) fn hold_heap_still() -> Option<HeapStillGuard> {
+    MALLOC_INIT_S.with(|| unsafe { init_malloc() });
+    if DISABLER.load(Ordering::Acquire) != 0 {
+        return None;
+    }
+    while HELD_STILL
+        .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed)
+        .is_err()
+    {
+        std::hint::spin_loop();
+    }
+    barrier_all_threads();
+    wait_for(&[HEAP]);
+    Some(HeapStillGuard)
+}
+
+/// Keeps the heap from being disabled while a thread cache bin is used, waiting for it to be enabled again if it is
+/// disabled. Only the counters of the calling thread are written.
+#[inline]
+pub(crate) fn cache_guard() -> ActivityGuard {
+    enter(CACHE)
+}
+
+/// Stops every other thread from allocating or freeing until [`enable_allocation()`](fn.enable_allocation.html) is
+/// called. Every allocation and free that is in progress, including those that only use a thread cache, is finished
+/// before this returns, and any new call from another thread blocks until the heap is enabled. The thread that disabled
+/// the heap can keep allocating and freeing. Calling this while the heap is already disabled has no effect.
+///
+/// This is what `malloc_disable` does in bionic, and is used to keep the heap still for
+/// [`IterationMode::Quiescent`](enum.IterationMode.html#variant.Quiescent), or around a `fork`.
+pub fn disable_allocation() {
+    MALLOC_INIT_S.with(|| unsafe { init_malloc() });
+    let me = ACTIVITY.with(|thread| thread as *const ThreadActivity as usize);
+    if DISABLER.compare_exchange(0, me, Ordering::SeqCst, Ordering::Relaxed).is_err() {
+        return;
+    }
+    barrier_all_threads();
+    wait_for(&[CACHE, HEAP]);
+}
+
+/// Lets threads allocate and free again after [`disable_allocation()`](fn.disable_allocation.html). Calling this while
+/// the heap is enabled has no effect.
+pub fn enable_allocation() {
+    DISABLER.store(0, Ordering::Release);
+}
+
+/// How [`iterate_heap()`](fn.iterate_heap.html) deals with other threads
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IterationMode {
+    /// Every block is reported exactly. No other thread may be using the allocator while the heap is walked, so they
+    /// must be stopped by the caller, or blocked with [`disable_allocation()`](fn.disable_allocation.html).
+    Quiescent,
+    /// Other threads keep running. The superblocks, large allocations and arenas are kept still while the heap is
+    /// walked, but the thread caches of other threads are not read, so their blocks are reported as allocated.
+    Concurrent,
+}
+
+/// Calls `callback` with the pointer and usable size of every block that is allocated to the user, in no particular
+/// order. Large allocations are reported with the pointer they were handed out with, and the rest of their segment.
+///
+/// # Safety
+/// `callback` must not allocate or free memory from this allocator. With
+/// [`IterationMode::Quiescent`](enum.IterationMode.html#variant.Quiescent), no other thread may use the allocator while
+/// this runs.
+pub unsafe fn iterate_heap<F: FnMut(*mut u8, usize)>(mode: IterationMode, mut callback: F) {
+    MALLOC_INIT_S.with(|| init_malloc());
+
+    let _guard = match mode {
+        IterationMode::Quiescent => None,
+        IterationMode::Concurrent => hold_heap_still(),
+    };
+
+    // The free blocks that are held outside of their superblock
+    let mut cached = Array::<usize>::new();
+    match mode {
+        IterationMode::Quiescent => {
+            for_each_registered_cache(|bins| {
+                for bin in bins.iter() {
+                    bin.for_each_block(|block| cached.push(block as usize));
+                }
+            });
+        }
+        IterationMode::Concurrent => {
+            for_each_own_cache(|bins| {
+                for bin in bins.iter() {
+                    bin.for_each_block(|block| cached.push(block as usize));
+                }
+            });
+        }
+    }
+    let mut arenas = Array::<*const ArenaHeap>::new();
+    Descriptor::for_each_live(|desc| {
+        if !desc.arena.is_null() && !arenas.contains(&desc.arena) {
+            arenas.push(desc.arena);
+            (*desc.arena).for_each_free_block(|block| cached.push(block as usize));
+        }
+    });
+    cached.sort_unstable();
+
+    let mut free = Array::<bool>::new();
+    Descriptor::for_each_live(|desc| {
+        let segment = desc.super_block.as_ref().unwrap();
+        let start = segment.get_ptr() as *mut u8;
+        let anchor = desc.anchor.load(Ordering::Acquire);
+        if desc.is_large() {
+            let offset = anchor.avail() as usize * PAGE;
+            return callback(start.add(offset), segment.len() - offset);
+        }
+
+        let block_size = desc.block_size as usize;
+        let max_count = desc.max_count as usize;
+        let end = start as usize + max_count * block_size;
+        free.grow(max_count);
+        free[..max_count].iter_mut().for_each(|free| *free = false);
+
+        let mut block = start.add(anchor.avail() as usize * block_size);
+        for _ in 0..anchor.count() {
+            if block < start || block as usize >= end {
+                break;
+            }
+            free[(block as usize - start as usize) / block_size] = true;
+            block = next_block(block, desc.block_size);
+        }
+        let first = cached.partition_point(|&block| block < start as usize);
+        for &block in cached[first..].iter().take_while(|&&block| block < end) {
+            free[(block - start as usize) / block_size] = true;
+        }
+
+        for index in (0..max_count).filter(|&index| !free[index]) {
+            callback(start.add(index * block_size), block_size);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mem_info::MAX_SZ;
+    use crate::{do_aligned_alloc, do_free, do_malloc};
+
+    #[test]
+    fn finds_allocated_blocks() {
+        std::thread::spawn(|| unsafe {
+            let small = do_malloc(100);
+            let freed = do_malloc(100);
+            let large = do_malloc(MAX_SZ * 3);
+            let aligned = do_aligned_alloc(PAGE * 4, MAX_SZ * 2);
+            do_free(freed);
+
+            let expected = [small, large, aligned];
+            let mut found = [0usize; 3];
+            let mut found_freed = false;
+            iterate_heap(IterationMode::Concurrent, |block, size| {
+                if let Some(index) = expected.iter().position(|&ptr| ptr == block) {
+                    found[index] = size;
+                }
+                found_freed |= block == freed;
+            });
+
+            assert!(found[0] >= 100);
+            assert!(found[1] >= MAX_SZ * 3);
+            assert!(found[2] >= MAX_SZ * 2, "An aligned allocation is reported from its aligned pointer");
+            assert!(!found_freed, "A block in the thread cache is free");
+            for ptr in expected.iter() {
+                do_free(*ptr);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn disable_while_allocating() {
+        const THREADS: usize = 4;
+        static STOP: AtomicBool = AtomicBool::new(false);
+        static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+        // Whether each thread is about to call, or is in, the allocator
+        static CALLING: [AtomicBool; THREADS] = [const { AtomicBool::new(false) }; THREADS];
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|index| {
+                std::thread::spawn(move || {
+                    while !STOP.load(Ordering::Acquire) {
+                        CALLING[index].store(true, Ordering::SeqCst);
+                        let ptr = do_malloc(64);
+                        CALLING[index].store(false, Ordering::SeqCst);
+                        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+                        CALLING[index].store(true, Ordering::SeqCst);
+                        unsafe { do_free(ptr) };
+                        CALLING[index].store(false, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..100 {
+            disable_allocation();
+            let before = ALLOCATIONS.load(Ordering::SeqCst);
+            // every thread gets to the allocator, and stays there until the heap is enabled
+            while !CALLING.iter().all(|calling| calling.load(Ordering::SeqCst)) {
+                std::thread::yield_now();
+            }
+            // reads the thread caches of the other threads, which none of them may be changing
+            unsafe { iterate_heap(IterationMode::Quiescent, |_, _| {}) };
+            // the thread that disabled the heap is not blocked
+            unsafe { do_free(do_malloc(64)) };
+            // a thread may only count the allocation it returned from before the heap was disabled
+            let during = ALLOCATIONS.load(Ordering::SeqCst) - before;
+            enable_allocation();
+            assert!(during <= THREADS, "{} allocations were made while the heap was disabled", during);
+        }
+        STOP.store(true, Ordering::Release);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
@@ -1,24 +1,30 @@
 #![allow(non_upper_case_globals)]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
 
 #[macro_use]
 extern crate bitfield;
 
 use std::ffi::c_void;
-use std::ptr::null_mut;
+use std::ptr::{null_mut, NonNull};
 use std::sync::atomic::AtomicUsize;
 
 use atomic::Ordering;
-use spin::Mutex;
 
-use crate::alloc::{get_page_info_for_ptr, register_desc, unregister_desc, update_page_map};
-use crate::allocation_data::{Anchor, Descriptor, DescriptorNode, get_heaps, SuperBlockState};
-use crate::bootstrap::{bootstrap_reserve, use_bootstrap};
+use crate::alloc::{
+    compute_index, get_page_info_for_ptr, malloc_count_from_partial, register_desc, release_empty_superblocks,
+    return_blocks, trim_partial_superblocks, try_malloc_count_from_new_sb, unregister_desc,
+};
+use crate::allocation_data::{Anchor, Descriptor, global_heaps, ProcHeap, SuperBlockState};
+use crate::heap::HeapCore;
+use crate::arena::{arena_of, arenas_exist};
+use crate::bootstrap::{bootstrap_reserve, ptr_in_bootstrap_range, ptr_in_bootstrap_reserve, use_bootstrap};
+use crate::iterate::{cache_guard, heap_guard};
+use crate::lifetime::Lifetime;
 use crate::mem_info::{align_addr, align_size, MAX_SZ, MAX_SZ_IDX, PAGE};
-use crate::page_map::S_PAGE_MAP;
 use crate::pages::external_mem_reservation::{SegAllocator, SEGMENT_ALLOCATOR};
 use crate::single_access::SingleAccess;
 use crate::size_classes::{get_size_class, init_size_class, SIZE_CLASSES};
-use crate::thread_cache::{fill_cache, flush_cache};
+use crate::thread_cache::{flush_cache, try_fill_cache, ThreadCacheBin};
 
 #[macro_export]
 macro_rules! dump_info {
@@ -33,7 +39,13 @@ pub mod macros;
 
 pub mod alloc;
 pub mod allocation_data;
+pub mod arena;
+pub mod config;
+pub mod heap;
 pub mod independent_collections;
+pub mod iterate;
+pub mod lifetime;
+pub mod mallocx;
 #[cfg(feature = "track_allocation")]
 pub mod info_dump;
 #[allow(unused)]
@@ -43,9 +55,23 @@ pub mod page_map;
 pub mod pages;
 pub mod single_access;
 pub mod size_classes;
+pub mod stats;
+pub mod tags;
 pub mod thread_cache;
 
 mod bootstrap;
+#[cfg(feature = "debug_checks")]
+mod debug_checks;
+mod error;
+mod global_alloc;
+
+pub use error::{AllocError, MallocError};
+pub use global_alloc::ApfAlloc;
+
+#[cfg(feature = "allocator_api")]
+mod allocator_api;
+#[cfg(feature = "allocator_api")]
+pub use allocator_api::Apf;
 
 pub mod ptr {
     pub mod auto_ptr;
@@ -54,8 +80,6 @@ pub mod ptr {
 
 pub mod apf;
 
-static AVAILABLE_DESC: Mutex<DescriptorNode> = Mutex::new(DescriptorNode::new());
-
 pub static IN_CACHE: AtomicUsize = AtomicUsize::new(0);
 pub static IN_BOOTSTRAP: AtomicUsize = AtomicUsize::new(0);
 
@@ -71,29 +95,25 @@ pub const TRACK_ALLOCATION_LOCATION: bool = false;
 /// Initializes malloc. Only needs to ran once for the entire program, and manually running it again will cause all of the memory saved
 /// in the central reserve to be lost
 unsafe fn init_malloc() {
-    init_size_class();
+    config::init();
+    USE_APF = config::use_apf();
 
-    S_PAGE_MAP.init();
-
-    let option = option_env!("USE_APF");
-    if option == Some("false") {
-        USE_APF = false;
-    }
+    init_size_class();
 
-    for idx in 0..MAX_SZ_IDX {
-        let heap = get_heaps().get_heap_at_mut(idx);
-
-        heap.partial_list.store(None, Ordering::Release);
-        heap.size_class_index = idx;
-    }
+    heap::map_global_page_map();
+    global_heaps();
 
     bootstrap_reserve.lock().init();
+    iterate::register_membarrier();
 
     //info!("Malloc Initialized")
 }
 
 /// Performs an aligned allocation for type `T`. Type `T` must be `Sized`
 ///
+/// A zero-sized type is not given any memory. Instead, the pointer is dangling, but aligned for `T`, and freeing it
+/// with [`do_free()`](fn.do_free.html) or [`deallocate_val()`](fn.deallocate_val.html) has no effect.
+///
 /// # Example
 /// ```
 /// use apfmalloc_lib::allocate_type;
@@ -107,9 +127,13 @@ unsafe fn init_malloc() {
 /// Although this function can not cause undefined behavior, the pointer created by this method should be deallocated using
 /// [`do_free()`](fn.do_free.html)
 pub fn allocate_type<T>() -> *mut T {
-    let size = std::mem::size_of::<T>();
-    let align = std::mem::align_of::<T>();
-    do_aligned_alloc(align, size) as *mut T
+    try_allocate_type::<T>().map_or(null_mut(), NonNull::as_ptr)
+}
+
+/// Performs an aligned allocation for type `T` in the same way as [`allocate_type()`](fn.allocate_type.html), but
+/// returns the reason the allocation failed instead of a NULL pointer
+pub fn try_allocate_type<T>() -> Result<NonNull<T>, MallocError> {
+    try_allocate_typed(1, false)
 }
 
 /// Allocates a space in memory with correct alignment for type `T`, then sets the value at the pointed location to `val`
@@ -118,19 +142,242 @@ pub fn allocate_type<T>() -> *mut T {
 /// If for whatever reason, the pointer created is a NULL pointer, this function will not attempt to write to the pointer,
 /// so no SEGFAULTS should occur
 pub fn allocate_val<T>(val: T) -> *mut T {
-    let ret = allocate_type::<T>();
-    if !ret.is_null() {
-        unsafe {
-            ret.write(val);
-        }
+    try_allocate_val(val).map_or(null_mut(), NonNull::as_ptr)
+}
+
+/// Allocates a space in memory for `val` and moves it there, or returns the reason the allocation failed, in which case
+/// `val` is dropped
+pub fn try_allocate_val<T>(val: T) -> Result<NonNull<T>, MallocError> {
+    let ptr = try_allocate_type::<T>()?;
+    unsafe { ptr.as_ptr().write(val) };
+    Ok(ptr)
+}
+
+/// Allocates a space in memory with correct alignment for type `T`, with every byte set to zero. Memory that has never
+/// been handed out is already zero, so only memory that is being reused is cleared.
+///
+/// If the allocation fails, a NULL pointer is returned.
+pub fn allocate_zeroed<T>() -> *mut T {
+    try_allocate_zeroed::<T>().map_or(null_mut(), NonNull::as_ptr)
+}
+
+/// Allocates a zeroed space in memory for type `T` in the same way as [`allocate_zeroed()`](fn.allocate_zeroed.html),
+/// but returns the reason the allocation failed instead of a NULL pointer
+pub fn try_allocate_zeroed<T>() -> Result<NonNull<T>, MallocError> {
+    try_allocate_typed(1, true)
+}
+
+/// Allocates a space in memory for `count` values of type `T` laid out one after another. The values are
+/// _uninitialized_.
+///
+/// If the allocation fails, including when the size of the array does not fit in a `usize`, a NULL pointer is returned.
+/// An array that takes no space, because `count` is 0 or `T` is zero-sized, gets a dangling pointer in the same way as
+/// [`allocate_type()`](fn.allocate_type.html).
+///
+/// # Example
+/// ```
+/// use apfmalloc_lib::{allocate_array, deallocate_array};
+///
+/// let squares = allocate_array::<u64>(10);
+/// unsafe {
+///     for i in 0..10 {
+///         squares.add(i).write(i as u64 * i as u64);
+///     }
+///     assert_eq!(*squares.add(9), 81);
+///     deallocate_array(squares, 10);
+/// }
+/// assert!(allocate_array::<u64>(usize::MAX).is_null());
+/// ```
+pub fn allocate_array<T>(count: usize) -> *mut T {
+    try_allocate_array::<T>(count).map_or(null_mut(), NonNull::as_ptr)
+}
+
+/// Allocates a space in memory for `count` values of type `T` in the same way as
+/// [`allocate_array()`](fn.allocate_array.html), but returns the reason the allocation failed instead of a NULL
+/// pointer. If the size of the array does not fit in a `usize`, the error is
+/// [`MallocError::SizeOverflow`](enum.MallocError.html) with a size of `usize::MAX`.
+pub fn try_allocate_array<T>(count: usize) -> Result<NonNull<T>, MallocError> {
+    try_allocate_typed(count, false)
+}
+
+fn try_allocate_typed<T>(count: usize, zeroed: bool) -> Result<NonNull<T>, MallocError> {
+    let size = std::mem::size_of::<T>()
+        .checked_mul(count)
+        .ok_or(MallocError::SizeOverflow(usize::MAX))?;
+    if size == 0 {
+        // Would otherwise be given the smallest block, and show up in the statistics as a live allocation
+        return Ok(NonNull::dangling());
+    }
+    let (ptr, state) = try_aligned_alloc_with_state(std::mem::align_of::<T>(), size)?;
+    if zeroed {
+        unsafe { zero_allocation(ptr.as_ptr(), size, state) };
+    }
+    Ok(ptr.cast())
+}
+
+/// Drops the value at `ptr`, then frees its memory. Passing a NULL pointer has no effect.
+///
+/// # Safety
+/// `ptr` must have been returned by [`allocate_val()`](fn.allocate_val.html), or by
+/// [`allocate_type()`](fn.allocate_type.html) or [`allocate_zeroed()`](fn.allocate_zeroed.html) for the same `T` and
+/// then initialized, and must not be used after this.
+pub unsafe fn deallocate_val<T>(ptr: *mut T) {
+    deallocate_array(ptr, 1)
+}
+
+/// Drops the `count` values of the array at `ptr`, then frees its memory. Passing a NULL pointer has no effect.
+///
+/// # Safety
+/// `ptr` must have been returned by [`allocate_array()`](fn.allocate_array.html) for the same `T` and `count`, every
+/// value in the array must be initialized, and the array must not be used after this.
+pub unsafe fn deallocate_array<T>(ptr: *mut T, count: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    std::ptr::drop_in_place(std::ptr::slice_from_raw_parts_mut(ptr, count));
+    let size = std::mem::size_of::<T>() * count;
+    if size != 0 {
+        do_free_sized(ptr, size, std::mem::align_of::<T>());
+    }
+}
+
+/// Whether the memory of a new allocation is already zero
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum ZeroState {
+    /// The memory may have been used before
+    Dirty,
+    /// A block that has never been handed out. Only its first word, which the thread cache uses as a link, may not be
+    /// zero
+    AllButLink,
+    /// Freshly mapped memory
+    Zeroed,
+}
+
+/// Sets the first `len` bytes of a new allocation to zero, skipping the bytes that are already known to be zero
+pub(crate) unsafe fn zero_allocation(ptr: *mut u8, len: usize, state: ZeroState) {
+    if ptr.is_null() {
+        return;
+    }
+    match state {
+        ZeroState::Dirty => ptr.write_bytes(0, len),
+        ZeroState::AllButLink => ptr.write_bytes(0, len.min(std::mem::size_of::<usize>())),
+        ZeroState::Zeroed => {}
     }
-    ret
 }
 
 /// Allocates a space in memory of length `size`.
 ///
 /// If the allocation fails, a NULL pointer is returned.
 pub fn do_malloc(size: usize) -> *mut u8 {
+    malloc_with_state(size).0
+}
+
+/// Allocates a space in memory of length `size`. Unlike [`do_malloc()`](fn.do_malloc.html), a failed allocation returns
+/// the reason it failed.
+///
+/// # Example
+/// ```
+/// use apfmalloc_lib::{do_free, try_malloc, MallocError};
+///
+/// let ptr = try_malloc(64).unwrap();
+/// unsafe { do_free(ptr.as_ptr()) };
+/// assert!(matches!(try_malloc(usize::MAX), Err(MallocError::SizeOverflow(_))));
+/// ```
+pub fn try_malloc(size: usize) -> Result<NonNull<u8>, MallocError> {
+    try_malloc_with_state(size).map(|(ptr, _)| ptr)
+}
+
+/// Allocates a space in memory of length `size`, with every byte set to zero. Memory that has never been handed out is
+/// already zero, so only memory that is being reused is cleared.
+///
+/// If the allocation fails, a NULL pointer is returned.
+pub fn do_malloc_zeroed(size: usize) -> *mut u8 {
+    let (ptr, state) = malloc_with_state(size);
+    unsafe { zero_allocation(ptr, size, state) };
+    ptr
+}
+
+/// Allocates a block of `size` bytes for every slot of `out`, and returns the number of blocks that were allocated,
+/// which are written to the start of `out`. Fewer blocks are allocated only if the allocation would otherwise fail, in
+/// which case the rest of `out` is left as it was.
+///
+/// Blocks are taken from the thread cache in runs, so the thread cache is only looked up once, and the blocks are not
+/// passed through the APF tuner. Whenever the thread cache runs out, it is refilled with the free blocks of a partial
+/// superblock or with a whole new superblock. Large allocations are made one at a time.
+///
+/// # Example
+/// ```
+/// use apfmalloc_lib::{do_free_batch, do_malloc_batch};
+///
+/// let mut nodes = [std::ptr::null_mut(); 1000];
+/// assert_eq!(do_malloc_batch(48, &mut nodes), 1000);
+/// unsafe { do_free_batch(&nodes) };
+/// ```
+pub fn do_malloc_batch(size: usize, out: &mut [*mut u8]) -> usize {
+    MALLOC_INIT_S.with(|| unsafe { init_malloc() });
+
+    let count = if size > MAX_SZ || std::thread::panicking() || use_bootstrap() {
+        // The bootstrap reserve has no thread cache to take a run from
+        malloc_each(size, out)
+    } else {
+        let size_class_index = get_size_class(size);
+        let _cache_guard = cache_guard();
+        thread_cache::cache_for(lifetime::current())
+            .try_with(|tcache| {
+                let cache = unsafe { &mut (*tcache.get())[size_class_index] };
+                malloc_runs(size_class_index, cache, &mut *out)
+            })
+            // The thread cache is gone once the thread is exiting, such as in a destructor of another thread local
+            .unwrap_or_else(|_| malloc_each(size, out))
+    };
+    tags::notify_soft_limit();
+    count
+}
+
+/// Fills `out` with blocks allocated one at a time, stopping at the first one that fails
+fn malloc_each(size: usize, out: &mut [*mut u8]) -> usize {
+    let mut count = 0;
+    for slot in out.iter_mut() {
+        match try_malloc(size) {
+            Ok(ptr) => *slot = ptr.as_ptr(),
+            Err(_) => break,
+        }
+        count += 1;
+    }
+    count
+}
+
+/// Fills `out` with runs of blocks popped from `cache`, refilling it whenever it is empty
+fn malloc_runs(size_class_index: usize, cache: &mut ThreadCacheBin, out: &mut [*mut u8]) -> usize {
+    let block_size = unsafe { SIZE_CLASSES[size_class_index].block_size } as usize;
+    let mut count = 0;
+    while count < out.len() {
+        if cache.get_block_num() == 0 && try_fill_cache(size_class_index, cache).is_err() {
+            break;
+        }
+        let mut run = (out.len() - count).min(cache.get_block_num() as usize);
+        if tags::charge_current_blocks(block_size, run).is_err() {
+            // The whole run would take the tag over its hard limit, but some of it might still fit
+            run = 1;
+            if tags::charge_current_blocks(block_size, run).is_err() {
+                break;
+            }
+        }
+        cache.pop_run(&mut out[count..count + run]);
+        #[cfg(feature = "debug_checks")]
+        for &ptr in &out[count..count + run] {
+            debug_checks::block_allocated(ptr);
+        }
+        count += run;
+    }
+    count
+}
+
+fn malloc_with_state(size: usize) -> (*mut u8, ZeroState) {
+    try_malloc_with_state(size).map_or((null_mut(), ZeroState::Dirty), |(ptr, state)| (ptr.as_ptr(), state))
+}
+
+fn try_malloc_with_state(size: usize) -> Result<(NonNull<u8>, ZeroState), MallocError> {
     MALLOC_INIT_S.with(|| unsafe { init_malloc() });
     /*
     unsafe {
@@ -144,34 +391,18 @@ pub fn do_malloc(size: usize) -> *mut u8 {
 
      */
 
-    if size > MAX_SZ {
-        let pages = page_ceiling!(size);
-        let desc = unsafe { &mut *Descriptor::alloc() };
-
-        desc.proc_heap = null_mut();
-        desc.block_size = pages as u32;
-        desc.max_count = 1;
-        desc.super_block = SEGMENT_ALLOCATOR.allocate(pages).ok();
-
-        let mut anchor = Anchor::default();
-        anchor.set_state(SuperBlockState::FULL);
-
-        desc.anchor.store(anchor, Ordering::Release);
-
-        register_desc(desc);
-        let ptr = desc.super_block.as_ref().unwrap().get_ptr() as *mut u8;
-        // Log malloc with tuner
-        return ptr;
-    }
-
-    let size_class_index = get_size_class(size);
-
-    allocate_to_cache(size, size_class_index)
+    let ret = if size > MAX_SZ {
+        allocate_large(size, 1, tags::alloc_tag()).map(|(ptr, _)| (ptr, ZeroState::Zeroed))
+    } else {
+        allocate_to_cache_with_state(size, get_size_class(size))
+    };
+    tags::notify_soft_limit();
+    ret
 }
 
 fn is_power_of_two(x: usize) -> bool {
     // https://stackoverflow.com/questions/3638431/determine-if-an-int-is-a-power-of-2-or-not-in-a-single-line
-    (if x != 0 { true } else { false }) && (if (!(x & (x - 1))) != 0 { true } else { false })
+    x != 0 && (x & (x - 1)) == 0
 }
 
 
@@ -180,59 +411,154 @@ fn is_power_of_two(x: usize) -> bool {
 ///
 /// If the allocation fails, a NULL pointer is returned.
 pub fn do_aligned_alloc(align: usize, size: usize) -> *mut u8 {
-    if !is_power_of_two(align) {
-        return null_mut();
-    }
+    aligned_alloc_with_state(align, size).0
+}
 
-    let mut size = align_size(size, align);
+/// Allocates a space in memory of length `size`, that is aligned to `align`. Unlike
+/// [`do_aligned_alloc()`](fn.do_aligned_alloc.html), a failed allocation returns the reason it failed, including an
+/// `align` that is not a power of 2.
+pub fn try_aligned_alloc(align: usize, size: usize) -> Result<NonNull<u8>, MallocError> {
+    try_aligned_alloc_with_state(align, size).map(|(ptr, _)| ptr)
+}
 
-    MALLOC_INIT_S.with(|| unsafe { init_malloc() });
+/// Allocates a space in memory of length `size`, that is aligned to `align`, with every byte set to zero. `align` must
+/// be a power of 2.
+///
+/// If the allocation fails, a NULL pointer is returned.
+pub fn do_aligned_alloc_zeroed(align: usize, size: usize) -> *mut u8 {
+    let (ptr, state) = aligned_alloc_with_state(align, size);
+    unsafe { zero_allocation(ptr, size, state) };
+    ptr
+}
 
-    if size > PAGE {
-        size = size.max(MAX_SZ + 1);
+pub(crate) fn aligned_alloc_with_state(align: usize, size: usize) -> (*mut u8, ZeroState) {
+    try_aligned_alloc_with_state(align, size)
+        .map_or((null_mut(), ZeroState::Dirty), |(ptr, state)| (ptr.as_ptr(), state))
+}
 
-        let need_more_pages = align > PAGE;
-        if need_more_pages {
-            size += align;
-        }
+fn try_aligned_alloc_with_state(align: usize, size: usize) -> Result<(NonNull<u8>, ZeroState), MallocError> {
+    aligned_alloc_with(align, size, true)
+}
 
-        let pages = page_ceiling!(size);
+/// Allocates `size` bytes aligned to `align`. If `use_cache` is false, a block is taken straight from the central
+/// reserve instead of the thread cache.
+pub(crate) fn aligned_alloc_with(
+    align: usize,
+    size: usize,
+    use_cache: bool,
+) -> Result<(NonNull<u8>, ZeroState), MallocError> {
+    if !is_power_of_two(align) {
+        return Err(MallocError::InvalidAlignment(align));
+    }
+    if size > isize::MAX as usize {
+        return Err(MallocError::SizeOverflow(size));
+    }
 
-        let seg = match SEGMENT_ALLOCATOR.allocate(pages) {
-            Ok(seg) => seg,
-            Err(_) => {
-                return null_mut();
-            }
-        };
+    let size = align_size(size, align);
 
-        let desc = unsafe { &mut *Descriptor::alloc() };
+    MALLOC_INIT_S.with(|| unsafe { init_malloc() });
 
-        desc.proc_heap = null_mut();
-        desc.block_size = pages as u32;
-        desc.max_count = 1;
-        desc.super_block = Some(seg);
+    let ret = if size > PAGE {
+        allocate_large(size, align, tags::alloc_tag()).map(|(ptr, _)| (ptr, ZeroState::Zeroed))
+    } else if use_cache {
+        allocate_to_cache_with_state(size, get_size_class(size))
+    } else {
+        allocate_from_central(get_size_class(size))
+    };
+    tags::notify_soft_limit();
+    ret
+}
 
-        let mut ptr = desc.super_block.as_ref().unwrap().get_ptr() as *mut u8;
+/// Gives an allocation of `size` bytes aligned to `align` its own segment, which is never smaller than the biggest size
+/// class. Allocations aligned to more than a page are padded so that the aligned pointer still fits, and the page of
+/// the aligned pointer is registered as well. The allocation is charged to `tag`.
+///
+/// Returns an error if the padded size overflows, the segment would take `tag` over its hard limit, or the segment
+/// could not be mapped.
+pub(crate) fn allocate_large(
+    size: usize,
+    align: usize,
+    tag: u16,
+) -> Result<(NonNull<u8>, &'static mut Descriptor), MallocError> {
+    // the heap of a large allocation only records its tag
+    let heap = tags::heap_for(tag, Lifetime::Short, 0);
+    allocate_large_in(heap::global_core(), heap, size, align, tag)
+}
+
+/// Gives a large allocation its own segment in the same way as [`allocate_large()`](fn.allocate_large.html), for the
+/// instance `core`. The descriptor of the allocation points to `heap`.
+pub(crate) fn allocate_large_in(
+    core: &HeapCore,
+    heap: *const ProcHeap,
+    size: usize,
+    align: usize,
+    tag: u16,
+) -> Result<(NonNull<u8>, &'static mut Descriptor), MallocError> {
+    let need_more_pages = align > PAGE;
+    let pages = large_segment_len(size, align)?;
+    let _guard = heap_guard();
+
+    tags::take(tag, pages)?;
+    let seg = match SEGMENT_ALLOCATOR.allocate(pages) {
+        Ok(seg) => seg,
+        Err(e) => {
+            tags::give_back(tag, pages);
+            return Err(e.into());
+        }
+    };
+    let base = seg.get_ptr() as *mut u8;
+    let ptr = if need_more_pages {
+        align_addr(base as usize, align) as *mut u8
+    } else {
+        base
+    };
 
-        let mut anchor = Anchor::default();
-        anchor.set_state(SuperBlockState::FULL);
+    let desc = match unsafe { core.descriptors.try_alloc() } {
+        Ok(desc) => unsafe { &mut *desc },
+        Err(e) => {
+            unsafe { SEGMENT_ALLOCATOR.deallocate(seg) };
+            tags::give_back(tag, pages);
+            return Err(e.into());
+        }
+    };
 
-        desc.anchor.store(anchor, Ordering::Release);
+    desc.proc_heap = heap;
+    desc.block_size = large_block_size(pages);
+    desc.max_count = 1;
+    desc.super_block = Some(seg);
+    if core.is_global() {
+        stats::large_mapped(pages);
+    }
 
-        register_desc(desc);
+    // The single block of a large allocation starts at the aligned pointer, which is `avail` pages into the segment
+    let mut anchor = Anchor::default();
+    anchor.set_state(SuperBlockState::FULL);
+    anchor.set_avail(((ptr as usize - base as usize) / PAGE) as u64);
 
-        if need_more_pages {
-            ptr = align_addr(ptr as usize, align) as *mut u8;
+    desc.anchor.store(anchor, Ordering::Release);
 
-            update_page_map(None, ptr, Some(desc), 0);
-        }
+    core.page_map.register(desc);
 
-        return ptr;
-    }
+    Ok((unsafe { NonNull::new_unchecked(ptr) }, desc))
+}
 
-    let size_class_index = get_size_class(size);
+/// The length of the segment given to a large allocation of `size` bytes aligned to `align`, which is rounded up to
+/// whole pages, and padded if the alignment is bigger than a page
+fn large_segment_len(size: usize, align: usize) -> Result<usize, MallocError> {
+    let padding = if align > PAGE { align } else { 0 };
+    let padded = size
+        .max(MAX_SZ + 1)
+        .checked_add(padding + PAGE - 1)
+        .filter(|&padded| padded <= isize::MAX as usize)
+        .ok_or(MallocError::SizeOverflow(size))?;
+    Ok(padded & !(PAGE - 1))
+}
 
-    allocate_to_cache(size, size_class_index)
+/// The block size stored in the descriptor of a large allocation of `len` bytes. Anything bigger than a `u32` is
+/// saturated, as the block size is only used to tell large allocations apart from size classes, and the real length is
+/// kept by the segment.
+fn large_block_size(len: usize) -> u32 {
+    len.min(u32::MAX as usize) as u32
 }
 
 /// Requests either a memory of length `size` from the bootstrap or a block from a reserve of the
@@ -244,6 +570,40 @@ pub fn do_aligned_alloc(align: usize, size: usize) -> *mut u8 {
 /// # Safety
 /// This function is safe, as if for whatever reason memory can not be reserved, a NULL pointer is returned
 pub fn allocate_to_cache(size: usize, size_class_index: usize) -> *mut u8 {
+    allocate_to_cache_with_state(size, size_class_index).map_or(null_mut(), |(ptr, _)| ptr.as_ptr())
+}
+
+/// Takes a single block of the `size_class_index` size class from the central reserve, mapping a new superblock if
+/// there are no partial superblocks. The thread cache is not used.
+fn allocate_from_central(size_class_index: usize) -> Result<(NonNull<u8>, ZeroState), MallocError> {
+    let _guard = heap_guard();
+    let block_size = unsafe { SIZE_CLASSES[size_class_index].block_size };
+    let mut bin = ThreadCacheBin::new();
+    bin.block_size = Some(block_size);
+
+    let tag = tags::alloc_tag();
+    tags::take(tag, block_size as usize)?;
+    let mut block_num = 0;
+    malloc_count_from_partial(size_class_index, &mut bin, &mut block_num, 1);
+    if block_num == 0 {
+        if let Err(e) = try_malloc_count_from_new_sb(size_class_index, &mut bin, &mut block_num, 1) {
+            tags::give_back(tag, block_size as usize);
+            return Err(e.into());
+        }
+    }
+    let (ptr, fresh) = bin.pop_fresh_block();
+    #[cfg(feature = "debug_checks")]
+    debug_checks::block_allocated(ptr);
+
+    let ptr = unsafe { NonNull::new_unchecked(ptr) };
+    if fresh {
+        Ok((ptr, ZeroState::AllButLink))
+    } else {
+        Ok((ptr, ZeroState::Dirty))
+    }
+}
+
+fn allocate_to_cache_with_state(size: usize, size_class_index: usize) -> Result<(NonNull<u8>, ZeroState), MallocError> {
     // dbg!(size_class_index);
     // Because of how rust creates thread locals, we have to assume the thread local does not exist yet
     // We also can't tell if a thread local exists without causing it to initialize, and when using
@@ -273,7 +633,8 @@ pub fn allocate_to_cache(size: usize, size_class_index: usize) -> *mut u8 {
             IN_BOOTSTRAP.fetch_add(size, Ordering::AcqRel);
         }
 
-        unsafe { bootstrap_reserve.lock().allocate(size) }
+        let ptr = unsafe { bootstrap_reserve.lock().allocate(size)? };
+        Ok((unsafe { NonNull::new_unchecked(ptr) }, ZeroState::Dirty))
     } else {
         /*
 
@@ -311,36 +672,38 @@ pub fn allocate_to_cache(size: usize, size_class_index: usize) -> *mut u8 {
             IN_CACHE.fetch_add(size, Ordering::AcqRel);
         }
 
-        // If we are able to reach this piece of code, we know that the thread local cache is initalized
-        let ret = thread_cache::thread_cache.with(|tcache| {
+        let _cache_guard = cache_guard();
+        let lifetime = lifetime::current();
+        // The thread cache is initialized by now, unless it has already been destroyed because the thread is exiting
+        let ret = thread_cache::cache_for(lifetime).try_with(|tcache| {
             let cache = unsafe {
                 (*tcache.get()).get_mut(size_class_index).unwrap() // Gets the correct bin based on size class index
             };
 
             if cache.get_block_num() == 0 {
-                fill_cache(size_class_index, cache); // Fills the cache if necessary
-                if cache.block_num == 0 {
-                    panic!("Cache didn't fill");
-                }
+                try_fill_cache(size_class_index, cache)?; // Fills the cache if necessary
             }
+            tags::charge_current(unsafe { SIZE_CLASSES[size_class_index].block_size } as usize)?;
             #[cfg(feature = "track_allocation")]
-            {
-                let ret = cache.pop_block();
-                let size = get_allocation_size(ret as *const c_void).unwrap() as usize;
+            let (ptr, fresh) = {
+                let (ptr, fresh) = cache.pop_fresh_block();
+                let size = usable_size(ptr).unwrap();
                 crate::info_dump::log_malloc(size);
                 #[cfg(feature = "show_all_allocations")]
                 dump_info!();
-                ret
-            }
+                (ptr, fresh)
+            };
             #[cfg(not(feature = "track_allocation"))]
-            let ptr = cache.pop_block(); // Pops the block from the thread cache bin
+            let (ptr, fresh) = cache.pop_fresh_block(); // Pops the block from the thread cache bin
+            #[cfg(feature = "debug_checks")]
+            debug_checks::block_allocated(ptr);
 
             /* WARNING -- ELIAS CODE -- WARNING */
 
             // #[cfg(unix)]
             {
-                if unsafe { USE_APF } {
-                    thread_cache::skip.with(|b| unsafe {
+                if unsafe { USE_APF } && lifetime == Lifetime::Short {
+                    let _ = thread_cache::skip.try_with(|b| unsafe {
                         if !*b.get() {
                             let skip = b.get();
                             *skip = true;
@@ -362,9 +725,9 @@ pub fn allocate_to_cache(size: usize, size_class_index: usize) -> *mut u8 {
                             let _ = thread_cache::thread_init.with(|_| ());
                         }
                     });
-                    thread_cache::skip_tuners.with(|b| unsafe {
+                    let _ = thread_cache::skip_tuners.try_with(|b| unsafe {
                         if *b.get() == 0 {
-                            thread_cache::apf_tuners.with(|tuners| {
+                            let _ = thread_cache::apf_tuners.try_with(|tuners| {
                                 (&mut *tuners.get())
                                     .get_mut(size_class_index)
                                     .unwrap()
@@ -377,10 +740,15 @@ pub fn allocate_to_cache(size: usize, size_class_index: usize) -> *mut u8 {
 
             //set_use_bootstrap(true);
 
-            ptr
+            let ptr = unsafe { NonNull::new_unchecked(ptr) };
+            if fresh {
+                Ok((ptr, ZeroState::AllButLink))
+            } else {
+                Ok((ptr, ZeroState::Dirty))
+            }
         });
 
-        ret
+        ret.unwrap_or(Err(MallocError::ThreadExited))
     }
 }
 
@@ -389,55 +757,268 @@ pub fn allocate_to_cache(size: usize, size_class_index: usize) -> *mut u8 {
 ///
 /// The original `ptr` is free'd within this function, and should not be called manually.
 ///
-/// If the size class of the new `size` is the same as the old size class, then no new memory is allocated and nothing performed. The exception
-/// to this is if both the old size class and the new size class are part of the size class 0, which does not have a specific size. If this is
-/// the case, then the re-alloc operation is performed only if the new size greater than the old size.
+/// If the size class of the new `size` is the same as the old size class, then no new memory is allocated and nothing
+/// performed. Blocks bigger than a page, including allocations in the size class 0, which does not have a specific
+/// size, are also kept as long as the new `size` still fits in them and is bigger than a page.
 ///
-/// If a NULL pointer is passed through, it's equivalent to calling `malloc(size)`
+/// If a NULL pointer is passed through, it's equivalent to calling `malloc(size)`. If the allocation fails, a NULL
+/// pointer is returned and `ptr` is left as it was.
 ///
 /// # Safety
 /// If an invalid pointer is passed to this function, then a SEGFAULT will occur. As such, this function is marked as unsafe.
+/// A pointer that is not in the heap at all aborts the program, as its contents can't be moved.
 pub unsafe fn do_realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    try_realloc(ptr as *mut u8, size).map_or(null_mut(), |ptr| ptr.as_ptr() as *mut c_void)
+}
+
+/// Resizes the allocation at `ptr` to `size` bytes in the same way as [`do_realloc()`](fn.do_realloc.html), but returns
+/// the reason the allocation failed instead of a NULL pointer. If it fails, `ptr` is not freed.
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by this allocator that has not been freed.
+pub unsafe fn try_realloc(ptr: *mut u8, size: usize) -> Result<NonNull<u8>, MallocError> {
+    try_aligned_realloc(ptr, 1, size)
+}
+
+/// Resizes the allocation at `ptr` to `size` bytes in the same way as [`do_realloc()`](fn.do_realloc.html), keeping it
+/// aligned to `align`, which must be a power of 2. The allocation is only kept in place if it is already aligned to
+/// `align`.
+///
+/// If the allocation fails, a NULL pointer is returned and `ptr` is left as it was.
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by this allocator that has not been freed.
+pub unsafe fn do_aligned_realloc(ptr: *mut u8, align: usize, size: usize) -> *mut u8 {
+    try_aligned_realloc(ptr, align, size).map_or(null_mut(), NonNull::as_ptr)
+}
+
+/// Resizes the allocation at `ptr` to `size` bytes aligned to `align` in the same way as
+/// [`do_aligned_realloc()`](fn.do_aligned_realloc.html), but returns the reason the allocation failed instead of a NULL
+/// pointer. If it fails, `ptr` is not freed.
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by this allocator that has not been freed.
+pub unsafe fn try_aligned_realloc(ptr: *mut u8, align: usize, size: usize) -> Result<NonNull<u8>, MallocError> {
+    if !is_power_of_two(align) {
+        return Err(MallocError::InvalidAlignment(align));
+    }
+    let alloc = |size: usize| {
+        if align == 1 {
+            try_malloc(size)
+        } else {
+            try_aligned_alloc(align, size)
+        }
+    };
     if ptr.is_null() {
-        return do_malloc(size) as *mut c_void;
+        return alloc(size);
     }
-    let new_size_class = get_size_class(size);
-    let old_size = match get_allocation_size(ptr) {
-        Ok(size) => size as usize,
-        Err(_) => {
-            /*
-            eprintln!("Given a pointer that isn't in the heap");
-            exit(libc::EINVAL);
-
-             */
-            // just give up and return a malloc
-            return do_malloc(size) as *mut c_void;
-            //return null_mut();
+    let old_size = match usable_size(ptr) {
+        Some(size) => size,
+        // the size of memory from the bootstrap reserve is not kept, so it can't be copied
+        None if ptr_in_bootstrap_reserve(ptr) => return alloc(size),
+        None => {
+            // there is no way to tell how much of the old contents to keep
+            eprintln!("apfmalloc: realloc of {:p}, which was not allocated by apfmalloc", ptr);
+            std::process::abort()
         }
     };
-    let old_size_class = get_size_class(old_size);
-    if old_size_class != 0 && old_size_class == new_size_class
-        || old_size_class == 0 && new_size_class == 0 && size < old_size
-    {
-        return ptr;
+    // a remapped segment is only aligned to a page
+    if align <= PAGE {
+        if let Some(ret) = remap_large(ptr as *mut c_void, size) {
+            return Ok(NonNull::new_unchecked(ret as *mut u8));
+        }
+    }
+    if fits_in_place(ptr, old_size, size, align) {
+        return Ok(NonNull::new_unchecked(ptr));
     }
 
-    let ret = do_malloc(size) as *mut c_void;
+    let ret = match arena_of(ptr) {
+        Some(arena) => arena.alloc(size, align),
+        None => alloc(size),
+    }?;
 
-    if !ret.is_null() && ret != ptr {
-        libc::memcpy(ret, ptr, old_size);
+    if ret.as_ptr() != ptr {
+        std::ptr::copy_nonoverlapping(ptr, ret.as_ptr(), old_size.min(size));
     }
     do_free(ptr);
-    ret
+    Ok(ret)
+}
+
+/// Resizes a large allocation by remapping its segment, which avoids copying the allocation, and gives the pages no
+/// longer needed after shrinking back to the OS. The segment is never made smaller than the smallest large allocation.
+///
+/// Returns `None` if `ptr` is not the start of a large allocation, `size` is small enough for a block, or the segment
+/// can not be remapped.
+pub(crate) unsafe fn remap_large(ptr: *mut c_void, size: usize) -> Option<*mut c_void> {
+    if size <= PAGE {
+        return None;
+    }
+    let info = get_page_info_for_ptr(ptr);
+    if info.get_size_class_index() != Some(0) {
+        return None;
+    }
+    let _guard = heap_guard();
+    let desc = &mut *info.get_desc()?;
+    let segment = desc.super_block.as_mut()?;
+    // an allocation aligned to more than a page would lose its alignment
+    if segment.get_ptr() != ptr {
+        return None;
+    }
+
+    let old_len = segment.len();
+    let new_len = page_ceiling!(size.max(MAX_SZ + 1));
+    if new_len != old_len {
+        let tag = tags::tag_of(desc.proc_heap);
+        tags::resize(tag, old_len, new_len).ok()?;
+        // the pages are unregistered while the segment is remapped, so that none of them can be registered by another
+        // allocation before they are cleared
+        unregister_desc(None, segment);
+        let remapped = SEGMENT_ALLOCATOR.reallocate(segment, new_len).is_ok();
+        register_desc(desc);
+        if !remapped {
+            let _ = tags::resize(tag, new_len, old_len);
+            return None;
+        }
+        desc.block_size = large_block_size(new_len);
+        stats::large_unmapped(old_len);
+        stats::large_mapped(new_len);
+    }
+
+    desc.super_block.as_ref().map(|segment| segment.get_ptr())
+}
+
+/// The number of bytes [`do_aligned_alloc()`](fn.do_aligned_alloc.html) hands out for `size` bytes aligned to `align`,
+/// without allocating anything. This is the block size of the size class for sizes of up to a page, and the length of
+/// the segment for anything bigger, including the padding for alignments bigger than a page.
+///
+/// The padding is put before the aligned pointer, so [`sallocx()`](fn.sallocx.html) on a pointer aligned to more than a
+/// page can be smaller than this. Returns `None` if `align` is not a power of 2, or the size is too big to be
+/// allocated.
+///
+/// # Example
+/// ```
+/// use apfmalloc_lib::{do_aligned_alloc, do_free, sallocx, usable_size_for};
+///
+/// let size = usable_size_for(100, 8).unwrap();
+/// let ptr = do_aligned_alloc(8, 100);
+/// assert_eq!(sallocx(ptr), Some(size));
+/// unsafe { do_free(ptr) };
+/// ```
+pub fn usable_size_for(size: usize, align: usize) -> Option<usize> {
+    if !is_power_of_two(align) || size > isize::MAX as usize {
+        return None;
+    }
+    let size = align_size(size, align);
+    if size > PAGE {
+        return large_segment_len(size, align).ok();
+    }
+
+    MALLOC_INIT_S.with(|| unsafe { init_malloc() });
+    Some(unsafe { SIZE_CLASSES[get_size_class(size)].block_size } as usize)
+}
+
+/// The exact number of bytes that can be used at `ptr`, which may be more than was asked for when it was allocated.
+/// Returns `None` if `ptr` was not allocated by this allocator.
+pub fn sallocx<T: ?Sized>(ptr: *const T) -> Option<usize> {
+    usable_size(ptr)
+}
+
+/// Finds the allocation that `addr` points into, where `addr` can be anywhere inside of the allocation. Returns the
+/// pointer the allocation was handed out with, the number of bytes usable from that pointer, and the size class of the
+/// allocation, which is 0 for large allocations.
+///
+/// Returns `None` if `addr` is not inside a block of a superblock, or inside a large allocation. The padding before the
+/// pointer of a large allocation aligned to more than a page is not part of the allocation. Free blocks are found as
+/// well, and the result is only meaningful as long as the allocation is not freed at the same time.
+///
+/// # Example
+/// ```
+/// use apfmalloc_lib::{do_free, do_malloc, find_allocation};
+///
+/// let ptr = do_malloc(100);
+/// let (base, usable, _) = find_allocation(ptr as usize + 50).unwrap();
+/// assert_eq!(base, ptr);
+/// assert!(usable >= 100);
+/// unsafe { do_free(ptr) };
+/// ```
+pub fn find_allocation(addr: usize) -> Option<(*mut u8, usize, usize)> {
+    MALLOC_INIT_S.with(|| unsafe { init_malloc() });
+
+    // Superblocks and large allocations register all of their pages
+    let info = get_page_info_for_ptr(addr as *const u8);
+    let desc = unsafe { &*info.get_desc()? };
+
+    let segment = desc.super_block.as_ref()?;
+    let start = segment.get_ptr() as usize;
+    match info.get_size_class_index()? {
+        0 => {
+            let base = start + desc.anchor.load(Ordering::Acquire).avail() as usize * PAGE;
+            let end = start + segment.len();
+            if addr < base || addr >= end {
+                return None;
+            }
+            Some((base as *mut u8, end - base, 0))
+        }
+        size_class_index => {
+            let block_size = desc.block_size as usize;
+            if addr < start || addr >= start + desc.max_count as usize * block_size {
+                return None;
+            }
+            let index = compute_index(start as *mut u8, addr as *mut u8, size_class_index) as usize;
+            Some(((start + index * block_size) as *mut u8, block_size, size_class_index))
+        }
+    }
+}
+
+/// The number of bytes that can be used at `ptr`, which is the block size for small allocations, and the rest of the
+/// segment for large allocations. Returns `None` if the pointer was not allocated from a superblock or segment.
+pub(crate) fn usable_size<T: ?Sized>(ptr: *const T) -> Option<usize> {
+    usable_size_in(heap::global_core(), ptr)
+}
+
+/// The usable size of the allocation at `ptr`, which is found in the page map of the instance `core`
+pub(crate) fn usable_size_in<T: ?Sized>(core: &HeapCore, ptr: *const T) -> Option<usize> {
+    let info = core.page_info(ptr);
+    let desc = unsafe { &*info.get_desc()? };
+    match info.get_size_class_index() {
+        Some(0) => {
+            // an aligned large allocation starts part way into its segment
+            let segment = desc.super_block.as_ref()?;
+            Some(segment.len() - (ptr as *const u8 as usize - segment.get_ptr() as usize))
+        }
+        _ => Some(desc.block_size as usize),
+    }
+}
+
+/// Checks whether a block with `usable` bytes at `ptr` can hold `size` bytes aligned to `align` without moving.
+///
+/// A block of up to a page can only be kept if the new size is in the same size class, as otherwise a sized free would
+/// return it to the wrong size class. A bigger block is kept as long as the new size still fits, and is still over a
+/// page, as a sized free of anything over a page goes through the page map.
+pub(crate) fn fits_in_place<T: ?Sized>(ptr: *const T, usable: usize, size: usize, align: usize) -> bool {
+    if ptr as *const u8 as usize & (align - 1) != 0 {
+        return false;
+    }
+    let size = align_size(size, align);
+    if usable <= PAGE {
+        get_size_class(usable) == get_size_class(size)
+    } else {
+        size > PAGE && size <= usable
+    }
 }
 
 /// Determines the size of the allocation for a pointer. If no allocation data is available for the pointer, `Err(())` is returned. Otherwise,
-/// `Ok(block size)` is returned
+/// `Ok(usable size)` is returned, which is the same as [`usable_size()`](fn.usable_size.html). A large allocation whose
+/// size doesn't fit in a `u32` returns `Err(())` as well.
+#[deprecated(note = "use `sallocx()` or `usable_size()`, which work for allocations of any size")]
 pub fn get_allocation_size(ptr: *const c_void) -> Result<u32, ()> {
-    let info = get_page_info_for_ptr(ptr);
-    let desc = unsafe { &*info.get_desc().ok_or(())? };
+    usable_size(ptr).and_then(|size| std::convert::TryFrom::try_from(size).ok()).ok_or(())
+}
 
-    Ok(desc.block_size)
+/// Checks whether `ptr` points into memory handed out by the global instance of this allocator, either into a
+/// superblock, a large allocation or the bootstrap reserve. A `NULL` pointer is not owned.
+pub fn owns<T: ?Sized>(ptr: *const T) -> bool {
+    !ptr.is_null() && (get_page_info_for_ptr(ptr).get_desc().is_some() || ptr_in_bootstrap_reserve(ptr))
 }
 
 /// Frees a location in memory so that it can be reused at a later time. A free to a `NULL` pointer has no effect.
@@ -446,131 +1027,418 @@ pub fn get_allocation_size(ptr: *const c_void) -> Result<u32, ()> {
 ///
 /// If a pointer has already been freed, a second free to that pointer will cause undefined behavior, and likely a SEGFAULT
 pub unsafe fn do_free<T: ?Sized>(ptr: *const T) {
+    try_free(ptr);
+}
+
+/// Frees `ptr` in the same way as [`do_free()`](fn.do_free.html), but returns `false` if `ptr` was not allocated by
+/// this allocator, in which case nothing is done, so that it can be passed on to whichever allocator it came from.
+/// Memory from the bootstrap reserve is never reused, and freeing it only returns `true`.
+///
+/// # Safety
+///
+/// If a pointer has already been freed, a second free to that pointer will cause undefined behavior, and likely a
+/// SEGFAULT
+pub unsafe fn try_free<T: ?Sized>(ptr: *const T) -> bool {
     if ptr.is_null() {
-        return;
+        return true;
     }
     let info = get_page_info_for_ptr(ptr);
     let desc = &mut *match info.get_desc() {
         Some(d) => d,
-        None => {
-            // #[cfg(debug_assertions)]
-            // println!("Free failed at {:?}", ptr);
-            return; // todo: Band-aid fix
-                    // panic!("Descriptor not found for the pointer {:x?} with page info {:?}", ptr, info);
-        }
+        None => return ptr_in_bootstrap_reserve(ptr),
     };
 
-    // #[cfg(debug_assertions)]
-    // println!("Free will succeed at {:?}", ptr);
-
     let size_class_index = info.get_size_class_index();
+    if let Some(arena) = desc.arena.as_ref() {
+        arena.free(ptr as *mut u8, desc, size_class_index.unwrap_or(0));
+        return true;
+    }
     match size_class_index {
-        None | Some(0) => {
-            let super_block = desc.super_block.as_ref().unwrap();
-            // unregister
+        None | Some(0) => free_large(desc),
+        // a block of another tag can't go in the thread cache, which only holds blocks of the current tag, but it can
+        // go in the bins set aside for its tag
+        Some(size_class_index) if heaps_segregated(size_class_index) => {
+            let heap = &*desc.proc_heap;
+            if heap.tag == tags::alloc_tag() {
+                tags::uncharge_current(desc.block_size as usize);
+                free_to_cache(ptr as *mut u8, size_class_index, heap.lifetime)
+            } else if thread_cache::push_parked(ptr as *mut u8, heap.tag, heap.lifetime, size_class_index) {
+                tags::uncharge(heap.tag, desc.block_size as usize);
+            } else {
+                free_to_central(ptr as *mut u8, desc, size_class_index)
+            }
+        }
+        Some(size_class_index) => free_to_cache(ptr as *mut u8, size_class_index, Lifetime::Short),
+    }
+    true
+}
 
-            unregister_desc(None, super_block);
+/// Frees a location in memory whose size and alignment are already known, such as the `Layout` passed to
+/// `GlobalAlloc::dealloc`. `size` and `align` must be the values the memory was allocated with, where memory from
+/// [`do_malloc()`](fn.do_malloc.html) has an `align` of 1. A free to a `NULL` pointer has no effect.
+///
+/// This works out the size class from `size` instead of reading it from the descriptor of the block, so the page map is
+/// only used for allocations that may have been given their own segment, or that may be in the bootstrap reserve. The
+/// fast path is lost, and every free goes through [`do_free()`](fn.do_free.html), once an
+/// [`Arena`](arena/struct.Arena.html) exists, as well as for the size classes that have had blocks of a
+/// [tag](tags/index.html) or [long-lived](lifetime/index.html) blocks, and on threads with a tag other than 0, as the
+/// block may then belong to a cache other than the one of its size class.
+///
+/// # Safety
+///
+/// Passing a different size or alignment than the pointer was allocated with will corrupt the thread cache. In debug
+/// builds, this is checked against the descriptor.
+pub unsafe fn do_free_sized<T: ?Sized>(ptr: *const T, size: usize, align: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    match sized_free_class(ptr, size, align) {
+        Some(size_class_index) => free_to_cache(ptr as *mut u8, size_class_index, Lifetime::Short),
+        None => do_free(ptr),
+    }
+}
 
-            // if large allocation
-            if ptr as *const u8 != super_block.get_ptr() as *const u8 {
-                unregister_desc(None, super_block)
+/// Frees `ptr` in the same way as [`do_free_sized()`](fn.do_free_sized.html), but returns `false` if `ptr` was not
+/// allocated by this allocator, in which case nothing is done, so that it can be passed on to whichever allocator it came
+/// from.
+///
+/// The fast path reads the entry of the page of `ptr` in the page map to check that the page belongs to this allocator,
+/// but not the descriptor of the block. Any other free looks the block up once, in [`try_free()`](fn.try_free.html).
+///
+/// # Safety
+///
+/// If `ptr` was allocated by this allocator, `size` and `align` must be the values it was allocated with, as for
+/// [`do_free_sized()`](fn.do_free_sized.html).
+pub unsafe fn try_free_sized<T: ?Sized>(ptr: *const T, size: usize, align: usize) -> bool {
+    if ptr.is_null() {
+        return true;
+    }
+    match sized_free_class(ptr, size, align) {
+        Some(size_class_index) => {
+            // A page of a superblock is always registered, and no other memory is
+            if get_page_info_for_ptr(ptr).get_desc().is_none() {
+                return false;
             }
+            free_to_cache(ptr as *mut u8, size_class_index, Lifetime::Short);
+            true
+        }
+        None => try_free(ptr),
+    }
+}
+
+/// The size class of the block at `ptr`, worked out from the `size` and `align` it was allocated with, or `None` if the
+/// block has to be looked up in the page map to be freed. None of the checks looks the block up.
+#[inline]
+fn sized_free_class<T: ?Sized>(ptr: *const T, size: usize, align: usize) -> Option<usize> {
+    if !is_power_of_two(align) {
+        return None;
+    }
+    let size = align_size(size, align);
+    // Anything over a page might be a large allocation if it was aligned
+    if size > PAGE || arenas_exist() || ptr_in_bootstrap_range(ptr) {
+        return None;
+    }
+
+    let size_class_index = get_size_class(size);
+    if heaps_segregated(size_class_index) {
+        return None;
+    }
+    // A page with no descriptor is not ours, which try_free_sized checks for
+    debug_assert!(
+        {
+            let info = get_page_info_for_ptr(ptr);
+            info.get_desc().is_none() || info.get_size_class_index() == Some(size_class_index)
+        },
+        "Size {} (align {}) does not match the size class of the block at {:?}",
+        size,
+        align,
+        ptr
+    );
+    Some(size_class_index)
+}
+
+/// Frees every pointer in `ptrs`, skipping NULL pointers.
+///
+/// The blocks are returned straight to their superblocks in the central reserve, without going through the thread cache
+/// or the APF tuner. The blocks of each superblock are linked together, even when they are interleaved with the blocks
+/// of other superblocks, and returned with a single update of the superblock, so freeing a batch takes about one update
+/// per superblock. Large allocations, blocks of an [`Arena`](arena/struct.Arena.html) and memory from the bootstrap
+/// reserve are freed one at a time.
+///
+/// # Safety
+///
+/// Every pointer must have been allocated by this allocator and not been freed, and no pointer may appear twice.
+pub unsafe fn do_free_batch(ptrs: &[*mut u8]) {
+    // The open runs, by descriptor. A run is returned early only when a block of another descriptor maps to its slot.
+    let mut runs: [Option<FreeRun>; FREE_RUN_SLOTS] = [None; FREE_RUN_SLOTS];
+
+    for &ptr in ptrs.iter().filter(|ptr| !ptr.is_null()) {
+        let info = get_page_info_for_ptr(ptr);
+        let (desc, size_class_index) = match (info.get_desc(), info.get_size_class_index()) {
+            (Some(desc), Some(size_class_index)) if size_class_index != 0 && (*desc).arena.is_null() => {
+                (desc, size_class_index)
+            }
+            _ => {
+                do_free(ptr);
+                continue;
+            }
+        };
+        // checked before the block is linked into the run, which would overwrite the link of a free block
+        #[cfg(feature = "debug_checks")]
+        debug_checks::block_freed(ptr);
+        // descriptors are laid out next to each other in their pool, so neighbouring descriptors get different slots
+        let slot = &mut runs[(desc as usize / std::mem::size_of::<Descriptor>()) % FREE_RUN_SLOTS];
+        match slot {
+            Some(run) if run.desc == desc => {
+                *(run.tail as *mut *mut u8) = ptr;
+                run.tail = ptr;
+                run.block_count += 1;
+            }
+            _ => {
+                if let Some(run) = slot.take() {
+                    run.free();
+                }
+                *slot = Some(FreeRun {
+                    desc,
+                    size_class_index,
+                    head: ptr,
+                    tail: ptr,
+                    block_count: 1,
+                });
+            }
+        }
+    }
+    for run in runs.iter_mut().filter_map(Option::take) {
+        run.free();
+    }
+}
+
+/// The number of runs [`do_free_batch()`](fn.do_free_batch.html) keeps open at once
+const FREE_RUN_SLOTS: usize = 64;
+
+/// Blocks in use of a single superblock, linked from `head` to `tail`, that are about to be freed together
+#[derive(Clone, Copy)]
+struct FreeRun {
+    desc: *mut Descriptor,
+    size_class_index: usize,
+    head: *mut u8,
+    tail: *mut u8,
+    block_count: u32,
+}
 
-            // free the super block
-            if let Some(segment) = std::mem::replace(&mut desc.super_block, None) {
-                SEGMENT_ALLOCATOR.deallocate(segment);
+impl FreeRun {
+    unsafe fn free(self) {
+        free_run_to_central(&mut *self.desc, self.head, self.tail, self.block_count, self.size_class_index);
+    }
+}
+
+/// Returns the `block_count` blocks in use linked from `head` to `tail` straight to their superblock
+unsafe fn free_run_to_central(
+    desc: &'static mut Descriptor,
+    head: *mut u8,
+    tail: *mut u8,
+    block_count: u32,
+    size_class_index: usize,
+) {
+    let _guard = heap_guard();
+    tags::give_back_blocks(tags::tag_of(desc.proc_heap), desc.block_size as usize, block_count as usize);
+    return_blocks(desc, head, tail, block_count, size_class_index);
+}
+
+/// Whether a block of the size class may belong to the heap of a tag or lifetime other than the default one, or the
+/// calling thread caches the blocks of a tag other than 0, in which case the thread cache a block is freed to depends
+/// on its superblock
+#[inline]
+fn heaps_segregated(size_class_index: usize) -> bool {
+    alloc::class_segregated(size_class_index) || tags::alloc_tag() != 0
+}
+
+/// Returns every block in the thread cache of the calling thread to the central reserve, where other threads can use
+/// them, including the blocks it set aside for the [tags](tags/index.html) it used before. A few superblocks whose
+/// blocks are then all free are kept to be reused, and can be released with [`purge()`](fn.purge.html).
+pub fn thread_cache_flush() {
+    let _cache_guard = cache_guard();
+    tags::flush_live();
+    unsafe {
+        thread_cache::for_each_own_cache(|bins| {
+            for (size_class_index, cache) in bins.iter_mut().enumerate().skip(1) {
+                flush_cache(size_class_index, cache);
             }
+        });
+    }
+}
 
-            // retire the descriptor
-            desc.retire();
+/// Gives every superblock in the central reserve whose blocks are all free back to the OS, and returns the number of
+/// bytes that were released.
+///
+/// Blocks held in thread caches are not free, so a superblock can only be released once every thread holding its blocks
+/// has flushed them, for example with [`thread_cache_flush()`](fn.thread_cache_flush.html). While this runs, other
+/// threads may map new superblocks instead of using the ones in the central reserve.
+pub fn purge() -> usize {
+    MALLOC_INIT_S.with(|| unsafe { init_malloc() });
+
+    let _guard = heap_guard();
+    let mut released = 0;
+    for lifetime in Lifetime::ALL {
+        for size_class_index in 1..MAX_SZ_IDX {
+            released += release_empty_superblocks(global_heaps().get_heap_for(lifetime, size_class_index));
         }
-        Some(size_class_index) => {
-            /*
-            let force_bootstrap = bootstrap_reserve.lock().ptr_in_bootstrap(ptr)
-                || use_bootstrap()
-                || std::thread::panicking()
-                || (!cfg!(unix) && thread_cache::thread_init.try_with(|_| {}).is_err());
-             */
-            let force_bootstrap = false;
-            #[cfg(feature = "track_allocation")]
-            crate::info_dump::log_free(get_allocation_size(ptr as *const c_void).unwrap() as usize);
-            #[cfg(feature = "show_all_allocations")]
-            dump_info!();
+    }
+    tags::for_each_tag_heap(|heap| released += release_empty_superblocks(heap));
+    released
+}
 
-            if force_bootstrap {
-            } else {
-                /*
-                #[cfg(not(unix))]
-                {
-                    set_use_bootstrap(true);
-                    thread_cache::thread_init.with(|mut val| {
-                        if !*val.borrow() {
-                            thread_cache::thread_cache.with(|tcache| {
-                                let _tcache = tcache;
-                            });
-                            *val.borrow_mut() = true;
-                        }
-                        set_use_bootstrap(false)
+/// Gives as much free memory back to the OS as possible, and returns the number of bytes that were released. This is
+/// what `malloc_trim` does.
+///
+/// The thread cache of the calling thread is flushed, and every superblock whose blocks are then all free is released
+/// as it is by [`purge()`](fn.purge.html). The pages of the rest of the superblocks in the central reserve that only
+/// hold free blocks are given back to the OS without unmapping them, and so are the pages of the bootstrap reserve past
+/// the first `pad` bytes that have not been handed out yet. Only the pages that were resident are counted.
+///
+/// Arenas and instances other than the global one are not trimmed.
+pub fn trim(pad: usize) -> usize {
+    thread_cache_flush();
+    let mut released = purge();
+
+    let _guard = heap_guard();
+    for lifetime in Lifetime::ALL {
+        for size_class_index in 1..MAX_SZ_IDX {
+            released += trim_partial_superblocks(global_heaps().get_heap_for(lifetime, size_class_index));
+        }
+    }
+    tags::for_each_tag_heap(|heap| released += trim_partial_superblocks(heap));
+    released + unsafe { (*std::ptr::addr_of!(bootstrap_reserve)).lock().trim(pad) }
+}
+
+/// Gives the segment of a large allocation back to the OS
+pub(crate) unsafe fn free_large(desc: &'static mut Descriptor) {
+    free_large_in(heap::global_core(), desc)
+}
+
+/// Gives the segment of a large allocation of the instance `core` back to the OS
+pub(crate) unsafe fn free_large_in(core: &HeapCore, desc: &'static mut Descriptor) {
+    let _guard = heap_guard();
+    let super_block = desc.super_block.as_ref().unwrap();
+    // unregister
+    core.page_map.unregister(None, super_block);
+
+    // free the super block
+    if let Some(segment) = std::mem::replace(&mut desc.super_block, None) {
+        if core.is_global() {
+            stats::large_unmapped(segment.len());
+        }
+        tags::give_back(tags::tag_of(desc.proc_heap), segment.len());
+        SEGMENT_ALLOCATOR.deallocate(segment);
+    }
+
+    // retire the descriptor
+    core.descriptors.retire(desc);
+}
+
+/// Returns a block straight to its superblock in the central reserve, without going through the thread cache
+pub(crate) unsafe fn free_to_central(ptr: *mut u8, desc: &'static mut Descriptor, size_class_index: usize) {
+    #[cfg(feature = "debug_checks")]
+    debug_checks::block_freed(ptr);
+    let _guard = heap_guard();
+    tags::give_back(tags::tag_of(desc.proc_heap), desc.block_size as usize);
+    return_blocks(desc, ptr, ptr, 1, size_class_index);
+}
+
+/// Returns a block to the thread cache of its size class, in the cache of the lifetime of its superblock
+unsafe fn free_to_cache(ptr: *mut u8, size_class_index: usize, lifetime: Lifetime) {
+    /*
+    let force_bootstrap = bootstrap_reserve.lock().ptr_in_bootstrap(ptr)
+        || use_bootstrap()
+        || std::thread::panicking()
+        || (!cfg!(unix) && thread_cache::thread_init.try_with(|_| {}).is_err());
+     */
+    let force_bootstrap = false;
+    #[cfg(feature = "debug_checks")]
+    debug_checks::block_freed(ptr);
+    #[cfg(feature = "track_allocation")]
+    crate::info_dump::log_free(usable_size(ptr).unwrap());
+    #[cfg(feature = "show_all_allocations")]
+    dump_info!();
+
+    if force_bootstrap {
+    } else {
+        /*
+        #[cfg(not(unix))]
+        {
+            set_use_bootstrap(true);
+            thread_cache::thread_init.with(|mut val| {
+                if !*val.borrow() {
+                    thread_cache::thread_cache.with(|tcache| {
+                        let _tcache = tcache;
                     });
+                    *val.borrow_mut() = true;
                 }
+                set_use_bootstrap(false)
+            });
+        }
 
-                 */
+         */
 
-                /* WARNING -- ELIAS CODE -- WARNING */
-
-                // Should always be initialized at this point
-                if USE_APF
-                    && thread_cache::apf_init
-                        .try_with(|init| *init.borrow())
-                        .unwrap_or(false)
-                {
-                    let _r1 = thread_cache::skip_tuners
-                        .try_with(|b| {
-                            if *b.get() == 0 {
-                                let _r2 = thread_cache::apf_tuners.try_with(|tuners| {
-                                    (*tuners.get())
-                                        .get_mut(size_class_index)
-                                        .unwrap()
-                                        .free(ptr as *mut u8);
-                                });
-                            }
-                        })
-                        .unwrap();
-                }
+        let _cache_guard = cache_guard();
+
+        /* WARNING -- ELIAS CODE -- WARNING */
 
-                /* END ELIAS CODE */
-                thread_cache::thread_cache
-                    .try_with(|tcache| {
-                        let cache = (*tcache.get()).get_mut(size_class_index).unwrap();
+        // Should always be initialized at this point
+        if USE_APF
+            && lifetime == Lifetime::Short
+            && thread_cache::apf_init
+                .try_with(|init| *init.borrow())
+                .unwrap_or(false)
+        {
+            let _r1 = thread_cache::skip_tuners
+                .try_with(|b| {
+                    if *b.get() == 0 {
+                        let _r2 = thread_cache::apf_tuners.try_with(|tuners| {
+                            (&mut *tuners.get())
+                                .get_mut(size_class_index)
+                                .unwrap()
+                                .free(ptr);
+                        });
+                    }
+                })
+                .unwrap();
+        }
 
-                        /*
-                        if sc.block_num == 0 {
-                            unsafe {
-                                let mut guard = bootstrap_cache.lock();
-                                let cache = guard.get_mut(size_class_index).unwrap();
+        /* END ELIAS CODE */
+        thread_cache::cache_for(lifetime)
+            .try_with(|tcache| {
+                let cache = (*tcache.get()).get_mut(size_class_index).unwrap();
 
+                /*
+                if sc.block_num == 0 {
+                    unsafe {
+                        let mut guard = bootstrap_cache.lock();
+                        let cache = guard.get_mut(size_class_index).unwrap();
 
-                                if cache.get_block_num() >= sc.cache_block_num {
-                                    flush_cache(size_class_index, cache);
-                                }
 
-                                return cache.push_block(ptr as *mut u8);
-                            }
+                        if cache.get_block_num() >= sc.cache_block_num {
+                            flush_cache(size_class_index, cache);
                         }
 
-                         */
-                        if !USE_APF {
-                            let sc = &SIZE_CLASSES[size_class_index];
-                            if cache.get_block_num() >= sc.cache_block_num {
-                                flush_cache(size_class_index, cache);
-                            }
-                        }
+                        return cache.push_block(ptr as *mut u8);
+                    }
+                }
 
-                        cache.push_block(ptr as *mut u8)
-                    })
-                    .expect("Freeing to cache failed");
-            }
-        }
+                 */
+                if !USE_APF || lifetime == Lifetime::Long {
+                    let sc = &SIZE_CLASSES[size_class_index];
+                    if cache.get_block_num() >= sc.cache_block_num {
+                        flush_cache(size_class_index, cache);
+                    }
+                }
+                if cache.get_block_num() == 0 {
+                    thread_cache::register_cache();
+                }
+
+                cache.push_block(ptr)
+            })
+            .expect("Freeing to cache failed");
     }
 }
 
@@ -581,7 +1449,9 @@ mod tests {
 
     use bitfield::size_of;
 
-    use crate::allocation_data::get_heaps;
+    use crate::alloc::MAX_EMPTY_SUPERBLOCKS;
+    use crate::allocation_data::global_heaps;
+    use crate::pages::external_mem_reservation::AllocationError;
     use crate::ptr::auto_ptr::AutoPtr;
     use crate::size_classes::SIZE_CLASSES;
 
@@ -589,8 +1459,8 @@ mod tests {
 
     #[test]
     fn heaps_valid() {
-        let heap = get_heaps();
-        let _p_heap = heap.get_heap_at_mut(0);
+        let heap = global_heaps();
+        let _p_heap = heap.get_heap_at(0);
     }
 
     #[test]
@@ -650,8 +1520,7 @@ mod tests {
         let v = do_malloc(0);
         assert_ne!(v, null_mut());
         assert_eq!(
-            get_allocation_size(v as *const c_void)
-                .expect("Zero Sized Allocation should act as an 8 byte allocation"),
+            usable_size(v).expect("Zero Sized Allocation should act as an 8 byte allocation"),
             8
         );
         unsafe {
@@ -796,6 +1665,420 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn free_sized_reuses_block() {
+        for &(size, align) in &[(0, 1), (8, 1), (100, 8), (24, 64), (PAGE, 1), (MAX_SZ, 1)] {
+            let ptr = do_aligned_alloc(align, size);
+            assert!(!ptr.is_null());
+            unsafe {
+                do_free_sized(ptr, size, align);
+            }
+            if size <= PAGE {
+                assert_eq!(do_aligned_alloc(align, size), ptr, "The block should be at the top of the thread cache");
+            }
+        }
+    }
+
+    #[test]
+    fn zeroed_allocations() {
+        unsafe {
+            let ptr = do_malloc(64);
+            ptr.write_bytes(0xff, 64);
+            do_free(ptr);
+            let reused = do_malloc_zeroed(64);
+            assert_eq!(reused, ptr);
+            assert!((0..64).all(|i| *reused.add(i) == 0));
+            do_free(reused);
+
+            let blocks = (0..64).map(|_| do_malloc_zeroed(200)).collect::<Vec<_>>();
+            for &block in &blocks {
+                assert!((0..200).all(|i| *block.add(i) == 0));
+            }
+            for block in blocks {
+                do_free(block);
+            }
+
+            let large = do_aligned_alloc_zeroed(PAGE * 2, MAX_SZ * 2);
+            assert_eq!(large as usize % (PAGE * 2), 0);
+            assert!((0..MAX_SZ * 2).all(|i| *large.add(i) == 0));
+            do_free(large);
+        }
+    }
+
+    #[test]
+    fn allocation_size_over_u32() {
+        let size = (u32::MAX as usize) + PAGE;
+        let ptr = do_malloc(size);
+        assert!(!ptr.is_null());
+        assert!(usable_size(ptr).unwrap() >= size);
+        #[allow(deprecated)]
+        let allocation_size = get_allocation_size(ptr as *const c_void);
+        assert_eq!(allocation_size, Err(()), "The size can't be given as a u32");
+        unsafe { do_free(ptr) };
+    }
+
+    #[test]
+    fn realloc_large_remaps() {
+        unsafe {
+            let ptr = do_malloc(MAX_SZ * 2) as *mut usize;
+            *ptr = 0xdeadbeaf;
+
+            let grown = do_realloc(ptr as *mut c_void, 1 << 26) as *mut usize;
+            assert_eq!(*grown, 0xdeadbeaf);
+            *grown.add((1 << 26) / std::mem::size_of::<usize>() - 1) = 1;
+            assert_eq!(usable_size(grown), Some(1 << 26));
+            #[allow(deprecated)]
+            let allocation_size = get_allocation_size(grown as *const c_void);
+            assert_eq!(allocation_size, Ok(1 << 26));
+
+            let shrunk = do_realloc(grown as *mut c_void, MAX_SZ * 4) as *mut usize;
+            assert_eq!(shrunk, grown, "Shrinking should not move the allocation");
+            assert_eq!(usable_size(shrunk), Some(MAX_SZ * 4));
+            assert_eq!(*shrunk, 0xdeadbeaf);
+
+            let kept = do_realloc(shrunk as *mut c_void, PAGE * 2) as *mut usize;
+            assert_eq!(kept, shrunk);
+            assert_eq!(usable_size(kept), Some(page_ceiling!(MAX_SZ + 1)));
+            do_free(kept);
+        }
+    }
+
+    #[test]
+    fn free_sized_large_aligned() {
+        let align = PAGE * 4;
+        let ptr = do_aligned_alloc(align, PAGE * 2);
+        assert_eq!(ptr as usize % align, 0);
+        unsafe {
+            do_free_sized(ptr, PAGE * 2, align);
+        }
+        assert!(
+            get_page_info_for_ptr(ptr).get_desc().is_none(),
+            "The aligned page should be unregistered"
+        );
+    }
+
+    #[test]
+    fn flush_and_purge() {
+        thread::spawn(|| {
+            let ptrs: Vec<*mut u8> = (0..256).map(|_| do_malloc(9000)).collect();
+            for &ptr in &ptrs {
+                unsafe { do_free(ptr) };
+            }
+            thread_cache_flush();
+            thread_cache::thread_cache.with(|tcache| {
+                assert!(unsafe { &*tcache.get() }.iter().all(|bin| bin.get_block_num() == 0));
+            });
+            assert!(purge() > 0, "The superblocks of the freed blocks should be released");
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn flush_releases_superblocks_over_the_limit() {
+        thread::spawn(|| {
+            unsafe { do_free(do_malloc(1400)) };
+            let size_class_index = get_size_class(1400);
+            let released = || stats::heap_stats().size_classes[size_class_index].released_superblocks;
+            let before = released();
+            let count = (MAX_EMPTY_SUPERBLOCKS + 2) * unsafe { SIZE_CLASSES[size_class_index].get_block_num() };
+            let ptrs: Vec<*mut u8> = (0..count).map(|_| do_malloc(1400)).collect();
+            for &ptr in &ptrs {
+                unsafe { do_free(ptr) };
+            }
+            thread_cache_flush();
+            assert!(
+                released() > before,
+                "The superblocks over the limit should be released without a purge"
+            );
+            let heap = global_heaps().get_heap_for(Lifetime::Short, size_class_index);
+            assert!(heap.empty_superblocks.load(Ordering::Acquire) <= MAX_EMPTY_SUPERBLOCKS as isize);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn flushed_blocks_handed_out_once() {
+        let threads: Vec<_> = (0..4usize)
+            .map(|thread| {
+                thread::spawn(move || {
+                    let mut live: Vec<(*mut usize, usize)> = Vec::new();
+                    for i in 0..20_000usize {
+                        let tag = thread << 32 | i;
+                        if i % 3 == 2 {
+                            let (ptr, expected) = live.swap_remove(i % live.len());
+                            assert_eq!(unsafe { *ptr }, expected, "Block was handed out twice");
+                            unsafe { do_free(ptr) };
+                        }
+                        let ptr = do_malloc(24 + i % 4 * 8) as *mut usize;
+                        unsafe { ptr.write(tag) };
+                        live.push((ptr, tag));
+                        if i % 1000 == 999 {
+                            thread_cache_flush();
+                        }
+                    }
+                    for (ptr, expected) in live {
+                        assert_eq!(unsafe { *ptr }, expected, "Block was handed out twice");
+                        unsafe { do_free(ptr) };
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        purge();
+    }
+
+    #[test]
+    fn try_alloc_errors() {
+        assert!(matches!(try_aligned_alloc(3, 8), Err(MallocError::InvalidAlignment(3))));
+        assert!(matches!(try_malloc(usize::MAX), Err(MallocError::SizeOverflow(usize::MAX))));
+        assert!(matches!(
+            try_aligned_alloc(PAGE * 2, isize::MAX as usize - PAGE),
+            Err(MallocError::SizeOverflow(_))
+        ));
+        match try_malloc(1 << 62) {
+            Err(MallocError::MapFailed(AllocationError::AllocationFailed(size, errno))) => {
+                assert_eq!(size, 1 << 62);
+                assert_eq!(errno.0, libc::ENOMEM);
+            }
+            other => panic!("Expected the mapping to fail, got {:?}", other),
+        }
+        assert!(do_malloc(1 << 62).is_null());
+        assert!(do_aligned_alloc(3, 8).is_null());
+    }
+
+    #[test]
+    fn usable_size_for_matches_allocation() {
+        for &(size, align) in &[
+            (1, 1),
+            (100, 8),
+            (PAGE, 64),
+            (PAGE + 1, 8),
+            (MAX_SZ * 3, 16),
+            (PAGE, PAGE * 4),
+        ] {
+            let expected = usable_size_for(size, align).unwrap();
+            assert!(expected >= size);
+            let ptr = do_aligned_alloc(align, size);
+            let base = match get_page_info_for_ptr(ptr).get_desc() {
+                Some(desc) if align > PAGE => unsafe { &*desc }.super_block.as_ref().unwrap().get_ptr() as usize,
+                _ => ptr as usize,
+            };
+            assert_eq!(sallocx(ptr), Some(expected - (ptr as usize - base)), "size {} align {}", size, align);
+            unsafe { do_free(ptr) };
+        }
+        assert_eq!(usable_size_for(8, 3), None);
+        assert_eq!(usable_size_for(usize::MAX, 1), None);
+    }
+
+    #[test]
+    fn find_interior_pointers() {
+        let small = do_malloc(100);
+        let (base, usable, size_class_index) = find_allocation(small as usize + 99).unwrap();
+        assert_eq!((base, usable), (small, usable_size(small).unwrap()));
+        assert_eq!(size_class_index, get_size_class(100));
+        for &size in &[24, 48, 1000, 3000, MAX_SZ] {
+            let blocks: Vec<*mut u8> = (0..64).map(|_| do_malloc(size)).collect();
+            for &block in &blocks {
+                assert_eq!(find_allocation(block as usize + size - 1).unwrap().0, block);
+                unsafe { do_free(block) };
+            }
+        }
+
+        let large = do_malloc(MAX_SZ * 10);
+        assert_eq!(
+            find_allocation(large as usize + MAX_SZ * 9),
+            Some((large, usable_size(large).unwrap(), 0))
+        );
+        // every page of a large allocation is registered again after it is remapped
+        let large = unsafe { do_realloc(large as *mut c_void, MAX_SZ * 40) } as *mut u8;
+        assert_eq!(
+            find_allocation(large as usize + MAX_SZ * 39),
+            Some((large, usable_size(large).unwrap(), 0))
+        );
+
+        let align = PAGE * 8;
+        let aligned = do_aligned_alloc(align, PAGE * 3);
+        assert_eq!(
+            find_allocation(aligned as usize + PAGE * 2 + 5),
+            Some((aligned, usable_size(aligned).unwrap(), 0))
+        );
+        let segment = get_page_info_for_ptr(aligned).get_desc().unwrap();
+        let start = unsafe { &*segment }.super_block.as_ref().unwrap().get_ptr() as usize;
+        if start != aligned as usize {
+            assert_eq!(find_allocation(start), None, "The padding is not part of the allocation");
+        }
+
+        let on_stack = 0usize;
+        assert_eq!(find_allocation(&on_stack as *const usize as usize), None);
+        unsafe {
+            do_free(small);
+            do_free(large);
+            do_free(aligned);
+        }
+    }
+
+    #[test]
+    fn failed_realloc_keeps_allocation() {
+        unsafe {
+            let ptr = try_malloc(64).unwrap().as_ptr() as *mut usize;
+            ptr.write(0xdeadbeaf);
+            assert!(try_realloc(ptr as *mut u8, 1 << 62).is_err());
+            assert!(do_realloc(ptr as *mut c_void, 1 << 62).is_null());
+            assert_eq!(*ptr, 0xdeadbeaf);
+
+            let grown = try_realloc(ptr as *mut u8, MAX_SZ * 2).unwrap().as_ptr() as *mut usize;
+            assert_eq!(*grown, 0xdeadbeaf);
+            do_free(grown);
+        }
+    }
+
+    #[test]
+    fn typed_allocation() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        struct Counted(u64);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(self.0 as usize, Ordering::Relaxed);
+            }
+        }
+        #[repr(align(64))]
+        struct Marker;
+
+        unsafe {
+            let array = allocate_array::<Counted>(10);
+            for i in 0..10 {
+                array.add(i).write(Counted(1));
+            }
+            deallocate_array(array, 10);
+            assert_eq!(DROPPED.load(Ordering::Relaxed), 10);
+            deallocate_val(allocate_val(Counted(100)));
+            assert_eq!(DROPPED.load(Ordering::Relaxed), 110);
+
+            let dirty = allocate_val([0xffu8; 72]);
+            do_free(dirty);
+            let zeroed = allocate_zeroed::<[u8; 72]>();
+            assert_eq!(*zeroed, [0; 72]);
+            do_free(zeroed);
+
+            assert!(allocate_array::<u64>(usize::MAX / 4).is_null());
+            assert!(matches!(
+                try_allocate_array::<u64>(usize::MAX / 4),
+                Err(MallocError::SizeOverflow(usize::MAX))
+            ));
+
+            // zero-sized types and empty arrays are never given a block
+            assert_eq!(try_allocate_val(Marker).unwrap(), NonNull::dangling());
+            assert_eq!(allocate_array::<u64>(0), NonNull::dangling().as_ptr());
+            let counted = allocate_array::<Counted>(0);
+            deallocate_array(counted, 0);
+            let markers = allocate_array::<Marker>(1 << 40);
+            assert_eq!(markers as usize % 64, 0);
+            deallocate_array(markers, 1 << 40);
+            do_free(allocate_type::<()>());
+        }
+    }
+
+    #[test]
+    fn batch_allocation() {
+        thread::spawn(|| unsafe {
+            // sets up the thread cache and tuners before the tag is set, so only the batch is charged to it
+            do_free(do_malloc(8));
+            tags::set_alloc_tag(19).unwrap();
+
+            let mut nodes = [null_mut(); 3000];
+            assert_eq!(do_malloc_batch(48, &mut nodes), nodes.len());
+            let mut sorted = nodes;
+            sorted.sort_unstable();
+            assert!(sorted.windows(2).all(|pair| pair[0] != pair[1]), "Every block is handed out once");
+            for (index, &node) in nodes.iter().enumerate() {
+                assert!(usable_size(node).unwrap() >= 48);
+                (node as *mut usize).write(index);
+            }
+            assert!(nodes.iter().enumerate().all(|(index, &node)| *(node as *mut usize) == index));
+            assert_eq!(stats::tag_stats(19).live_allocations, nodes.len());
+
+            let mut large = [null_mut(); 2];
+            assert_eq!(do_malloc_batch(MAX_SZ * 2, &mut large), 2);
+            assert_eq!(stats::tag_stats(19).live_allocations, nodes.len() + 2);
+
+            // interleaves the blocks of the superblocks, which are still grouped by their descriptor
+            let (evens, odds): (Vec<_>, Vec<_>) = nodes.iter().enumerate().partition(|(index, _)| index % 2 == 0);
+            let shuffled: Vec<*mut u8> = odds.iter().rev().chain(evens.iter()).map(|&(_, &node)| node).collect();
+            do_free_batch(&shuffled);
+            do_free_batch(&[large[0], null_mut(), large[1]]);
+            assert_eq!(stats::tag_stats(19), stats::TagStats::default());
+            tags::set_alloc_tag(0).unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn batch_allocation_after_thread_cache_is_gone() {
+        static ALLOCATED: AtomicUsize = AtomicUsize::new(usize::MAX);
+        struct AllocatesOnDrop;
+        impl Drop for AllocatesOnDrop {
+            fn drop(&mut self) {
+                let mut nodes = [null_mut(); 8];
+                let count = do_malloc_batch(48, &mut nodes);
+                unsafe { do_free_batch(&nodes[..count]) };
+                ALLOCATED.store(count, Ordering::Relaxed);
+            }
+        }
+        thread_local! {
+            static GUARD: AllocatesOnDrop = const { AllocatesOnDrop };
+        }
+
+        thread::spawn(|| {
+            // registered before the thread locals of the allocator, so its destructor runs after theirs
+            GUARD.with(|_| ());
+            let mut nodes = [null_mut(); 8];
+            assert_eq!(do_malloc_batch(48, &mut nodes), nodes.len());
+            unsafe { do_free_batch(&nodes) };
+        })
+        .join()
+        .unwrap();
+        assert_ne!(ALLOCATED.load(Ordering::Relaxed), usize::MAX, "The destructor should have run");
+    }
+
+    #[test]
+    fn trim_releases_free_pages() {
+        thread::spawn(|| unsafe {
+            // blocks bigger than a page have pages without a link in them, even with the `no_met_stack` feature
+            for &size in [100, MAX_SZ - 2000].iter() {
+                do_free(do_malloc(size));
+                let size_class_index = get_size_class(size);
+                let count = SIZE_CLASSES[size_class_index].block_num as usize * 2;
+                let blocks: Vec<*mut u8> = (0..count).map(|_| do_malloc(size)).collect();
+                blocks.iter().for_each(|&block| block.write_bytes(1, size));
+                let (kept, freed): (Vec<_>, Vec<_>) = blocks.iter().enumerate().partition(|(index, _)| index % 64 == 0);
+                freed.iter().for_each(|&(_, &block)| do_free(block));
+                thread_cache_flush();
+
+                // Only this size class is trimmed, so that the superblocks of other tests are left alone
+                let heap = global_heaps().get_heap_for(Lifetime::Short, size_class_index);
+                let released = trim_partial_superblocks(heap);
+                assert!(released > 0 || (size < PAGE && cfg!(feature = "no_met_stack")));
+                assert!(kept.iter().all(|&(_, &block)| (0..size).all(|offset| *block.add(offset) == 1)));
+
+                let again: Vec<*mut u8> = (0..freed.len()).map(|_| do_malloc(size)).collect();
+                again.iter().for_each(|&block| block.write_bytes(2, size));
+                let mut sorted = again.clone();
+                sorted.sort_unstable();
+                assert!(sorted.windows(2).all(|pair| pair[0] != pair[1]), "The free blocks are linked again");
+                assert!(kept.iter().all(|&(_, &block)| (0..size).all(|offset| *block.add(offset) == 1)));
+                do_free_batch(&again);
+                kept.iter().for_each(|&(_, &block)| do_free(block));
+            }
+        })
+        .join()
+        .unwrap();
+    }
 }
 
 
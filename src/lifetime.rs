@@ -0,0 +1,140 @@
+//! Lifetime hints, which keep allocations that are expected to live for a long time out of the superblocks of
+//! short-lived ones.
+//!
+//! A single long-lived block keeps its superblock from ever becoming EMPTY, so after a spike of short-lived
+//! allocations, a few long-lived blocks scattered through the superblocks of the spike keep all of them mapped.
+//! Long-lived allocations are given their own heaps and thread cache bins, so the superblocks of short-lived
+//! allocations drain to EMPTY once the spike is over, and can be released by [`purge()`](../fn.purge.html).
+//!
+//! An allocation is long-lived if it is made inside of a [`LifetimeScope`](struct.LifetimeScope.html) for
+//! [`Lifetime::Long`](enum.Lifetime.html#variant.Long), or with the
+//! [`MALLOCX_LONG_LIVED`](../mallocx/constant.MALLOCX_LONG_LIVED.html) flag. Large allocations have their own segment
+//! either way, and allocations from an [`Arena`](../arena/struct.Arena.html) ignore the hint.
+//!
+//! # Example
+//! ```
+//! use apfmalloc_lib::lifetime::{lifetime_scope, Lifetime};
+//! use apfmalloc_lib::{do_free, do_malloc};
+//!
+//! let config = {
+//!     let _scope = lifetime_scope(Lifetime::Long);
+//!     do_malloc(200)
+//! };
+//! // not in the same superblock as `config`
+//! let scratch = do_malloc(200);
+//! unsafe {
+//!     do_free(scratch);
+//!     do_free(config);
+//! }
+//! ```
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+
+/// The number of lifetimes, which each have their own heaps
+pub(crate) const LIFETIMES: usize = 2;
+
+/// How long an allocation is expected to live
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Lifetime {
+    /// Freed soon after it is allocated, which is what allocations are expected to be unless told otherwise
+    #[default]
+    Short,
+    /// Kept for a long time, or for as long as the program runs
+    Long,
+}
+
+impl Lifetime {
+    /// Every lifetime, in the order their heaps are laid out
+    pub(crate) const ALL: [Lifetime; LIFETIMES] = [Lifetime::Short, Lifetime::Long];
+}
+
+thread_local! {
+    static current_lifetime: Cell<Lifetime> = const { Cell::new(Lifetime::Short) };
+}
+
+/// Sets the lifetime of the allocations made by the thread that created it, until it is dropped, after which the
+/// lifetime that was set before is used again. Created with [`lifetime_scope()`](fn.lifetime_scope.html).
+#[must_use = "the lifetime is only set until the scope is dropped"]
+pub struct LifetimeScope {
+    previous: Lifetime,
+    /// The scope sets the lifetime of the thread that created it, so it can't be moved to another thread
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for LifetimeScope {
+    fn drop(&mut self) {
+        set_lifetime(self.previous);
+    }
+}
+
+/// Makes the allocations of the calling thread have `lifetime` until the returned scope is dropped
+pub fn lifetime_scope(lifetime: Lifetime) -> LifetimeScope {
+    let previous = current();
+    set_lifetime(lifetime);
+    LifetimeScope {
+        previous,
+        _not_send: PhantomData,
+    }
+}
+
+fn set_lifetime(lifetime: Lifetime) {
+    let _ = current_lifetime.try_with(|current| current.set(lifetime));
+}
+
+/// The lifetime of the allocations made by the calling thread
+#[inline]
+pub fn current() -> Lifetime {
+    current_lifetime.try_with(|current| current.get()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::alloc::{get_page_info_for_ptr, release_empty_superblocks};
+    use crate::allocation_data::global_heaps;
+    use crate::mallocx::{do_mallocx, MALLOCX_LONG_LIVED};
+    use crate::size_classes::get_size_class;
+    use crate::stats::heap_stats;
+    use crate::{do_free, do_malloc, thread_cache_flush};
+
+    fn lifetime_of(ptr: *mut u8) -> Lifetime {
+        let desc = get_page_info_for_ptr(ptr).get_desc().unwrap();
+        unsafe { (*(*desc).proc_heap).lifetime }
+    }
+
+    #[test]
+    fn long_lived_blocks_do_not_pin_short_lived_superblocks() {
+        std::thread::spawn(|| unsafe {
+            let mut short = Vec::with_capacity(1000);
+            let mut long = Vec::with_capacity(100);
+            for i in 0..1000 {
+                if i % 10 == 0 {
+                    let _scope = lifetime_scope(Lifetime::Long);
+                    long.push(do_malloc(3000));
+                }
+                short.push(do_malloc(3000));
+            }
+            long.push(do_mallocx(3000, MALLOCX_LONG_LIVED));
+            let size_class_index = get_size_class(3000);
+            assert_eq!(current(), Lifetime::Short, "The scope is over");
+            assert!(long.iter().all(|&ptr| lifetime_of(ptr) == Lifetime::Long));
+            assert!(short.iter().all(|&ptr| lifetime_of(ptr) == Lifetime::Short));
+            assert!(heap_stats().size_classes[size_class_index].long_lived_superblocks > 0);
+
+            let released = heap_stats().size_classes[size_class_index].released_superblocks;
+            short.into_iter().for_each(|ptr| do_free(ptr));
+            thread_cache_flush();
+            // Only this size class is purged, so that the superblocks other tests expect to purge are left alone
+            let heap = global_heaps().get_heap_for(Lifetime::Short, size_class_index);
+            release_empty_superblocks(heap);
+            assert!(
+                heap_stats().size_classes[size_class_index].released_superblocks > released,
+                "The short-lived superblocks are all free once the short-lived blocks are"
+            );
+            long.into_iter().for_each(|ptr| do_free(ptr));
+        })
+        .join()
+        .unwrap();
+    }
+}
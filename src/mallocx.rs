@@ -0,0 +1,274 @@
+//! Allocation functions that take their options as flags, in the style of jemalloc's `mallocx`.
+//!
+//! The flags are a bitwise or of:
+//! - [`mallocx_lg_align(lg_align)`](fn.mallocx_lg_align.html), to align the allocation to `1 << lg_align` bytes
+//! - [`MALLOCX_ZERO`](constant.MALLOCX_ZERO.html), to set the memory to zero
+//! - [`MALLOCX_TCACHE_NONE`](constant.MALLOCX_TCACHE_NONE.html), to take blocks from and give blocks back to the
+//!   central reserve instead of the thread cache
+//! - [`mallocx_arena(index)`](fn.mallocx_arena.html), to allocate from the [`Arena`](../arena/struct.Arena.html) with
+//!   that index
+//! - [`MALLOCX_LONG_LIVED`](constant.MALLOCX_LONG_LIVED.html), to keep the allocation out of the superblocks of
+//!   short-lived allocations
+//!
+//! The values of the flags are the same as jemalloc's, so C code written for `mallocx` can use them as is.
+//! `MALLOCX_LONG_LIVED` is not in jemalloc, and uses a bit jemalloc leaves unused.
+//!
+//! # Example
+//! ```
+//! use apfmalloc_lib::mallocx::{do_dallocx, do_mallocx, mallocx_lg_align, MALLOCX_ZERO};
+//!
+//! let ptr = do_mallocx(100, mallocx_lg_align(6) | MALLOCX_ZERO);
+//! assert_eq!(ptr as usize % 64, 0);
+//! assert!((0..100).all(|i| unsafe { *ptr.add(i) } == 0));
+//! unsafe { do_dallocx(ptr, 0) };
+//! ```
+
+use std::ffi::c_void;
+use std::ptr::{null_mut, NonNull};
+
+use crate::alloc::get_page_info_for_ptr;
+use crate::arena::{arena_at, arena_of, ArenaHeap, PinnedArena};
+use crate::bootstrap::ptr_in_bootstrap_reserve;
+use crate::lifetime::{lifetime_scope, Lifetime};
+use crate::mem_info::PAGE;
+use crate::{
+    aligned_alloc_with, do_free, fits_in_place, free_to_central, remap_large, usable_size, usable_size_for,
+    zero_allocation, MallocError, ZeroState,
+};
+
+/// Sets every byte of the allocation to zero. When resizing, only the bytes past the old usable size are set to zero.
+pub const MALLOCX_ZERO: i32 = 0x40;
+/// Skips the thread cache, so that blocks are taken from and returned to the central reserve
+pub const MALLOCX_TCACHE_NONE: i32 = 0x100;
+/// Hints that the allocation will live for a long time, so it is given a block from the
+/// [long-lived heaps](../lifetime/index.html)
+pub const MALLOCX_LONG_LIVED: i32 = 0x80;
+
+const LG_ALIGN_MASK: i32 = 0x3f;
+const ARENA_SHIFT: u32 = 20;
+
+/// The flag to align an allocation to `1 << lg_align` bytes
+pub const fn mallocx_lg_align(lg_align: u32) -> i32 {
+    lg_align as i32 & LG_ALIGN_MASK
+}
+
+/// The flag to allocate from the arena with the index `index`, as given by
+/// [`Arena::index()`](../arena/struct.Arena.html#method.index)
+pub const fn mallocx_arena(index: usize) -> i32 {
+    ((index + 1) << ARENA_SHIFT) as i32
+}
+
+/// The options of a set of flags
+struct Flags {
+    align: usize,
+    zero: bool,
+    use_cache: bool,
+    long_lived: bool,
+    arena: Option<usize>,
+}
+
+impl Flags {
+    fn new(flags: i32) -> Self {
+        let arena = (flags as u32 >> ARENA_SHIFT) as usize;
+        Flags {
+            align: 1 << (flags & LG_ALIGN_MASK),
+            zero: flags & MALLOCX_ZERO != 0,
+            use_cache: flags & MALLOCX_TCACHE_NONE == 0,
+            long_lived: flags & MALLOCX_LONG_LIVED != 0,
+            arena: arena.checked_sub(1),
+        }
+    }
+
+    /// The arena picked by the flags. Fails if there is no arena with the index.
+    fn arena(&self) -> Result<Option<PinnedArena>, ()> {
+        match self.arena {
+            None => Ok(None),
+            Some(index) => arena_at(index).map(Some).ok_or(()),
+        }
+    }
+}
+
+fn alloc_in(
+    arena: Option<&ArenaHeap>,
+    size: usize,
+    flags: &Flags,
+) -> Result<(NonNull<u8>, ZeroState), MallocError> {
+    match arena {
+        Some(arena) => arena.alloc(size, flags.align).map(|ptr| (ptr, ZeroState::Dirty)),
+        None => {
+            let _scope = flags.long_lived.then(|| lifetime_scope(Lifetime::Long));
+            aligned_alloc_with(flags.align, size, flags.use_cache)
+        }
+    }
+}
+
+/// Allocates a space in memory of length `size`, with the options set by `flags`.
+///
+/// If the allocation fails, or the flags pick an arena that doesn't exist, a NULL pointer is returned.
+pub fn do_mallocx(size: usize, flags: i32) -> *mut u8 {
+    let options = Flags::new(flags);
+    let arena = match options.arena() {
+        Ok(arena) => arena,
+        Err(()) => return null_mut(),
+    };
+    match alloc_in(arena.as_deref(), size, &options) {
+        Ok((ptr, state)) => {
+            if options.zero {
+                unsafe { zero_allocation(ptr.as_ptr(), size, state) };
+            }
+            ptr.as_ptr()
+        }
+        Err(_) => null_mut(),
+    }
+}
+
+/// The number of usable bytes [`do_mallocx()`](fn.do_mallocx.html) would hand out for `size` bytes with the alignment
+/// set by `flags`, without allocating anything. The rest of the flags are ignored.
+///
+/// Returns `None` if the size is too big to be allocated.
+pub fn do_nallocx(size: usize, flags: i32) -> Option<usize> {
+    usable_size_for(size, Flags::new(flags).align)
+}
+
+/// Resizes the allocation at `ptr` to `size` bytes, with the options set by `flags`, moving it if it does not fit. An
+/// allocation is kept in its arena unless the flags pick a different one, in which case it is always moved.
+///
+/// If a NULL pointer is passed through, it's equivalent to calling `do_mallocx(size, flags)`. If the allocation fails,
+/// a NULL pointer is returned and `ptr` is left as it was.
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by this allocator that has not been freed.
+pub unsafe fn do_rallocx(ptr: *mut u8, size: usize, flags: i32) -> *mut u8 {
+    if ptr.is_null() {
+        return do_mallocx(size, flags);
+    }
+    let old_size = match usable_size(ptr) {
+        Some(size) => size,
+        None => return do_mallocx(size, flags),
+    };
+    let options = Flags::new(flags);
+    let current = arena_of(ptr);
+    let picked = match options.arena() {
+        Ok(picked) => picked,
+        Err(()) => return null_mut(),
+    };
+    let arena = match &picked {
+        None => current,
+        Some(arena) => Some(&**arena),
+    };
+
+    let same_arena = match (arena, current) {
+        (Some(arena), Some(current)) => std::ptr::eq(arena, current),
+        (None, None) => true,
+        _ => false,
+    };
+    if same_arena {
+        // a remapped segment is aligned to a page, and the pages it gains are already zero
+        if options.align <= PAGE {
+            if let Some(ret) = remap_large(ptr as *mut c_void, size) {
+                return ret as *mut u8;
+            }
+        }
+        if fits_in_place(ptr, old_size, size, options.align) {
+            return ptr;
+        }
+    }
+
+    let ret = match alloc_in(arena, size, &options) {
+        Ok((ret, _)) => ret.as_ptr(),
+        Err(_) => return null_mut(),
+    };
+    std::ptr::copy_nonoverlapping(ptr, ret, old_size.min(size));
+    if options.zero && size > old_size {
+        ret.add(old_size).write_bytes(0, size - old_size);
+    }
+    do_dallocx(ptr, flags);
+    ret
+}
+
+/// Frees a location in memory, with the options set by `flags`. Only
+/// [`MALLOCX_TCACHE_NONE`](constant.MALLOCX_TCACHE_NONE.html) changes how memory is freed, and the rest of the flags
+/// are ignored. A free to a `NULL` pointer has no effect.
+///
+/// # Safety
+/// `ptr` must be NULL, or a pointer returned by this allocator that has not been freed.
+pub unsafe fn do_dallocx(ptr: *mut u8, flags: i32) {
+    if ptr.is_null() {
+        return;
+    }
+    if Flags::new(flags).use_cache || ptr_in_bootstrap_reserve(ptr) {
+        return do_free(ptr);
+    }
+    let info = get_page_info_for_ptr(ptr);
+    let desc = match info.get_desc() {
+        Some(desc) => &mut *desc,
+        None => return,
+    };
+    match info.get_size_class_index() {
+        Some(size_class_index) if size_class_index != 0 && desc.arena.is_null() => {
+            free_to_central(ptr, desc, size_class_index)
+        }
+        _ => do_free(ptr),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arena::{Arena, MAX_ARENAS};
+    use crate::mem_info::MAX_SZ;
+    use crate::thread_cache;
+
+    #[test]
+    fn aligned_and_zeroed() {
+        unsafe {
+            let dirty = do_mallocx(200, 0);
+            dirty.write_bytes(0xff, 200);
+            do_dallocx(dirty, 0);
+
+            let ptr = do_mallocx(200, mallocx_lg_align(7) | MALLOCX_ZERO);
+            assert_eq!(ptr as usize % 128, 0);
+            assert!((0..200).all(|i| *ptr.add(i) == 0));
+
+            ptr.write_bytes(0xff, 200);
+            let grown = do_rallocx(ptr, MAX_SZ * 2, mallocx_lg_align(7) | MALLOCX_ZERO);
+            assert_eq!(grown as usize % 128, 0);
+            assert!((0..200).all(|i| *grown.add(i) == 0xff));
+            assert!((200..MAX_SZ * 2).all(|i| *grown.add(i) == 0));
+
+            let aligned = do_rallocx(grown, PAGE * 2, mallocx_lg_align(14));
+            assert_eq!(aligned as usize % (1 << 14), 0);
+            do_dallocx(aligned, 0);
+        }
+    }
+
+    #[test]
+    fn without_thread_cache() {
+        std::thread::spawn(|| unsafe {
+            let ptr = do_mallocx(3000, MALLOCX_TCACHE_NONE);
+            assert!(!ptr.is_null());
+            do_dallocx(ptr, MALLOCX_TCACHE_NONE);
+            let size_class_index = get_page_info_for_ptr(ptr).get_size_class_index().unwrap();
+            thread_cache::thread_cache.with(|tcache| {
+                assert_eq!((*tcache.get())[size_class_index].get_block_num(), 0);
+            });
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn in_arena() {
+        let arena = Arena::new().unwrap();
+        let index = arena.index().unwrap();
+        unsafe {
+            let ptr = do_mallocx(64, mallocx_arena(index));
+            assert!(std::ptr::eq(arena_of(ptr).unwrap(), &*arena_at(index).unwrap()));
+            let moved = do_rallocx(ptr, 2000, 0);
+            assert!(arena_of(moved).is_some(), "The allocation should stay in its arena");
+            let out = do_rallocx(moved, 2000, mallocx_arena(MAX_ARENAS));
+            assert!(out.is_null(), "There is no arena with that index");
+            do_dallocx(moved, 0);
+        }
+    }
+}
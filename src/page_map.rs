@@ -1,11 +1,10 @@
-use crate::allocation_data::Descriptor;
-use crate::mem_info::{LG_PAGE, MAX_SZ, MAX_SZ_IDX};
-#[cfg(not(unix))]
-use crate::mem_info::PAGE;
+use crate::allocation_data::{Descriptor, ProcHeap};
+use crate::mem_info::{LG_PAGE, MAX_SZ, MAX_SZ_IDX, PAGE, PAGE_MASK};
 use bitfield::size_of;
 
 use std::ptr::slice_from_raw_parts_mut;
 
+use crate::pages::external_mem_reservation::{AllocationError, Segment};
 use crate::pages::page_alloc_over_commit;
 use crate::size_classes::get_size_class;
 use atomic::Atomic;
@@ -168,8 +167,29 @@ pub struct PageMap<'a> {
     page_map: &'a [Atomic<PageInfo>],
 }
 
+impl Default for PageMap<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PageMap<'_> {
+    /// A page map that has not been mapped yet
+    pub const fn new() -> Self {
+        PageMap {
+            mem_location: None,
+            page_map: &[],
+        }
+    }
+
     pub fn init(&mut self) {
+        if let Err(e) = self.try_init() {
+            panic!("Error creating memory map: {:?}", e)
+        }
+    }
+
+    /// Maps the page map, or returns the reason it could not be mapped
+    pub(crate) fn try_init(&mut self) -> Result<(), AllocationError> {
         //println!("PM_NLS = {:?}", PM_NLS);
         // println!("PM_NHS = {:?}", PM_NHS);
         // println!("PM_SB = {:?}", PM_SB);
@@ -206,8 +226,66 @@ impl PageMap<'_> {
                 self.page_map = slice;
 
                 self.mem_location = Some(map);
+                Ok(())
             }
-            Err(e) => panic!("Error creating memory map: {:?}", e),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Registers `desc` for the page of `ptr`, or for every page of the superblock starting at `ptr` if it belongs to
+    /// `heap`. Passing no descriptor clears the pages instead.
+    pub(crate) fn update(
+        &self,
+        heap: Option<&ProcHeap>,
+        ptr: *mut u8,
+        desc: Option<&mut Descriptor>,
+        size_class_index: usize,
+    ) {
+        if ptr.is_null() {
+            panic!("Pointer should not be null");
+        }
+
+        let mut info: PageInfo = PageInfo::default();
+        info.set_ptr(desc.map_or(null_mut(), |d| d as *mut Descriptor), size_class_index);
+        let heap = match heap {
+            None => return self.set_page_info(ptr, info),
+            Some(heap) => heap,
+        };
+
+        let sb_size = heap.get_size_class().sb_size;
+        assert_eq!(sb_size & PAGE_MASK as u32, 0, "sb_size must be a multiple of a page");
+        for index in 0..(sb_size / PAGE as u32) {
+            self.set_page_info(unsafe { ptr.add((index * PAGE as u32) as usize) }, info)
+        }
+    }
+
+    /// Registers the superblock or large allocation of `desc`. Every page of a large allocation is registered, so that
+    /// any address inside of it leads back to its descriptor.
+    pub(crate) fn register(&self, desc: &mut Descriptor) {
+        if desc.is_large() {
+            let segment = desc.super_block.as_ref().unwrap();
+            let (ptr, len) = (segment.get_ptr() as *mut u8, segment.len());
+            let mut info = PageInfo::default();
+            info.set(desc, 0);
+            return self.set_pages_info(ptr, len, info);
+        }
+        let heap = unsafe { &*desc.proc_heap };
+        let ptr = desc.super_block.as_ref().unwrap().get_ptr() as *mut u8;
+        self.update(Some(heap), ptr, Some(desc), 0);
+    }
+
+    /// Clears the pages of `super_block`, which is a superblock of `heap`, or a large allocation if there is no heap
+    pub(crate) fn unregister(&self, heap: Option<&ProcHeap>, super_block: &Segment) {
+        match heap {
+            Some(heap) => self.update(Some(heap), super_block.get_ptr() as *mut u8, None, 0),
+            None => self.set_pages_info(super_block.get_ptr() as *mut u8, super_block.len(), PageInfo::default()),
+        }
+    }
+
+    /// Sets the info of every page in the `len` bytes starting at `ptr`
+    fn set_pages_info(&self, ptr: *mut u8, len: usize, info: PageInfo) {
+        for offset in (0..len).step_by(PAGE) {
+            self.set_page_info(unsafe { ptr.add(offset) }, info)
         }
     }
 
@@ -375,7 +453,7 @@ impl PageMap<'_> {
     }
 }
 
-pub static mut S_PAGE_MAP: PageMap = PageMap {
-    mem_location: None,
-    page_map: &[],
-};
+/// The page map of the global instance. It is mapped when the allocator is initialized, and the global instance
+/// registers its pages in it from then on, so it must not be changed through this.
+#[deprecated(note = "use `alloc::get_page_info_for_ptr()` to look up the pointers of the global instance")]
+pub static mut S_PAGE_MAP: PageMap = PageMap::new();
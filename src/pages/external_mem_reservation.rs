@@ -89,11 +89,40 @@ pub static LOCK: AtomicBool = AtomicBool::new(false);
 pub trait SegAllocator {
     /// Must guarantee that a segment is returned safetly, or results in an error.
     /// It must no panic when called
+    ///
+    /// The memory of a new segment must be zeroed, as the allocator skips zeroing memory it has never handed out.
     fn allocate(&self, size: usize) -> Result<Segment, AllocationError>;
 
     /// Allocates a MASSIVE amount of space, but should not cause an out of memory error. The method by which this achieved is system dependent
     fn allocate_massive(&self, size: usize) -> Result<Segment, AllocationError>;
 
+    /// Resizes a segment to `size` bytes, keeping its contents up to the smaller of the two sizes. The segment may be
+    /// moved, in which case `segment` is updated to the new location. Shrinking a segment gives the pages past the new
+    /// end back to the OS without moving it.
+    ///
+    /// If the segment can not be resized, an error is returned and the segment is left unchanged. By default, resizing
+    /// is not supported.
+    ///
+    /// # Safety
+    /// The segment must have been created by [allocate()](trait.SegAllocator.html#tymethod.allocate), and no pointers
+    /// into it may be used after it has been moved.
+    unsafe fn reallocate(&self, segment: &mut Segment, size: usize) -> Result<(), AllocationError> {
+        let _ = segment;
+        Err(AllocationFailed(size, Errno(libc::ENOSYS)))
+    }
+
+    /// Gives the pages from `ptr` to `ptr + len` back to the OS while keeping them mapped, so that they read as zero
+    /// the next time they are used. Returns the number of those bytes that were resident before, or 0 if pages can not
+    /// be released on this platform, which is the default.
+    ///
+    /// # Safety
+    /// `ptr` and `len` must be aligned to a page, the pages must be part of a segment created by
+    /// [allocate()](trait.SegAllocator.html#tymethod.allocate), and nothing may be kept in them.
+    unsafe fn release_pages(&self, ptr: *mut c_void, len: usize) -> usize {
+        let _ = (ptr, len);
+        0
+    }
+
     /// De-allocates a segment. Depending on the platform, this may not do anything.
     ///
     /// # Safety
@@ -194,7 +223,8 @@ impl SegAllocator for SegmentAllocator {
                 null_mut(),
                 size,
                 libc::PROT_WRITE | libc::PROT_READ,
-                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                // A private mapping, as a shared anonymous mapping can not be grown by mremap
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
                 -1,
                 0,
             )
@@ -227,6 +257,37 @@ impl SegAllocator for SegmentAllocator {
         }
     }
 
+    #[cfg(target_os = "linux")]
+    unsafe fn reallocate(&self, segment: &mut Segment, size: usize) -> Result<(), AllocationError> {
+        let mmap = libc::mremap(segment.ptr, segment.length, size, libc::MREMAP_MAYMOVE);
+        if mmap == libc::MAP_FAILED {
+            Err(AllocationFailed(size, errno::errno()))
+        } else {
+            segment.ptr = mmap;
+            segment.length = size;
+            Ok(())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn release_pages(&self, ptr: *mut c_void, len: usize) -> usize {
+        use crate::mem_info::PAGE;
+
+        // counted a chunk of pages at a time, as the allocator can't allocate a vector for mincore
+        let mut resident = [0u8; 64];
+        let mut released = 0;
+        for offset in (0..len).step_by(resident.len() * PAGE) {
+            let chunk = (len - offset).min(resident.len() * PAGE);
+            if libc::mincore(ptr.add(offset), chunk, resident.as_mut_ptr()) == 0 {
+                released += resident[..chunk.div_ceil(PAGE)].iter().filter(|&&page| page & 1 != 0).count() * PAGE;
+            }
+        }
+        if libc::madvise(ptr, len, libc::MADV_DONTNEED) == 0 {
+            released
+        } else {
+            0
+        }
+    }
 
     unsafe fn deallocate(&self, segment: Segment) -> bool {
         // while LOCK.compare_and_swap(false, true, Ordering::Acquire) { }
@@ -275,6 +336,28 @@ mod test {
         }
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    pub fn reallocate_segment() {
+        unsafe {
+            let mut segment = SEGMENT_ALLOCATOR
+                .allocate(PAGE)
+                .expect("Test must fail is this fails");
+            *(segment.get_ptr() as *mut usize) = 0xdeadbeaf;
+
+            SEGMENT_ALLOCATOR.reallocate(&mut segment, PAGE * 64).unwrap();
+            assert_eq!(segment.len(), PAGE * 64);
+            assert_eq!(*(segment.get_ptr() as *mut usize), 0xdeadbeaf);
+            // the new pages must be usable
+            *(segment.get_ptr() as *mut u8).add(PAGE * 64 - 1) = 1;
+
+            let ptr = segment.get_ptr();
+            SEGMENT_ALLOCATOR.reallocate(&mut segment, PAGE * 2).unwrap();
+            assert_eq!(segment.get_ptr(), ptr, "Shrinking should not move the segment");
+            assert!(SEGMENT_ALLOCATOR.deallocate(segment));
+        }
+    }
+
     #[test]
     pub fn allocate_page_table_size() {
         let size = PM_SZ;
@@ -52,7 +52,7 @@ pub unsafe fn init_size_class() {
     for sc_index in 1..MAX_SZ_IDX {
         let sc = &mut SIZE_CLASSES[sc_index];
         let mut sb_size = sc.sb_size;
-        let max = page_ceiling!(*crate::apf::TARGET_APF * sc.block_size as usize) as u32;
+        let max = page_ceiling!(crate::apf::target_apf() * sc.block_size as usize) as u32;
         while sb_size < max {
             sb_size += sc.sb_size;
         }
@@ -0,0 +1,272 @@
+//! Always available statistics on the state of the allocator.
+//!
+//! Unlike the `info_dump` module, which requires the `track_allocation` feature and locks on every allocation, nothing
+//! is counted while allocating. [`heap_stats()`](fn.heap_stats.html) walks the descriptor of every superblock and large
+//! allocation of the global heap, and reads the free blocks left in each superblock from its anchor and the free blocks
+//! held outside of it from the bins of the thread caches and arenas. Only the numbers that can't be seen in the heap,
+//! such as the most memory large allocations have ever held, are kept in counters as segments are mapped. The most
+//! memory held by superblocks is the most that any snapshot has found.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::allocation_data::Descriptor;
+use crate::arena::ArenaHeap;
+use crate::independent_collections::Array;
+use crate::iterate::hold_heap_still;
+use crate::lifetime::Lifetime;
+use crate::mem_info::MAX_SZ_IDX;
+use crate::size_classes::SIZE_CLASSES;
+use crate::tags::{tag_counters, tagged_counters};
+use crate::thread_cache::count_cached_blocks;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SUPERBLOCKS: AtomicUsize = AtomicUsize::new(0);
+/// The number of superblocks of each size class that have been given back to the OS since the program started
+static RELEASED_SUPERBLOCKS: [AtomicUsize; MAX_SZ_IDX] = [NO_SUPERBLOCKS; MAX_SZ_IDX];
+
+// The most bytes of superblocks found by a snapshot
+static MAX_ARENA_BYTES: AtomicUsize = AtomicUsize::new(0);
+// Only kept to find the most memory that has been mapped at once
+static LARGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+static LARGE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static MAX_LARGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+static MAX_LARGE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Records that a superblock of the size class was given back to the OS
+pub(crate) fn superblock_unmapped(size_class_index: usize) {
+    RELEASED_SUPERBLOCKS[size_class_index].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records a large allocation, which is given its own segment
+pub(crate) fn large_mapped(bytes: usize) {
+    let count = LARGE_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+    let total = LARGE_BYTES.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    MAX_LARGE_COUNT.fetch_max(count, Ordering::Relaxed);
+    MAX_LARGE_BYTES.fetch_max(total, Ordering::Relaxed);
+}
+
+pub(crate) fn large_unmapped(bytes: usize) {
+    LARGE_COUNT.fetch_sub(1, Ordering::Relaxed);
+    LARGE_BYTES.fetch_sub(bytes, Ordering::Relaxed);
+}
+
+/// The state of a single size class
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    /// The size of every block in the size class
+    pub block_size: usize,
+    /// The number of superblocks currently mapped
+    pub superblocks: usize,
+    /// The number of the mapped superblocks that hold [long-lived](../lifetime/index.html) blocks
+    pub long_lived_superblocks: usize,
+    /// The number of superblocks that have been given back to the OS since the program started, either once their
+    /// blocks were all freed, by [`purge()`](../fn.purge.html), or by destroying an arena
+    pub released_superblocks: usize,
+    /// The number of bytes in the mapped superblocks
+    pub superblock_bytes: usize,
+    /// The number of blocks in the mapped superblocks
+    pub total_blocks: usize,
+    /// The number of blocks in use by the program
+    pub in_use_blocks: usize,
+    /// The number of free blocks held by the thread caches of all threads, and by arenas
+    pub thread_cache_blocks: usize,
+    /// The number of free blocks held by the central reserve
+    pub central_blocks: usize,
+}
+
+/// A snapshot of the state of the allocator. The superblocks and large allocations are kept still while the snapshot is
+/// taken, but the thread caches of other threads are read while they keep allocating, so the split between blocks in
+/// use and blocks held by thread caches may be slightly off while other threads are allocating.
+#[derive(Debug, Clone)]
+pub struct HeapStats {
+    /// The bytes mapped for superblocks
+    pub arena_bytes: usize,
+    /// The largest that `arena_bytes` has been in any snapshot so far
+    pub max_arena_bytes: usize,
+    /// The bytes in superblocks that are in use by the program
+    pub in_use_bytes: usize,
+    /// The bytes of free blocks held by the thread caches and arenas
+    pub thread_cache_bytes: usize,
+    /// The bytes of free blocks held by the central reserve
+    pub central_free_bytes: usize,
+    /// The number of large allocations, each of which is mapped directly
+    pub mmapped_count: usize,
+    /// The bytes mapped for large allocations
+    pub mmapped_bytes: usize,
+    /// The largest that `mmapped_count` has ever been
+    pub max_mmapped_count: usize,
+    /// The largest that `mmapped_bytes` has ever been
+    pub max_mmapped_bytes: usize,
+    /// The state of every size class. Index 0 is reserved for large allocations, and is always empty.
+    pub size_classes: [SizeClassStats; MAX_SZ_IDX],
+}
+
+impl HeapStats {
+    /// The free bytes held by the allocator in superblocks
+    pub fn free_bytes(&self) -> usize {
+        self.thread_cache_bytes + self.central_free_bytes
+    }
+}
+
+/// Takes a snapshot of the state of the allocator by walking its superblocks, large allocations and thread caches. This
+/// blocks while the heap is [disabled](../iterate/fn.disable_allocation.html) by another thread.
+pub fn heap_stats() -> HeapStats {
+    let mut stats = HeapStats {
+        arena_bytes: 0,
+        max_arena_bytes: 0,
+        in_use_bytes: 0,
+        thread_cache_bytes: 0,
+        central_free_bytes: 0,
+        mmapped_count: 0,
+        mmapped_bytes: 0,
+        max_mmapped_count: MAX_LARGE_COUNT.load(Ordering::Relaxed),
+        max_mmapped_bytes: MAX_LARGE_BYTES.load(Ordering::Relaxed),
+        size_classes: [SizeClassStats::default(); MAX_SZ_IDX],
+    };
+
+    // The free blocks of each size class that are held outside of their superblock
+    let mut cached = [0; MAX_SZ_IDX];
+    {
+        let _still = hold_heap_still();
+        let mut arenas = Array::<*const ArenaHeap>::new();
+        unsafe {
+            Descriptor::for_each_live(|desc| {
+                let len = desc.super_block.as_ref().map_or(0, |segment| segment.len());
+                if desc.is_large() {
+                    stats.mmapped_count += 1;
+                    stats.mmapped_bytes += len;
+                    return;
+                }
+                let heap = &*desc.proc_heap;
+                let class = &mut stats.size_classes[heap.size_class_index];
+                class.superblocks += 1;
+                if heap.lifetime == Lifetime::Long {
+                    class.long_lived_superblocks += 1;
+                }
+                class.superblock_bytes += len;
+                class.total_blocks += desc.max_count as usize;
+                class.central_blocks += desc.anchor.load(Ordering::Acquire).count() as usize;
+                if !desc.arena.is_null() && !arenas.contains(&desc.arena) {
+                    arenas.push(desc.arena);
+                    (*desc.arena).count_free_blocks(&mut cached);
+                }
+            });
+        }
+        count_cached_blocks(&mut cached);
+    }
+
+    for size_class_index in 1..MAX_SZ_IDX {
+        let block_size = unsafe { SIZE_CLASSES[size_class_index].block_size } as usize;
+        let class = &mut stats.size_classes[size_class_index];
+        class.block_size = block_size;
+        class.released_superblocks = RELEASED_SUPERBLOCKS[size_class_index].load(Ordering::Relaxed);
+        let out_blocks = class.total_blocks - class.central_blocks;
+        class.thread_cache_blocks = cached[size_class_index].min(out_blocks);
+        class.in_use_blocks = out_blocks - class.thread_cache_blocks;
+
+        stats.arena_bytes += class.superblock_bytes;
+        stats.in_use_bytes += class.in_use_blocks * block_size;
+        stats.thread_cache_bytes += class.thread_cache_blocks * block_size;
+        stats.central_free_bytes += class.central_blocks * block_size;
+    }
+    stats.max_arena_bytes = MAX_ARENA_BYTES.fetch_max(stats.arena_bytes, Ordering::Relaxed).max(stats.arena_bytes);
+    stats.max_mmapped_count = stats.max_mmapped_count.max(stats.mmapped_count);
+    stats.max_mmapped_bytes = stats.max_mmapped_bytes.max(stats.mmapped_bytes);
+
+    stats
+}
+
+/// Prints a snapshot from [`heap_stats()`](fn.heap_stats.html) to stderr, in the same format as the `malloc_stats` of
+/// glibc
+pub fn print_stats() {
+    let stats = heap_stats();
+    eprintln!("Arena 0:");
+    eprintln!("system bytes     = {:>10}", stats.arena_bytes);
+    eprintln!("in use bytes     = {:>10}", stats.in_use_bytes);
+    eprintln!("Total (incl. mmap):");
+    eprintln!("system bytes     = {:>10}", stats.arena_bytes + stats.mmapped_bytes);
+    eprintln!("in use bytes     = {:>10}", stats.in_use_bytes + stats.mmapped_bytes);
+    eprintln!("max mmap regions = {:>10}", stats.max_mmapped_count);
+    eprintln!("max mmap bytes   = {:>10}", stats.max_mmapped_bytes);
+}
+
+/// The allocations charged to an [allocation tag](../tags/index.html)
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct TagStats {
+    /// The bytes of the blocks and large allocations in use, counting the whole block or segment of each
+    pub live_bytes: usize,
+    /// The number of blocks and large allocations in use
+    pub live_allocations: usize,
+}
+
+/// Takes a snapshot of the allocations charged to `tag`. The allocations of tag 0 are what is left of a snapshot from
+/// [`heap_stats()`](fn.heap_stats.html) once every other tag is taken out, so they are only as exact as that snapshot.
+pub fn tag_stats(tag: u16) -> TagStats {
+    let (live_bytes, live_allocations) = if tag == 0 {
+        let stats = heap_stats();
+        let live_bytes = stats.in_use_bytes + stats.mmapped_bytes;
+        let live_allocations = stats.mmapped_count
+            + stats.size_classes.iter().map(|class| class.in_use_blocks).sum::<usize>();
+        let (tagged_bytes, tagged_allocations) = tagged_counters();
+        (
+            live_bytes.saturating_sub(tagged_bytes),
+            live_allocations.saturating_sub(tagged_allocations),
+        )
+    } else {
+        tag_counters(tag)
+    };
+    TagStats {
+        live_bytes,
+        live_allocations,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{do_free, do_malloc};
+
+    #[test]
+    fn counts_live_blocks() {
+        let ptrs = (0..64).map(|_| do_malloc(200)).collect::<Vec<_>>();
+        let stats = heap_stats();
+        let class = &stats.size_classes[crate::size_classes::get_size_class(200)];
+        assert!(class.superblocks > 0);
+        assert!(class.in_use_blocks >= 64);
+        assert!(class.total_blocks >= class.in_use_blocks + class.thread_cache_blocks);
+        assert!(stats.arena_bytes >= class.superblock_bytes);
+
+        for ptr in ptrs {
+            unsafe {
+                do_free(ptr);
+            }
+        }
+        for class in heap_stats().size_classes.iter() {
+            assert_eq!(
+                class.total_blocks,
+                class.in_use_blocks + class.thread_cache_blocks + class.central_blocks
+            );
+        }
+    }
+
+    #[test]
+    fn snapshot_while_disabled() {
+        crate::iterate::disable_allocation();
+        let stats = heap_stats();
+        crate::iterate::enable_allocation();
+        assert!(stats.arena_bytes > 0);
+        assert!(stats.max_arena_bytes >= stats.arena_bytes);
+    }
+
+    #[test]
+    fn counts_large_allocations() {
+        let ptr = do_malloc(crate::mem_info::MAX_SZ * 4);
+        let stats = heap_stats();
+        assert!(stats.mmapped_count >= 1);
+        assert!(stats.mmapped_bytes >= crate::mem_info::MAX_SZ * 4);
+        assert!(stats.max_mmapped_bytes >= stats.mmapped_bytes);
+        unsafe {
+            do_free(ptr);
+        }
+    }
+}
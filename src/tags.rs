@@ -0,0 +1,677 @@
+//! Allocation tags, which charge allocations to categories picked by the program, such as the subsystem that made them.
+//!
+//! Every thread has a current tag, set with [`set_alloc_tag()`](fn.set_alloc_tag.html), and every allocation it makes
+//! is charged to that tag. Tag 0 is the default, and holds everything that was not tagged. Each other tag has its own
+//! heaps, so the superblocks of a tag only ever hold blocks of that tag, and a block freed by any thread is charged
+//! back to the tag of its superblock. The bytes and allocations held by a tag are given by
+//! [`tag_stats()`](../stats/fn.tag_stats.html).
+//!
+//! Allocations from an [`Arena`](../arena/struct.Arena.html) are never tagged. Memory the allocator needs for a thread,
+//! which is set up on its first allocation, is charged to the tag of the thread at the time.
+//!
+//! # Budgets
+//! A tag other than 0 can be given a [`TagBudget`](struct.TagBudget.html) with
+//! [`set_tag_budget()`](fn.set_tag_budget.html). Once the tag goes over its soft limit, the handler set with
+//! [`set_soft_limit_handler()`](fn.set_soft_limit_handler.html) is called, and an allocation that would take it over
+//! its hard limit fails instead, with [`MallocError::LimitExceeded`](../enum.MallocError.html#variant.LimitExceeded).
+//!
+//! So that allocating from the thread cache stays as cheap as without a budget, a tag is counted as holding every block
+//! moved out of the central reserve for it, which includes the free blocks in thread caches, and the limits are only
+//! checked when a thread cache is filled. A tag can then fail to allocate while other threads cache free blocks of it,
+//! until they are flushed. [`set_exact_accounting()`](fn.set_exact_accounting.html) checks the limits against the live
+//! bytes of the tag on every allocation instead, which is slower, but gives the same result no matter how the blocks
+//! are cached.
+//!
+//! # Example
+//! ```
+//! use apfmalloc_lib::stats::tag_stats;
+//! use apfmalloc_lib::tags::set_alloc_tag;
+//! use apfmalloc_lib::{do_free, do_malloc};
+//!
+//! // the first allocation of a thread sets up its cache, which would be charged to the tag as well
+//! unsafe { do_free(do_malloc(8)) };
+//! set_alloc_tag(3).unwrap();
+//! let buffer = do_malloc(1000);
+//! set_alloc_tag(0).unwrap();
+//! assert_eq!(tag_stats(3).live_allocations, 1);
+//! unsafe { do_free(buffer) };
+//! assert_eq!(tag_stats(3).live_allocations, 0);
+//! ```
+
+use std::cell::Cell;
+use std::num::NonZeroU16;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::allocation_data::{global_heaps, ProcHeap};
+use crate::lifetime::{self, Lifetime, LIFETIMES};
+use crate::mem_info::MAX_SZ_IDX;
+use crate::pages::external_mem_reservation::{SegAllocator, SEGMENT_ALLOCATOR};
+use crate::thread_cache::switch_tag;
+use crate::MallocError;
+
+/// The number of tags, as a tag is a `u16`
+const TAG_COUNT: usize = 1 << 16;
+/// The value of a limit that is not set
+const UNLIMITED: usize = usize::MAX;
+
+/// The heaps and counters of a tag other than 0
+struct TagHeap {
+    heaps: [[ProcHeap; MAX_SZ_IDX]; LIFETIMES],
+    live_bytes: AtomicUsize,
+    live_allocations: AtomicUsize,
+    /// The bytes moved out of the central reserve for the tag, which are either live or in a thread cache
+    held_bytes: AtomicUsize,
+    soft_limit: AtomicUsize,
+    hard_limit: AtomicUsize,
+}
+
+impl TagHeap {
+    /// The hard limit on the live bytes if `live`, or on the held bytes otherwise. Only the counter picked by the
+    /// accounting mode is limited.
+    fn hard_limit(&self, live: bool) -> usize {
+        if live == EXACT_ACCOUNTING.load(Ordering::Relaxed) {
+            self.hard_limit.load(Ordering::Relaxed)
+        } else {
+            UNLIMITED
+        }
+    }
+
+    /// Remembers to call the soft limit handler once the allocation is done, if a counter went from `before` to `after`
+    /// bytes over the soft limit. Only the counter checked by the current accounting mode is passed through.
+    fn check_soft_limit(&self, tag: u16, before: usize, after: usize) {
+        let soft_limit = self.soft_limit.load(Ordering::Relaxed);
+        if before <= soft_limit && after > soft_limit {
+            let _ = soft_limit_hit.try_with(|hit| hit.set(tag));
+        }
+    }
+}
+
+/// The limits on the bytes held by a tag. A limit of `None` is not checked.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct TagBudget {
+    /// The number of bytes the tag can hold before the soft limit handler is called
+    pub soft_limit: Option<usize>,
+    /// The number of bytes the tag can never hold more than
+    pub hard_limit: Option<usize>,
+}
+
+/// Called with a tag, the bytes it holds, and its soft limit, once the tag goes over its soft limit. The handler is
+/// called by the thread whose allocation went over the limit, after the allocation is done, and may allocate.
+pub type SoftLimitHandler = fn(tag: u16, bytes: usize, soft_limit: usize);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TAG: AtomicPtr<TagHeap> = AtomicPtr::new(null_mut());
+/// The state of every tag that has been used. Tag 0 uses the global heaps, and its counters are worked out from the
+/// rest.
+static TAGS: [AtomicPtr<TagHeap>; TAG_COUNT] = [NO_TAG; TAG_COUNT];
+/// The biggest tag that has been used, so that the unused end of `TAGS` can be skipped
+static HIGHEST_TAG: AtomicUsize = AtomicUsize::new(0);
+/// Set once a tag other than 0 has been used. Until then, nothing is charged to a tag.
+static TAGS_USED: AtomicBool = AtomicBool::new(false);
+static EXACT_ACCOUNTING: AtomicBool = AtomicBool::new(false);
+static SOFT_LIMIT_HANDLER: Mutex<Option<SoftLimitHandler>> = Mutex::new(None);
+
+thread_local! {
+    static current_tag: Cell<u16> = const { Cell::new(0) };
+    /// The tag that went over its soft limit during the current allocation, or 0
+    static soft_limit_hit: Cell<u16> = const { Cell::new(0) };
+    /// The bytes and allocations charged to the current tag of the thread, less those taken back, that have not been
+    /// added to the counters of the tag yet
+    static pending_live: Cell<(isize, isize)> = const { Cell::new((0, 0)) };
+}
+
+/// Sets the tag that the allocations of the calling thread are charged to, until it is set again. The blocks cached by
+/// the thread belong to the old tag, so they are set aside, and used again once the thread switches back to that tag.
+/// The blocks of the last few tags are kept, and those of the tag the thread switched away from the longest ago are
+/// returned to the central reserve to make room.
+///
+/// Fails if the heaps of a tag used for the first time could not be mapped, in which case the tag is left as it was.
+pub fn set_alloc_tag(tag: u16) -> Result<(), MallocError> {
+    let old = alloc_tag();
+    if tag == old {
+        return Ok(());
+    }
+    if tag != 0 {
+        tag_heap_or_create(tag)?;
+    }
+    flush_live();
+    switch_tag(old, tag);
+    let _ = current_tag.try_with(|current| current.set(tag));
+    Ok(())
+}
+
+/// The tag that the allocations of the calling thread are charged to
+#[inline]
+pub fn alloc_tag() -> u16 {
+    current_tag.try_with(|current| current.get()).unwrap_or(0)
+}
+
+/// Sets the limits of `tag`, replacing any limits it had. Tag 0 can not be given a budget, as it has no counters of its
+/// own. Lowering a limit below what the tag already holds does not free anything, but no more memory is given to the
+/// tag until it is under its hard limit again.
+///
+/// Fails if the heaps of a tag used for the first time could not be mapped.
+///
+/// # Example
+/// ```
+/// use apfmalloc_lib::tags::{set_alloc_tag, set_tag_budget, TagBudget};
+/// use apfmalloc_lib::{do_free, try_malloc, MallocError};
+/// use std::num::NonZeroU16;
+///
+/// let budget = TagBudget {
+///     soft_limit: None,
+///     hard_limit: Some(1 << 20),
+/// };
+/// set_tag_budget(NonZeroU16::new(4).unwrap(), budget).unwrap();
+/// set_alloc_tag(4).unwrap();
+/// let result = try_malloc(2 << 20);
+/// set_alloc_tag(0).unwrap();
+/// assert!(matches!(result, Err(MallocError::LimitExceeded { .. })));
+/// ```
+pub fn set_tag_budget(tag: NonZeroU16, budget: TagBudget) -> Result<(), MallocError> {
+    let heap = tag_heap_or_create(tag.get())?;
+    heap.soft_limit
+        .store(budget.soft_limit.unwrap_or(UNLIMITED), Ordering::Relaxed);
+    heap.hard_limit
+        .store(budget.hard_limit.unwrap_or(UNLIMITED), Ordering::Relaxed);
+    Ok(())
+}
+
+/// The limits of `tag`
+pub fn tag_budget(tag: u16) -> TagBudget {
+    let limit = |limit: &AtomicUsize| Some(limit.load(Ordering::Relaxed)).filter(|&limit| limit != UNLIMITED);
+    tag_heap(tag)
+        .filter(|_| tag != 0)
+        .map_or(TagBudget::default(), |heap| TagBudget {
+            soft_limit: limit(&heap.soft_limit),
+            hard_limit: limit(&heap.hard_limit),
+        })
+}
+
+/// Sets the function called when a tag goes over its soft limit. Passing `None` stops the soft limits from being
+/// reported.
+pub fn set_soft_limit_handler(handler: Option<SoftLimitHandler>) {
+    *SOFT_LIMIT_HANDLER.lock() = handler;
+}
+
+/// Checks the budgets of the tags against their live bytes on every allocation, instead of against the blocks taken out
+/// of the central reserve when a thread cache is filled. This is meant for tests, which need a limit to be hit at the
+/// same point however the thread caches happen to be filled.
+pub fn set_exact_accounting(exact: bool) {
+    EXACT_ACCOUNTING.store(exact, Ordering::Relaxed);
+}
+
+/// Calls the soft limit handler if the allocation that just finished took its tag over its soft limit
+#[inline]
+pub(crate) fn notify_soft_limit() {
+    if !tags_used() {
+        return;
+    }
+    let tag = soft_limit_hit.try_with(|hit| hit.replace(0)).unwrap_or(0);
+    if tag == 0 {
+        return;
+    }
+    let handler = *SOFT_LIMIT_HANDLER.lock();
+    if let (Some(handler), Some(heap)) = (handler, tag_heap(tag)) {
+        let bytes = if EXACT_ACCOUNTING.load(Ordering::Relaxed) {
+            &heap.live_bytes
+        } else {
+            &heap.held_bytes
+        };
+        handler(
+            tag,
+            bytes.load(Ordering::Relaxed),
+            heap.soft_limit.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// Whether any tag other than 0 has been used
+#[inline]
+pub(crate) fn tags_used() -> bool {
+    TAGS_USED.load(Ordering::Acquire)
+}
+
+fn tag_heap(tag: u16) -> Option<&'static TagHeap> {
+    unsafe { TAGS[tag as usize].load(Ordering::Acquire).as_ref() }
+}
+
+fn tag_heap_or_create(tag: u16) -> Result<&'static TagHeap, MallocError> {
+    if let Some(heap) = tag_heap(tag) {
+        return Ok(heap);
+    }
+
+    let segment = SEGMENT_ALLOCATOR.allocate(page_ceiling!(std::mem::size_of::<TagHeap>()))?;
+    let ptr = segment.get_ptr() as *mut TagHeap;
+    unsafe {
+        ptr.write(TagHeap {
+            heaps: Lifetime::ALL.map(|lifetime| {
+                std::array::from_fn(|size_class_index| ProcHeap {
+                    tag,
+                    lifetime,
+                    ..ProcHeap::new_none(size_class_index)
+                })
+            }),
+            live_bytes: AtomicUsize::new(0),
+            live_allocations: AtomicUsize::new(0),
+            held_bytes: AtomicUsize::new(0),
+            soft_limit: AtomicUsize::new(UNLIMITED),
+            hard_limit: AtomicUsize::new(UNLIMITED),
+        });
+    }
+    match TAGS[tag as usize].compare_exchange(null_mut(), ptr, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {
+            HIGHEST_TAG.fetch_max(tag as usize, Ordering::AcqRel);
+            TAGS_USED.store(true, Ordering::Release);
+            Ok(unsafe { &*ptr })
+        }
+        // Another thread used the tag for the first time at the same time
+        Err(heap) => {
+            unsafe { SEGMENT_ALLOCATOR.deallocate(segment) };
+            Ok(unsafe { &*heap })
+        }
+    }
+}
+
+/// The heap of the `size_class_index` size class for blocks of `tag` with the given `lifetime`. The heaps of a tag
+/// exist once it has been set.
+pub(crate) fn heap_for(tag: u16, lifetime: Lifetime, size_class_index: usize) -> &'static ProcHeap {
+    match tag {
+        0 => global_heaps().get_heap_for(lifetime, size_class_index),
+        tag => {
+            let heap = TAGS[tag as usize].load(Ordering::Acquire);
+            unsafe { &(*heap).heaps[lifetime as usize][size_class_index] }
+        }
+    }
+}
+
+/// The heap of the `size_class_index` size class for the tag and lifetime of the calling thread
+#[inline]
+pub(crate) fn current_heap(size_class_index: usize) -> &'static ProcHeap {
+    heap_for(alloc_tag(), lifetime::current(), size_class_index)
+}
+
+/// The tag that a superblock or large allocation of `heap` is charged to
+#[inline]
+pub(crate) fn tag_of(heap: *const ProcHeap) -> u16 {
+    unsafe { heap.as_ref() }.map_or(0, |heap| heap.tag)
+}
+
+/// Calls `f` on every heap of every tag other than 0
+pub(crate) fn for_each_tag_heap<F: FnMut(&'static ProcHeap)>(mut f: F) {
+    for tag in TAGS.iter().take(HIGHEST_TAG.load(Ordering::Acquire) + 1).skip(1) {
+        let heap = tag.load(Ordering::Acquire);
+        if !heap.is_null() {
+            for heaps in unsafe { (*heap).heaps.iter() } {
+                heaps.iter().skip(1).for_each(&mut f);
+            }
+        }
+    }
+}
+
+fn budgeted_heap(tag: u16) -> Option<&'static TagHeap> {
+    tag_heap(tag).filter(|_| tag != 0)
+}
+
+/// Moves up to `count` blocks of `block_size` bytes out of the central reserve for `tag`, and returns how many of them
+/// fit under its hard limit. Fails if not even one of them fits. The blocks that end up not being taken must be given
+/// back with [`release()`](fn.release.html).
+pub(crate) fn reserve(tag: u16, block_size: usize, count: usize) -> Result<usize, MallocError> {
+    let heap = match budgeted_heap(tag) {
+        Some(heap) => heap,
+        None => return Ok(count),
+    };
+    let hard_limit = heap.hard_limit(false);
+    let mut reserved = count;
+    let held = heap
+        .held_bytes
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |held| {
+            reserved = count.min(hard_limit.saturating_sub(held) / block_size);
+            Some(held + reserved * block_size).filter(|_| reserved > 0)
+        })
+        .map_err(|_| MallocError::LimitExceeded {
+            requested: block_size,
+            limit: hard_limit,
+        })?;
+    if !EXACT_ACCOUNTING.load(Ordering::Relaxed) {
+        heap.check_soft_limit(tag, held, held + reserved * block_size);
+    }
+    Ok(reserved)
+}
+
+/// Gives `bytes` bytes moved out of the central reserve for `tag` back to it
+#[inline]
+pub(crate) fn release(tag: u16, bytes: usize) {
+    if let Some(heap) = budgeted_heap(tag) {
+        heap.held_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+fn add_live(tag: u16, bytes: usize, allocations: usize) -> Result<(), MallocError> {
+    if let Some(heap) = budgeted_heap(tag) {
+        let hard_limit = heap.hard_limit(true);
+        let live = if hard_limit == UNLIMITED {
+            heap.live_bytes.fetch_add(bytes, Ordering::Relaxed)
+        } else {
+            heap.live_bytes
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| {
+                    Some(live + bytes).filter(|&live| live <= hard_limit)
+                })
+                .map_err(|_| MallocError::LimitExceeded {
+                    requested: bytes,
+                    limit: hard_limit,
+                })?
+        };
+        heap.live_allocations.fetch_add(allocations, Ordering::Relaxed);
+        if EXACT_ACCOUNTING.load(Ordering::Relaxed) {
+            heap.check_soft_limit(tag, live, live + bytes);
+        }
+    }
+    Ok(())
+}
+
+fn sub_live(tag: u16, bytes: usize, allocations: usize) {
+    if let Some(heap) = budgeted_heap(tag) {
+        heap.live_bytes.fetch_sub(bytes, Ordering::Relaxed);
+        heap.live_allocations.fetch_sub(allocations, Ordering::Relaxed);
+    }
+}
+
+/// Charges an allocation of `bytes` bytes to `tag`. This only fails with exact accounting, if the allocation would take
+/// the tag over its hard limit.
+#[inline]
+pub(crate) fn charge(tag: u16, bytes: usize) -> Result<(), MallocError> {
+    add_live(tag, bytes, 1)
+}
+
+/// Charges `allocations` allocations of `bytes` bytes in all to the tag of the calling thread. Unless the accounting is
+/// exact, they are only added to the counters of the tag when the thread cache is filled or flushed, or the tag
+/// changes.
+#[inline]
+fn charge_current_live(bytes: usize, allocations: usize) -> Result<(), MallocError> {
+    if !tags_used() {
+        return Ok(());
+    }
+    let tag = alloc_tag();
+    if tag == 0 {
+        Ok(())
+    } else if EXACT_ACCOUNTING.load(Ordering::Relaxed) {
+        add_live(tag, bytes, allocations)
+    } else {
+        let _ = pending_live.try_with(|pending| {
+            let (pending_bytes, pending_allocations) = pending.get();
+            pending.set((pending_bytes + bytes as isize, pending_allocations + allocations as isize))
+        });
+        Ok(())
+    }
+}
+
+/// Charges an allocation of `bytes` bytes to the tag of the calling thread
+#[inline]
+pub(crate) fn charge_current(bytes: usize) -> Result<(), MallocError> {
+    charge_current_live(bytes, 1)
+}
+
+/// Charges `count` allocations of `block_size` bytes to the tag of the calling thread at once
+#[inline]
+pub(crate) fn charge_current_blocks(block_size: usize, count: usize) -> Result<(), MallocError> {
+    charge_current_live(block_size * count, count)
+}
+
+/// Takes an allocation of `bytes` bytes back from the tag of the calling thread, which it was charged to. Like a
+/// charge, it is only taken from the counters of the tag later, unless the accounting is exact.
+#[inline]
+pub(crate) fn uncharge_current(bytes: usize) {
+    let tag = alloc_tag();
+    if tag == 0 {
+        return;
+    }
+    if EXACT_ACCOUNTING.load(Ordering::Relaxed)
+        || pending_live
+            .try_with(|pending| {
+                let (pending_bytes, pending_allocations) = pending.get();
+                pending.set((pending_bytes - bytes as isize, pending_allocations - 1))
+            })
+            .is_err()
+    {
+        sub_live(tag, bytes, 1);
+    }
+}
+
+/// Takes an allocation of `bytes` bytes back from `tag`, which it was charged to
+#[inline]
+pub(crate) fn uncharge(tag: u16, bytes: usize) {
+    sub_live(tag, bytes, 1)
+}
+
+/// Adds the charges of the calling thread that are pending to the counters of its current tag
+pub(crate) fn flush_live() {
+    let (bytes, allocations) = pending_live.try_with(|pending| pending.replace((0, 0))).unwrap_or((0, 0));
+    if bytes == 0 && allocations == 0 {
+        return;
+    }
+    if let Some(heap) = budgeted_heap(alloc_tag()) {
+        // the counters wrap around while a block charged on one thread is freed on another before the charge is added
+        heap.live_bytes.fetch_add(bytes as usize, Ordering::Relaxed);
+        heap.live_allocations.fetch_add(allocations as usize, Ordering::Relaxed);
+    }
+}
+
+/// Reserves and charges an allocation of `bytes` bytes that does not go through a thread cache, such as a large
+/// allocation
+pub(crate) fn take(tag: u16, bytes: usize) -> Result<(), MallocError> {
+    reserve(tag, bytes, 1)?;
+    charge(tag, bytes).inspect_err(|_| release(tag, bytes))
+}
+
+/// Gives back an allocation made with [`take()`](fn.take.html)
+pub(crate) fn give_back(tag: u16, bytes: usize) {
+    release(tag, bytes);
+    sub_live(tag, bytes, 1);
+}
+
+/// Gives back `count` blocks of `block_size` bytes of `tag` that are freed straight to the central reserve at once
+pub(crate) fn give_back_blocks(tag: u16, block_size: usize, count: usize) {
+    release(tag, block_size * count);
+    sub_live(tag, block_size * count, count);
+}
+
+/// Moves an allocation of `tag` from `old_bytes` to `new_bytes`. Growing fails if the allocation would take the tag
+/// over its hard limit, in which case nothing is changed.
+pub(crate) fn resize(tag: u16, old_bytes: usize, new_bytes: usize) -> Result<(), MallocError> {
+    if new_bytes > old_bytes {
+        let grown = new_bytes - old_bytes;
+        reserve(tag, grown, 1)?;
+        add_live(tag, grown, 0).inspect_err(|_| release(tag, grown))
+    } else {
+        let shrunk = old_bytes - new_bytes;
+        release(tag, shrunk);
+        sub_live(tag, shrunk, 0);
+        Ok(())
+    }
+}
+
+/// The bytes and allocations charged to `tag`, which must not be 0. The charges of other threads that are still
+/// pending are not counted, so a counter that they would take back above 0 is given as 0.
+pub(crate) fn tag_counters(tag: u16) -> (usize, usize) {
+    flush_live();
+    let counter = |counter: &AtomicUsize| (counter.load(Ordering::Relaxed) as isize).max(0) as usize;
+    tag_heap(tag).map_or((0, 0), |heap| (counter(&heap.live_bytes), counter(&heap.live_allocations)))
+}
+
+/// The bytes and allocations charged to every tag other than 0
+pub(crate) fn tagged_counters() -> (usize, usize) {
+    (1..=HIGHEST_TAG.load(Ordering::Acquire)).fold((0, 0), |(bytes, allocations), tag| {
+        let (tag_bytes, tag_allocations) = tag_counters(tag as u16);
+        (bytes + tag_bytes, allocations + tag_allocations)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::alloc::get_page_info_for_ptr;
+    use crate::mem_info::MAX_SZ;
+    use crate::size_classes::get_size_class;
+    use crate::stats::tag_stats;
+    use crate::thread_cache::thread_cache;
+    use crate::{do_free, do_malloc, try_malloc, usable_size_for};
+
+    #[test]
+    fn frees_are_charged_back_to_the_tag() {
+        let ptrs = std::thread::spawn(|| {
+            // The first allocation of a thread sets up its cache, which is charged to the tag as well
+            unsafe { do_free(do_malloc(200)) };
+            let mut small = Vec::with_capacity(10);
+            set_alloc_tag(7).unwrap();
+            small.extend((0..10).map(|_| do_malloc(200) as usize));
+            let large = do_malloc(MAX_SZ * 2) as usize;
+            set_alloc_tag(0).unwrap();
+            (small, large)
+        })
+        .join()
+        .unwrap();
+        let (small, large) = ptrs;
+
+        let stats = tag_stats(7);
+        assert_eq!(stats.live_allocations, 11);
+        assert!(stats.live_bytes >= 10 * 200 + MAX_SZ * 2);
+        for &ptr in &small {
+            let desc = get_page_info_for_ptr(ptr as *const u8).get_desc().unwrap();
+            assert_eq!(
+                tag_of(unsafe { (*desc).proc_heap }),
+                7,
+                "The superblock belongs to the tag"
+            );
+        }
+
+        // This thread is on tag 0, and the worker that allocated the blocks is gone
+        unsafe {
+            for &ptr in &small[..5] {
+                do_free(ptr as *mut u8);
+            }
+            do_free(large as *mut u8);
+        }
+        let stats = tag_stats(7);
+        assert_eq!(stats.live_allocations, 5);
+        assert!(stats.live_bytes >= 5 * 200 && stats.live_bytes < MAX_SZ);
+
+        unsafe {
+            for &ptr in &small[5..] {
+                do_free(ptr as *mut u8);
+            }
+        }
+        assert_eq!(tag_stats(7), Default::default());
+    }
+
+    #[test]
+    fn switching_tags_keeps_the_cached_blocks() {
+        std::thread::spawn(|| unsafe {
+            do_free(do_malloc(300));
+            let size_class_index = get_size_class(300);
+            let cached = || thread_cache.with(|tcache| (*tcache.get())[size_class_index].get_block_num());
+
+            set_alloc_tag(11).unwrap();
+            let ptr = do_malloc(300);
+            let held = cached();
+            assert!(held > 0);
+            for tag in [0, 12, 11] {
+                set_alloc_tag(tag).unwrap();
+            }
+            assert_eq!(cached(), held, "The blocks of the tag were set aside");
+            assert_eq!(tag_stats(11).live_allocations, 1);
+
+            do_free(ptr);
+            set_alloc_tag(0).unwrap();
+            assert_eq!(tag_stats(11), Default::default());
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn a_block_of_another_tag_goes_to_the_bins_set_aside_for_it() {
+        std::thread::spawn(|| unsafe {
+            do_free(do_malloc(8));
+            set_alloc_tag(13).unwrap();
+            let ptr = do_malloc(400);
+            set_alloc_tag(0).unwrap();
+
+            do_free(ptr);
+            assert_eq!(tag_stats(13).live_allocations, 0);
+            set_alloc_tag(13).unwrap();
+            assert_eq!(do_malloc(400), ptr, "The block was cached for its tag");
+            do_free(ptr);
+            set_alloc_tag(0).unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+
+    static SOFT_LIMIT_TAG: AtomicUsize = AtomicUsize::new(0);
+
+    fn record_soft_limit(tag: u16, bytes: usize, soft_limit: usize) {
+        assert!(bytes > soft_limit);
+        SOFT_LIMIT_TAG.store(tag as usize, Ordering::Relaxed);
+    }
+
+    /// Allocates blocks of `size` bytes for `tag` until one fails, and returns them
+    fn allocate_until_full(tag: u16, size: usize) -> (Vec<*mut u8>, MallocError) {
+        let mut ptrs = Vec::with_capacity(1000);
+        set_alloc_tag(tag).unwrap();
+        let error = loop {
+            match try_malloc(size) {
+                Ok(ptr) => ptrs.push(ptr.as_ptr()),
+                Err(error) => break error,
+            }
+        };
+        set_alloc_tag(0).unwrap();
+        (ptrs, error)
+    }
+
+    #[test]
+    fn budgets() {
+        std::thread::spawn(|| unsafe {
+            do_free(do_malloc(1000));
+            let block_size = usable_size_for(1000, 1).unwrap();
+            set_soft_limit_handler(Some(record_soft_limit));
+
+            // Limited when the thread cache is filled
+            let budget = TagBudget {
+                soft_limit: Some(1 << 15),
+                hard_limit: Some(1 << 16),
+            };
+            set_tag_budget(NonZeroU16::new(8).unwrap(), budget).unwrap();
+            assert_eq!(tag_budget(8), budget);
+            let (ptrs, error) = allocate_until_full(8, 1000);
+            assert_eq!(ptrs.len(), (1 << 16) / block_size);
+            assert!(matches!(error, MallocError::LimitExceeded { limit, .. } if limit == 1 << 16));
+            assert_eq!(SOFT_LIMIT_TAG.load(Ordering::Relaxed), 8);
+            set_alloc_tag(8).unwrap();
+            assert!(do_malloc(MAX_SZ * 2).is_null(), "A large allocation is limited as well");
+            set_alloc_tag(0).unwrap();
+            ptrs.into_iter().for_each(|ptr| do_free(ptr));
+
+            // Limited on every allocation
+            set_exact_accounting(true);
+            let budget = TagBudget {
+                soft_limit: None,
+                hard_limit: Some(block_size * 10),
+            };
+            set_tag_budget(NonZeroU16::new(9).unwrap(), budget).unwrap();
+            let (ptrs, _) = allocate_until_full(9, 1000);
+            assert_eq!(ptrs.len(), 10);
+            do_free(ptrs[0]);
+            let (more, _) = allocate_until_full(9, 1000);
+            assert_eq!(more.len(), 1, "A freed block can be allocated again");
+            set_exact_accounting(false);
+            ptrs[1..].iter().chain(more.iter()).for_each(|&ptr| do_free(ptr));
+            set_soft_limit_handler(None);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -1,17 +1,21 @@
-use crate::allocation_data::Anchor;
-
 use crate::alloc::{
-    compute_index, get_page_info_for_ptr, heap_push_partial, malloc_count_from_new_sb,
-    malloc_count_from_partial, malloc_from_new_sb, malloc_from_partial, unregister_desc,
+    malloc_count_from_partial, malloc_from_partial, return_blocks_in, try_malloc_count_from_new_sb,
+    try_malloc_from_new_sb,
 };
-use crate::allocation_data::{get_heaps, SuperBlockState};
+use crate::heap::{global_core, HeapCore};
+use crate::independent_collections::Array;
+use crate::iterate::{cache_guard, heap_guard};
 use crate::mem_info::{CACHE_LINE, MAX_SZ_IDX};
 use crate::size_classes::{get_size_class, SIZE_CLASSES};
+use crate::lifetime::{lifetime_scope, Lifetime, LIFETIMES};
+use crate::tags::{alloc_tag, tag_of};
+use crate::{tags, MallocError};
 use core::ops::{Deref, DerefMut};
+use spin::Mutex;
 use std::cell::RefCell;
-use std::cell::UnsafeCell;
+use std::cell::{Cell, UnsafeCell};
 use std::ptr::null_mut;
-use std::sync::atomic::Ordering;
+use std::thread::LocalKey;
 
 static RECORDED_SC: usize = 41; // Size class to record and display graph of -- 41 if none
 
@@ -27,7 +31,12 @@ static RECORDED_SC: usize = 41; // Size class to record and display graph of --
 pub struct ThreadCacheBin {
     pub(crate) block: *mut u8,
     pub(crate) block_num: u32,
-    block_size: Option<u32>,
+    pub(crate) block_size: Option<u32>,
+    /// The blocks of a new superblock are handed out in order, starting at `fresh_next`. Until a block in
+    /// `fresh_next..fresh_end` is handed out, the only bytes in it that may not be zero are the link to the next block.
+    fresh_next: *mut u8,
+    fresh_end: *mut u8,
+    fresh_block_size: u32,
 }
 
 impl ThreadCacheBin {
@@ -37,9 +46,28 @@ impl ThreadCacheBin {
             block: null_mut(),
             block_num: 0,
             block_size: None,
+            fresh_next: null_mut(),
+            fresh_end: null_mut(),
+            fresh_block_size: 0,
         }
     }
 
+    /// Marks the `count` blocks of `block_size` bytes starting at `start` as never having been handed out. Must be
+    /// called after the blocks of a new superblock are pushed to the bin.
+    #[inline]
+    pub(crate) fn set_fresh(&mut self, start: *mut u8, count: usize, block_size: u32) {
+        self.fresh_next = start;
+        self.fresh_end = unsafe { start.add(count * block_size as usize) };
+        self.fresh_block_size = block_size;
+    }
+
+    /// Forgets which blocks have never been handed out
+    #[inline]
+    fn clear_fresh(&mut self) {
+        self.fresh_next = null_mut();
+        self.fresh_end = null_mut();
+    }
+
     /// Common and Fast. Pushes a block to the top of the stack so it can be used again later. This function should be unsafe,
     /// but because it is only ever called from an unsafe context, it's unnecessary.
     #[inline]
@@ -90,17 +118,68 @@ impl ThreadCacheBin {
             panic!("Attempting to push a block list while cache is not empty");
         } else {
             //info!("Pushing {} blocks to cache", length);
+            self.clear_fresh();
             self.block = block;
             self.block_num = length;
         }
     }
 
+    /// Pushes the first `count` blocks of a new superblock, starting at `start`, on top of the stack. The last block of
+    /// the superblock must still be marked as the end of the list.
+    #[inline]
+    pub(crate) fn push_fresh_list(&mut self, start: *mut u8, count: u32, block_size: u32) {
+        if count == 0 {
+            return;
+        }
+        if self.block_num > 0 {
+            unsafe {
+                let last = start.add((count - 1) as usize * block_size as usize);
+                *(last as *mut *mut u8) = self.block;
+            }
+        }
+        self.block = start;
+        self.block_num += count;
+        self.block_size = Some(block_size);
+        self.set_fresh(start, count as usize, block_size);
+    }
+
     /// Pops a block from the cache
     ///
     /// # Panic
     /// Panics if the cache is empty
     #[inline]
     pub fn pop_block(&mut self) -> *mut u8 {
+        self.pop_fresh_block().0
+    }
+
+    /// Pops a block from the cache, and checks whether the block has never been handed out before. If it hasn't, all of
+    /// the block except for its first word is known to be zero.
+    ///
+    /// # Panic
+    /// Panics if the cache is empty
+    #[inline]
+    pub fn pop_fresh_block(&mut self) -> (*mut u8, bool) {
+        let ret = self.pop();
+        let fresh = !ret.is_null() && ret == self.fresh_next && ret < self.fresh_end;
+        if fresh {
+            self.fresh_next = unsafe { ret.add(self.fresh_block_size as usize) };
+        }
+        (ret, fresh)
+    }
+
+    /// Pops a block into every slot of `out`, starting from the top of the stack
+    ///
+    /// # Panic
+    /// Panics if the cache holds fewer blocks than `out` has slots
+    #[inline]
+    pub(crate) fn pop_run(&mut self, out: &mut [*mut u8]) {
+        for slot in out.iter_mut() {
+            *slot = self.pop_block();
+        }
+    }
+
+    #[inline]
+    fn pop(&mut self) -> *mut u8 {
         if self.block_num == 0 {
             panic!("Attempting to pop a block from cache while cache is empty")
         } else {
@@ -114,23 +193,12 @@ impl ThreadCacheBin {
                     //self.block = unsafe { self.block.offset(-1) };
 
                 }
-                Some(block_size) => if cfg!(feature = "no_met_stack") {
-                    if (self.block as *mut u8).is_null() {
+                Some(block_size) => {
+                    if cfg!(feature = "no_met_stack") && (self.block as *mut u8).is_null() {
                         return null_mut();
                     }
-                    self.block = unsafe { *(self.block as *mut *mut u8) };
-                } else {
-                    unsafe {
-                        let block_read = *(self.block as *mut *mut usize);
-                        if block_read.is_null() {
-                            self.block = self.block.add(block_size as usize);
-                        } else if block_read as usize == std::usize::MAX {
-                            self.block = null_mut();
-                        } else {
-                            self.block = *(self.block as *mut *mut u8);
-                        }
-                    }
-                },
+                    self.block = next_block(self.block, block_size);
+                }
             };
             self.block_num -= 1;
             ret
@@ -150,6 +218,7 @@ impl ThreadCacheBin {
         if self.block_num < length {
             panic!("The block_num must be greater than or equal to the provided length");
         } else {
+            self.clear_fresh();
             self.block = block;
             self.block_num -= length;
         }
@@ -166,32 +235,76 @@ impl ThreadCacheBin {
     pub fn get_block_num(&self) -> u32 {
         self.block_num
     }
+
+    /// Calls `f` on every block in the stack, starting from the top
+    pub(crate) fn for_each_block<F: FnMut(*mut u8)>(&self, mut f: F) {
+        let mut block = self.block;
+        for _ in 0..self.block_num {
+            if block.is_null() {
+                return;
+            }
+            f(block);
+            block = match self.block_size {
+                None => unsafe { *(block as *mut *mut u8) },
+                Some(block_size) => next_block(block, block_size),
+            };
+        }
+    }
+}
+
+/// Gets the block after `block` in a list of blocks of `block_size` bytes. The blocks of a new superblock are linked by
+/// a 0, which means the next block is right after it, and the last block is marked with `usize::MAX`.
+#[inline]
+pub(crate) fn next_block(block: *mut u8, block_size: u32) -> *mut u8 {
+    let next = unsafe { *(block as *mut *mut u8) };
+    if cfg!(feature = "no_met_stack") {
+        next
+    } else if next.is_null() {
+        unsafe { block.add(block_size as usize) }
+    } else if next as usize == usize::MAX {
+        null_mut()
+    } else {
+        next
+    }
 }
 
 /// Fills a cache with blocks of the `size_class_index`.
 ///
-/// This either fills the cache using a partial list in the central reserve, or by creating a new super block.
-pub fn fill_cache(size_class_index: usize, cache: &mut ThreadCacheBin) {
+/// This either fills the cache using a partial list in the central reserve, or by creating a new super block. If a new
+/// super block is needed but can not be mapped, or the blocks would take the tag of the thread over its hard limit, an
+/// error is returned and the cache is left empty.
+pub fn try_fill_cache(size_class_index: usize, cache: &mut ThreadCacheBin) -> Result<(), MallocError> {
+    let _guard = heap_guard();
+    tags::flush_live();
+    let sc = unsafe { &SIZE_CLASSES[size_class_index] };
+    let block_size = sc.block_size as usize;
+    let tag = alloc_tag();
+    let reserved = tags::reserve(tag, block_size, sc.cache_block_num as usize)?;
     let mut block_num = 0;
-    let mut used_partial = true;
 
-    // Uses a partial list from the central reserve
-    malloc_from_partial(size_class_index, cache, &mut block_num);
-    if block_num == 0 {
-        // Creates a new super block. Depending on the load on the kernel, the tail latency on this operation is high.
-        malloc_from_new_sb(size_class_index, cache, &mut block_num);
-        used_partial = false;
-    }
-    if block_num == 0 || cache.block_num == 0 {
-        panic!(
-            "Didn't allocate any blocks to the cache. USED PARTIAL={}, block_num={}, cache.block_num={}",
-            used_partial,
-            block_num,
-            cache.block_num
-        );
-    }
+    let filled = if reserved == sc.cache_block_num as usize {
+        // Uses a partial list from the central reserve
+        malloc_from_partial(size_class_index, cache, &mut block_num);
+        if block_num == 0 {
+            // Creates a new super block. Depending on the load on the kernel, the tail latency on this operation is
+            // high.
+            try_malloc_from_new_sb(size_class_index, cache, &mut block_num)
+        } else {
+            Ok(())
+        }
+    } else {
+        // Only the blocks left in the budget of the tag are taken
+        malloc_count_from_partial(size_class_index, cache, &mut block_num, reserved);
+        if block_num == 0 {
+            try_malloc_count_from_new_sb(size_class_index, cache, &mut block_num, reserved)
+        } else {
+            Ok(())
+        }
+    };
+    tags::release(tag, (reserved - block_num) * block_size);
+    filled?;
+    debug_assert!(cache.block_num > 0, "Didn't allocate any blocks to the cache");
 
-    let sc = unsafe { &SIZE_CLASSES[size_class_index] };
     cache.block_size = Some(sc.block_size);
 
     #[cfg(debug_assertions)]
@@ -199,25 +312,47 @@ pub fn fill_cache(size_class_index: usize, cache: &mut ThreadCacheBin) {
             debug_assert!(block_num > 0);
             debug_assert!(block_num <= sc.cache_block_num as usize);
         }
+    register_cache();
+    Ok(())
+}
+
+/// Fills a cache with blocks of the `size_class_index`, panicking if it can't be filled.
+#[deprecated(note = "use `try_fill_cache()`, which returns the error instead of panicking")]
+pub fn fill_cache(size_class_index: usize, cache: &mut ThreadCacheBin) {
+    try_fill_cache(size_class_index, cache).expect("Cache didn't fill")
 }
 
 /// Flushes the contents of a thread cache bin back to the central reserve.
 pub fn flush_cache(size_class_index: usize, cache: &mut ThreadCacheBin) {
+    flush_blocks(size_class_index, cache, cache.get_block_num());
+    if cache.get_block_num() == 0 {
+        cache.block_size = None;
+    }
+}
+
+/// Returns the top `count` blocks of a thread cache bin to the central reserve.
+pub(crate) fn flush_blocks(size_class_index: usize, cache: &mut ThreadCacheBin, count: u32) {
+    tags::flush_live();
+    flush_blocks_in(global_core(), size_class_index, cache, count)
+}
+
+/// Returns the top `count` blocks of a cache bin to their superblocks, which belong to the instance `core`
+pub(crate) fn flush_blocks_in(core: &HeapCore, size_class_index: usize, cache: &mut ThreadCacheBin, count: u32) {
     // println!("Flushing Cache");
     //info!("Flushing size class {} cache...", size_class_index);
-    let heap = get_heaps().get_heap_at_mut(size_class_index);
     let sc = unsafe { &SIZE_CLASSES[size_class_index] };
 
     let sb_size = sc.sb_size;
     let block_size = sc.block_size;
 
-    let _max_count = sc.get_block_num();
+    let _guard = heap_guard();
+    let mut remaining = count.min(cache.get_block_num());
 
     // There's a to do here in the original program to optimize, which is amusing
-    while cache.get_block_num() > 0 {
+    while remaining > 0 {
         let head = cache.peek_block();
         let mut tail = head;
-        let info = get_page_info_for_ptr(head);
+        let info = core.page_map.get_page_info(head);
         let desc = unsafe {
             match info.get_desc() {
                 None => {
@@ -232,8 +367,8 @@ pub fn flush_cache(size_class_index: usize, cache: &mut ThreadCacheBin) {
         let super_block = desc.super_block.as_ref().unwrap().get_ptr() as *mut u8;
 
         let mut block_count = 1;
-        while cache.get_block_num() > block_count {
-            let ptr = unsafe { *(tail as *mut *mut u8) };
+        while remaining > block_count {
+            let ptr = next_block(tail, block_size);
             if ptr < super_block || ptr as usize >= super_block as usize + sb_size as usize {
                 break;
             }
@@ -242,67 +377,12 @@ pub fn flush_cache(size_class_index: usize, cache: &mut ThreadCacheBin) {
             tail = ptr;
         }
         //info!("Reclaiming {} blocks", block_count);
-        cache.pop_list(unsafe { *(tail as *mut *mut u8) }, block_count);
-
-        let index = compute_index(super_block, head, size_class_index);
-
-
-        let mut new_anchor: Anchor;
-        let old_anchor = loop {
-            let old_anchor = desc.anchor.load(Ordering::Acquire);
-            unsafe {
-                // update avail
-                let next = super_block.offset((old_anchor.avail() * block_size as u64) as isize);
-
-                *(tail as *mut *mut u8) = next;
-            }
+        cache.pop_list(next_block(tail, block_size), block_count);
+        remaining -= block_count;
 
-            new_anchor = old_anchor;
-            new_anchor.set_avail(index as u64);
-
-            // state updates
-            // dont set to partial if state is active
-            if old_anchor.state() == SuperBlockState::FULL {
-                new_anchor.set_state(SuperBlockState::PARTIAL);
-            }
-
-            if old_anchor.count() + block_count as u64 == desc.max_count as u64 {
-                //info!("Setting new anchor to EMPTY");
-                new_anchor.set_count(desc.max_count as u64 - 1);
-                new_anchor.set_state(SuperBlockState::EMPTY);
-            } else {
-                new_anchor.set_count(block_count as u64);
-            }
-
-            if desc
-                .anchor
-                .compare_exchange_weak(old_anchor, new_anchor, Ordering::Acquire, Ordering::Relaxed)
-                .is_ok()
-            {
-                break old_anchor;
-            }
-        };
-
-        if new_anchor.state() == SuperBlockState::EMPTY {
-            unregister_desc(Some(heap), desc.super_block.as_ref().unwrap());
-            if let Some(segment) = std::mem::replace(&mut desc.super_block, None) {
-                unsafe {
-                    SEGMENT_ALLOCATOR.deallocate(segment);
-                }
-            }
-        } else if old_anchor.state() == SuperBlockState::FULL {
-            /*info!("Pushing a partially used list to the heap (Size Class Index = {}, available = {}, count = {})",
-                  size_class_index,
-                  desc.anchor.load(Ordering::Acquire).avail(),
-                  desc.anchor.load(Ordering::Acquire).count()
-            );
-
-             */
-            heap_push_partial(desc)
-        }
+        tags::release(tag_of(desc.proc_heap), block_count as usize * block_size as usize);
+        return_blocks_in(core, desc, head, tail, block_count, size_class_index);
     }
-
-    cache.block_size = None;
 }
 
 pub struct ThreadCache([ThreadCacheBin; MAX_SZ_IDX]);
@@ -368,6 +448,199 @@ impl Drop for ThreadEmpty {
          */
     }
 }
+/// The number of tags other than its current one that a thread keeps the thread cache bins of
+pub(crate) const PARKED_TAGS: usize = 3;
+/// The tag of a parked slot that holds no bins
+const NO_PARKED_TAG: u32 = u32::MAX;
+
+/// The thread cache bins of a tag the thread switched away from, kept so that switching back to the tag doesn't have to
+/// flush and fill the bins again
+#[derive(Copy, Clone)]
+struct ParkedCache {
+    tag: u32,
+    /// When the bins were parked, so that the bins parked the longest ago are flushed first
+    parked_at: u64,
+    bins: [[ThreadCacheBin; MAX_SZ_IDX]; LIFETIMES],
+}
+
+impl ParkedCache {
+    const fn new() -> Self {
+        Self {
+            tag: NO_PARKED_TAG,
+            parked_at: 0,
+            bins: [[ThreadCacheBin::new(); MAX_SZ_IDX]; LIFETIMES],
+        }
+    }
+}
+
+/// Parks the thread cache bins of the calling thread, which hold blocks of `old`, and takes out the bins parked for
+/// `new`. If no bins are parked for `new`, the thread starts with empty bins, and if every slot is taken, the bins that
+/// were parked the longest ago are flushed to make room.
+pub(crate) fn switch_tag(old: u16, new: u16) {
+    let _cache_guard = cache_guard();
+    let _ = parked_caches.try_with(|parked| {
+        let parked = unsafe { &mut *parked.get() };
+        let index = match parked.iter().position(|slot| slot.tag == new as u32) {
+            Some(index) => index,
+            None => {
+                let index = (0..PARKED_TAGS).min_by_key(|&index| parked[index].parked_at).unwrap();
+                for bins in parked[index].bins.iter_mut() {
+                    for (size_class_index, cache) in bins.iter_mut().enumerate().skip(1) {
+                        flush_cache(size_class_index, cache);
+                    }
+                }
+                index
+            }
+        };
+        let slot = &mut parked[index];
+        for lifetime in Lifetime::ALL {
+            let _ = cache_for(lifetime).try_with(|tcache| {
+                std::mem::swap(unsafe { &mut *tcache.get() }, &mut slot.bins[lifetime as usize])
+            });
+        }
+        slot.tag = old as u32;
+        slot.parked_at = parked_count.with(|count| count.replace(count.get() + 1) + 1);
+    });
+}
+
+/// Returns a block of `tag` to the bin the calling thread has parked for the tag, if it has one. The bin is flushed
+/// first if it is full. Returns `false` if no bins are parked for `tag`, in which case nothing is done.
+pub(crate) fn push_parked(block: *mut u8, tag: u16, lifetime: Lifetime, size_class_index: usize) -> bool {
+    let _cache_guard = cache_guard();
+    parked_caches
+        .try_with(|parked| {
+            let parked = unsafe { &mut *parked.get() };
+            let slot = match parked.iter_mut().find(|slot| slot.tag == tag as u32) {
+                Some(slot) => slot,
+                None => return false,
+            };
+            #[cfg(feature = "debug_checks")]
+            crate::debug_checks::block_freed(block);
+            let cache = &mut slot.bins[lifetime as usize][size_class_index];
+            let sc = unsafe { &SIZE_CLASSES[size_class_index] };
+            if cache.get_block_num() >= sc.cache_block_num {
+                flush_cache(size_class_index, cache);
+            }
+            if cache.get_block_num() == 0 {
+                register_cache();
+            }
+            cache.push_block(block);
+            true
+        })
+        .unwrap_or(false)
+}
+
+/// Calls `f` on the bins of the thread cache of each lifetime of the calling thread, and on the bins it has parked for
+/// other tags
+///
+/// # Safety
+/// The bins must not be in use by anything else on the calling thread.
+pub(crate) unsafe fn for_each_own_cache<F: FnMut(&mut [ThreadCacheBin; MAX_SZ_IDX])>(mut f: F) {
+    for lifetime in Lifetime::ALL {
+        let _ = cache_for(lifetime).try_with(|tcache| f(&mut *tcache.get()));
+    }
+    let _ = parked_caches.try_with(|parked| {
+        for slot in (*parked.get()).iter_mut() {
+            slot.bins.iter_mut().for_each(&mut f);
+        }
+    });
+}
+
+/// The thread cache of every thread that has held blocks, so that blocks in the caches of other threads can be told
+/// apart from allocated blocks
+static THREAD_CACHES: Mutex<Array<usize>> = Mutex::new(Array::new());
+
+/// Adds the thread cache of the calling thread to the registered caches the first time it is given blocks. The cache is
+/// flushed and removed once the thread exits.
+struct CacheRegistration {
+    /// The thread cache of each lifetime, and the parked bins of each lifetime
+    caches: Cell<[usize; REGISTERED_CACHES]>,
+}
+
+/// The number of caches a thread registers
+const REGISTERED_CACHES: usize = LIFETIMES * (PARKED_TAGS + 1);
+
+impl Drop for CacheRegistration {
+    fn drop(&mut self) {
+        if self.caches.get()[0] == 0 {
+            return;
+        }
+        crate::thread_cache_flush();
+        let mut caches = THREAD_CACHES.lock();
+        for cache in self.caches.get() {
+            if let Some(index) = (0..caches.len()).find(|&index| caches[index] == cache) {
+                let last = caches.pop().unwrap();
+                caches.swap(index, last);
+            }
+        }
+    }
+}
+
+/// Registers the thread cache of the calling thread, if it isn't already. Must be called whenever a bin of the cache is
+/// given blocks while it is empty.
+pub(crate) fn register_cache() {
+    // Creating the registration can allocate, which would register the cache again
+    // A thread that is exiting has nothing left to register
+    if cache_registered.try_with(|registered| registered.replace(true)).unwrap_or(true) {
+        return;
+    }
+    let _ = cache_registration.try_with(|registration| {
+        let mut caches = [0; REGISTERED_CACHES];
+        for lifetime in Lifetime::ALL {
+            caches[lifetime as usize] = cache_for(lifetime).with(|tcache| tcache.get() as usize);
+        }
+        parked_caches.with(|parked| {
+            let parked = parked.get() as *const ParkedCache;
+            for slot in 0..PARKED_TAGS {
+                for lifetime in 0..LIFETIMES {
+                    caches[LIFETIMES * (slot + 1) + lifetime] =
+                        unsafe { std::ptr::addr_of!((*parked.add(slot)).bins[lifetime]) } as usize;
+                }
+            }
+        });
+        let mut registered = THREAD_CACHES.lock();
+        for cache in caches {
+            registered.push(cache);
+        }
+        registration.caches.set(caches);
+    });
+}
+
+/// The thread cache of the calling thread that holds blocks with the given `lifetime`
+#[inline]
+pub(crate) fn cache_for(lifetime: Lifetime) -> &'static LocalKey<UnsafeCell<[ThreadCacheBin; MAX_SZ_IDX]>> {
+    match lifetime {
+        Lifetime::Short => &thread_cache,
+        Lifetime::Long => &long_lived_cache,
+    }
+}
+
+/// Calls `f` on the bins of every registered thread cache
+///
+/// # Safety
+/// None of the threads whose caches are registered may be using them.
+pub(crate) unsafe fn for_each_registered_cache<F: FnMut(&[ThreadCacheBin; MAX_SZ_IDX])>(mut f: F) {
+    let caches = THREAD_CACHES.lock();
+    let caches: &[usize] = &caches;
+    for &cache in caches {
+        f(&*(cache as *const [ThreadCacheBin; MAX_SZ_IDX]));
+    }
+}
+
+/// Adds the number of blocks held by each bin of every registered thread cache to `counts`, which is indexed by size
+/// class. The bins of other threads are read while those threads keep using them, so their counts are only a snapshot.
+pub(crate) fn count_cached_blocks(counts: &mut [usize; MAX_SZ_IDX]) {
+    let caches = THREAD_CACHES.lock();
+    let caches: &[usize] = &caches;
+    for &cache in caches {
+        let bins = cache as *const ThreadCacheBin;
+        for (size_class_index, count) in counts.iter_mut().enumerate() {
+            let block_num = unsafe { std::ptr::addr_of!((*bins.add(size_class_index)).block_num).read_volatile() };
+            *count += block_num as usize;
+        }
+    }
+}
+
 // APF Functions
 
 pub fn init_tuners() {
@@ -409,15 +682,31 @@ fn fetch(size_class_index: usize, count: usize) -> bool {
     let cache = &mut thread_cache
         .with(|tcache| unsafe { (*tcache.get()).get_mut(size_class_index).unwrap() });
 
+    let _guard = heap_guard();
+    // The tuners only size the cache of short-lived blocks, even when the thread is allocating long-lived ones
+    let _scope = lifetime_scope(Lifetime::Short);
     let mut block_num = 0;
-
+    let block_size = unsafe { SIZE_CLASSES[size_class_index].block_size };
+    cache.block_size = Some(block_size);
+
+    let tag = alloc_tag();
+    let count = match tags::reserve(tag, block_size as usize, count) {
+        Ok(count) => count,
+        Err(_) => return false,
+    };
     malloc_count_from_partial(size_class_index, cache, &mut block_num, count);
 
     // Handles no partial block and insufficient partial block cases
     // Shouldn't need to loop more than once unless fetching *really* large count
+    // If a superblock can't be mapped, the cache is left short, and is filled as normal once it runs out
     while block_num < count {
-        malloc_count_from_new_sb(size_class_index, cache, &mut block_num, count);
+        let missing = count - block_num;
+        if try_malloc_count_from_new_sb(size_class_index, cache, &mut block_num, missing).is_err() {
+            break;
+        }
     }
+    tags::release(tag, (count - block_num) * block_size as usize);
+    register_cache();
 
     return false;
 }
@@ -431,20 +720,20 @@ fn ret(size_class_index: usize, count: u32) -> bool {
         "Trying to pop return more blocks than in cache"
     );
 
-    for _i in 0..count {
-        cache.pop_block();
-    }
+    flush_blocks(size_class_index, cache, count);
 
     return true;
 }
 
 use crate::apf::ApfTuner;
-use crate::pages::external_mem_reservation::{SegAllocator, SEGMENT_ALLOCATOR};
 
 thread_local! {
     // pub static thread_cache: UnsafeCell<ThreadCache> = UnsafeCell::new(ThreadCache::new());
     /// The actual thread cache
     pub static thread_cache: UnsafeCell<[ThreadCacheBin; MAX_SZ_IDX]> = UnsafeCell::new([ThreadCacheBin::new(); MAX_SZ_IDX]);
+    /// The thread cache of [long-lived](../lifetime/index.html) blocks, which is not tuned
+    pub static long_lived_cache: UnsafeCell<[ThreadCacheBin; MAX_SZ_IDX]> =
+        const { UnsafeCell::new([ThreadCacheBin::new(); MAX_SZ_IDX]) };
     /// Enables dropping of the thread cache bins (see [ThreadEmpty](struct.ThreadEmpty.html))
     pub static thread_init: ThreadEmpty = ThreadEmpty;
 
@@ -459,6 +748,16 @@ thread_local! {
     pub static apf_init: RefCell<bool> = RefCell::new(false);
 
     pub static thread_use_bootstrap: UnsafeCell<bool> = UnsafeCell::new(false);
+
+    /// The bins the thread has parked for the tags it used before its current one
+    static parked_caches: UnsafeCell<[ParkedCache; PARKED_TAGS]> =
+        const { UnsafeCell::new([ParkedCache::new(); PARKED_TAGS]) };
+    /// The number of times the thread has parked its bins
+    static parked_count: Cell<u64> = const { Cell::new(0) };
+
+    static cache_registration: CacheRegistration =
+        const { CacheRegistration { caches: Cell::new([0; REGISTERED_CACHES]) } };
+    static cache_registered: Cell<bool> = const { Cell::new(false) };
 }
 
 #[inline]
@@ -483,4 +782,27 @@ mod test {
     fn check_bin_consistency() {
         let _bin = ThreadCacheBin::new();
     }
+
+    // The blocks are linked the way a new superblock is without the `no_met_stack` feature
+    #[cfg(not(feature = "no_met_stack"))]
+    #[test]
+    fn tracks_fresh_blocks() {
+        let mut blocks = [0usize; 4];
+        blocks[3] = usize::MAX;
+        let start = blocks.as_mut_ptr() as *mut u8;
+        let block_size = std::mem::size_of::<usize>();
+
+        let mut bin = ThreadCacheBin::new();
+        bin.block_size = Some(block_size as u32);
+        bin.push_list(start, 4);
+        bin.set_fresh(start, 4, block_size as u32);
+
+        let (first, fresh) = bin.pop_fresh_block();
+        assert_eq!(first, start);
+        assert!(fresh);
+        bin.push_block(first);
+        assert_eq!(bin.pop_fresh_block(), (first, false), "A block that was handed out is not fresh");
+        assert!(bin.pop_fresh_block().1);
+        assert!(bin.pop_fresh_block().1);
+    }
 }
@@ -1,24 +1,11 @@
 extern crate apfmalloc_lib;
 
 use apfmalloc_lib::ptr::auto_ptr::AutoPtr;
-use apfmalloc_lib::{do_aligned_alloc, do_free};
-use std::alloc::{GlobalAlloc, Layout};
+use apfmalloc_lib::ApfAlloc;
 use std::thread;
 
-struct Apf;
-
-unsafe impl GlobalAlloc for Apf {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        do_aligned_alloc(layout.align(), layout.size())
-    }
-
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        do_free(ptr);
-    }
-}
-
 #[global_allocator]
-static ALLOCATOR: Apf = Apf;
+static ALLOCATOR: ApfAlloc = ApfAlloc::new().use_apf(true);
 
 #[test]
 fn test_apf_tuning() {
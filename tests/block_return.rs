@@ -1,11 +1,11 @@
-use apfmalloc_lib::allocation_data::get_heaps;
+use apfmalloc_lib::allocation_data::global_heaps;
 use apfmalloc_lib::{do_free, do_malloc, dump_info};
 use std::sync::atomic::Ordering;
 use std::thread;
 
 #[test]
 fn threads_return_extra_to_heap() {
-    let heaps = get_heaps().get_heap_at(1);
+    let heaps = global_heaps().get_heap_at(1);
     assert!(heaps.partial_list.load(Ordering::Acquire).is_none());
     let handle = thread::spawn(move || {
         let ret = do_malloc(8);
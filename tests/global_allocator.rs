@@ -1,27 +1,11 @@
-use apfmalloc_lib::{do_aligned_alloc, do_free, do_malloc, IN_BOOTSTRAP};
-use std::alloc::GlobalAlloc;
-use std::alloc::Layout;
+use apfmalloc_lib::{config, do_malloc, ApfAlloc, IN_BOOTSTRAP};
+use std::alloc::{GlobalAlloc, Layout};
 use std::collections::HashMap;
 use std::sync::atomic::Ordering;
 use std::thread;
 
-struct Dummy;
-
 #[global_allocator]
-static ALLOCATOR: Dummy = Dummy;
-
-unsafe impl GlobalAlloc for Dummy {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // do_malloc(layout.size())
-        let output = do_aligned_alloc(layout.align(), layout.size());
-        output
-    }
-
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let _layout = layout;
-        do_free(ptr)
-    }
-}
+static ALLOCATOR: ApfAlloc = ApfAlloc::new().target_apf(4000).burst_length(500);
 
 #[test]
 fn global_allocator() {
@@ -70,3 +54,32 @@ fn panic_uses_direct_allocation() {
         }
     }
 }
+
+#[test]
+fn settings_applied_on_first_allocation() {
+    let _boxed = Box::new([0u8; 16]);
+    assert!(!config::is_configurable());
+    assert_eq!(config::target_apf(), 4000);
+    assert_eq!(config::burst_length(), 500);
+    assert_eq!(config::hibernation_period(), 1000, "Defaults to twice the burst length");
+}
+
+#[test]
+fn realloc_keeps_alignment() {
+    unsafe {
+        for &align in [64usize, 256, 4096, 4096 * 4].iter() {
+            let layout = Layout::from_size_align(40, align).unwrap();
+            let mut ptr = ALLOCATOR.alloc_zeroed(layout);
+            assert!((0..40).all(|i| *ptr.add(i) == 0));
+            ptr.write_bytes(7, 40);
+            let mut size = 40;
+            for &new_size in [100usize, 3000, 9000, 70000, 50].iter() {
+                ptr = ALLOCATOR.realloc(ptr, Layout::from_size_align(size, align).unwrap(), new_size);
+                assert_eq!(ptr as usize % align, 0, "{} bytes lost the alignment of {}", new_size, align);
+                assert!((0..40.min(new_size)).all(|i| *ptr.add(i) == 7));
+                size = new_size;
+            }
+            ALLOCATOR.dealloc(ptr, Layout::from_size_align(size, align).unwrap());
+        }
+    }
+}
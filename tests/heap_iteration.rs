@@ -0,0 +1,52 @@
+use apfmalloc_lib::iterate::{disable_allocation, enable_allocation, iterate_heap, IterationMode};
+use apfmalloc_lib::{do_free, do_malloc};
+use std::sync::mpsc::channel;
+use std::thread;
+
+fn live_blocks() -> Vec<usize> {
+    let mut blocks = Vec::with_capacity(1 << 16);
+    disable_allocation();
+    unsafe {
+        iterate_heap(IterationMode::Quiescent, |block, _| {
+            if blocks.len() < blocks.capacity() {
+                blocks.push(block as usize)
+            }
+        });
+    }
+    enable_allocation();
+    blocks
+}
+
+#[test]
+fn blocks_cached_by_other_threads_are_free() {
+    let (send_blocks, blocks) = channel();
+    let (send_done, done) = channel::<()>();
+    let worker = thread::spawn(move || {
+        let ptrs: Vec<usize> = (0..10).map(|_| do_malloc(200) as usize).collect();
+        for &ptr in &ptrs[5..] {
+            unsafe { do_free(ptr as *mut u8) };
+        }
+        send_blocks.send(ptrs).unwrap();
+        done.recv().unwrap();
+    });
+
+    let ptrs = blocks.recv().unwrap();
+    let live = live_blocks();
+    for ptr in &ptrs[..5] {
+        assert!(live.contains(ptr), "{:#x} is allocated", ptr);
+    }
+    for ptr in &ptrs[5..] {
+        assert!(!live.contains(ptr), "{:#x} is in the cache of the worker", ptr);
+    }
+
+    send_done.send(()).unwrap();
+    worker.join().unwrap();
+    let live = live_blocks();
+    assert!(
+        ptrs[5..].iter().all(|ptr| !live.contains(ptr)),
+        "The cache of the worker is flushed when it exits"
+    );
+    for &ptr in &ptrs[..5] {
+        unsafe { do_free(ptr as *mut u8) };
+    }
+}
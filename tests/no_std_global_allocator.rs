@@ -1,28 +1,14 @@
 #![no_std]
 
-use core::alloc::{GlobalAlloc, Layout};
-use apfmalloc_lib::{do_aligned_alloc, do_free};
+use apfmalloc_lib::ApfAlloc;
 
 extern crate alloc;
 
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 
-struct Dummy;
-
 #[global_allocator]
-static ALLOCATOR: Dummy = Dummy;
-
-unsafe impl GlobalAlloc for Dummy {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        do_aligned_alloc(layout.align(), layout.size())
-    }
-
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let _ = layout;
-        do_free(ptr)
-    }
-}
+static ALLOCATOR: ApfAlloc = ApfAlloc::new();
 
 #[test]
 fn no_std_global_allocator() {